@@ -0,0 +1,102 @@
+use anyhow::{anyhow, bail};
+use common::auth::password::hashing::{self, Verified};
+use common::config::Config;
+use common::person::db::NewDbPerson;
+use common::role::RoleId;
+use common::surreal::Record;
+use common::user::{db, Credentials, NewDbUser, PasswordHash};
+use surrealdb::{engine::any::Any, sql::Thing, Surreal};
+use tracing::*;
+
+// Make sure there's someone who can sign in as an admin, so a fresh install can be set up. The
+// configured password is only used when the account doesn't already have one, after that it's
+// changed from the profile page like anyone else's.
+pub async fn ensure_admin(db: &Surreal<Any>, config: &Config) -> anyhow::Result<()> {
+    let login = &config.login;
+    let release = !cfg!(debug_assertions);
+    if release && login.admin_password == Config::default().login.admin_password {
+        bail!("refusing to start with the default admin password, set login.admin_password");
+    }
+
+    let person = match db::find_by_email(db, &login.admin_email).await? {
+        Some(user) => {
+            // Anyone could have signed up with the address before the first boot, and chosen
+            // the password too
+            let is_admin = user.roles.contains(&Thing::from(RoleId::admin()));
+            if !is_admin && !user.person.email_verified {
+                bail!(
+                    "{} has an account that was never verified, refusing to make it an admin",
+                    login.admin_email
+                );
+            }
+
+            match &user.credentials.password {
+                Some(password) if release && is_default(config, password).await? => {
+                    bail!("{} still has the default admin password, change it", login.admin_email);
+                }
+                Some(_) => {}
+                None => {
+                    info!("giving admin account {} a password", login.admin_email);
+                    let mut credentials = user.credentials.clone();
+                    credentials.password = Some(PasswordHash {
+                        hash: hash(config).await?,
+                        salt: None,
+                    });
+                    db::save_credentials(db, &user.person.id, &credentials).await?;
+                }
+            }
+            if is_admin {
+                return Ok(());
+            }
+            user.person.id
+        }
+        None => {
+            info!("creating admin account {}", login.admin_email);
+            let record: Option<Record> = db
+                .create("person")
+                .content(NewDbUser {
+                    person: NewDbPerson {
+                        given_name: "Admin".to_string(),
+                        family_name: "".to_string(),
+                        picture: None,
+                        email: login.admin_email.clone(),
+                        email_verified: true,
+                        phone: None,
+                    },
+                    credentials: Credentials::password(hash(config).await?),
+                })
+                .await?
+                .pop();
+            record.ok_or(anyhow!("admin account not created"))?.id
+        }
+    };
+
+    info!("granting admin role to {}", login.admin_email);
+    let _: Option<Record> = db.update(RoleId::admin()).await?;
+    db.query("RELATE $person->has_role->$role;")
+        .bind(("person", person))
+        .bind(("role", Thing::from(RoleId::admin())))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+// An account set up by a development build, or before the password had to be changed, could
+// still have the one everybody knows
+async fn is_default(config: &Config, password: &PasswordHash) -> anyhow::Result<bool> {
+    let verified = hashing::verify_blocking(
+        &config.login.password_hashing,
+        Config::default().login.admin_password,
+        password.hash.clone(),
+        password.salt.clone(),
+    )
+    .await
+    .map_err(|e| anyhow!("failed to check admin password: {}", e))?;
+    Ok(matches!(verified, Verified::Yes { .. }))
+}
+
+async fn hash(config: &Config) -> anyhow::Result<String> {
+    hashing::hash_blocking(&config.login.password_hashing, config.login.admin_password.clone())
+        .await
+        .map_err(|e| anyhow!("failed to hash admin password: {}", e))
+}
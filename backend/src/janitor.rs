@@ -0,0 +1,218 @@
+use std::time::Duration;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use common::config::{self, Sweep};
+use common::janitor;
+use common::storage::Storage;
+use common::surreal::Record;
+use serde::Deserialize;
+use surrealdb::{engine::any::Any, sql::Datetime, Surreal};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+use crate::server::shutdown_signal;
+
+// How long to wait before restarting a sweep that panicked
+const RESTART_DELAY: Duration = Duration::from_secs(60);
+
+// Emailed links issued through one_time_token, they all have an expires_at
+const ONE_TIME_TOKEN_TABLES: &[&str] = &["password_reset", "email_verification", "magic_link"];
+
+#[derive(Clone, Copy)]
+enum Leftovers {
+    Sessions,
+    OAuthStates,
+    DraftBookings,
+    OneTimeTokens,
+    Uploads,
+}
+
+impl Leftovers {
+    fn name(self) -> &'static str {
+        match self {
+            Leftovers::Sessions => "sessions",
+            Leftovers::OAuthStates => "oauth_states",
+            Leftovers::DraftBookings => "draft_bookings",
+            Leftovers::OneTimeTokens => "one_time_tokens",
+            Leftovers::Uploads => "uploads",
+        }
+    }
+}
+
+// Runs each sweep on its own schedule until the server is shut down. A sweep that fails is
+// tried again next time round, one that panics is restarted after a short wait.
+pub fn spawn(db: Surreal<Any>, storage: Arc<dyn Storage>, cfg: config::Janitor) -> JoinHandle<()> {
+    let sweeps = [
+        (Leftovers::Sessions, cfg.sessions),
+        (Leftovers::OAuthStates, cfg.oauth_states),
+        (Leftovers::DraftBookings, cfg.draft_bookings),
+        (Leftovers::OneTimeTokens, cfg.one_time_tokens),
+        (Leftovers::Uploads, cfg.uploads),
+    ];
+
+    tokio::spawn(async move {
+        let stop = CancellationToken::new();
+        let mut tasks = JoinSet::new();
+        for (leftovers, sweep) in sweeps {
+            let storage = storage.clone();
+            tasks.spawn(supervise(db.clone(), storage, leftovers, sweep, stop.clone()));
+        }
+
+        shutdown_signal().await;
+        debug!("signal received, stopping janitor");
+        stop.cancel();
+        while tasks.join_next().await.is_some() {}
+    })
+}
+
+async fn supervise(
+    db: Surreal<Any>,
+    storage: Arc<dyn Storage>,
+    leftovers: Leftovers,
+    sweep: Sweep,
+    stop: CancellationToken,
+) {
+    loop {
+        let task = tokio::spawn(run(
+            db.clone(),
+            storage.clone(),
+            leftovers,
+            sweep.clone(),
+            stop.clone(),
+        ));
+        match task.await {
+            Err(e) if e.is_panic() => error!("janitor sweep {} panicked", leftovers.name()),
+            _ => return,
+        }
+
+        tokio::select! {
+            _ = stop.cancelled() => return,
+            _ = tokio::time::sleep(RESTART_DELAY) => {}
+        }
+    }
+}
+
+async fn run(
+    db: Surreal<Any>,
+    storage: Arc<dyn Storage>,
+    leftovers: Leftovers,
+    sweep: Sweep,
+    stop: CancellationToken,
+) {
+    let period = Duration::from_secs(sweep.interval_minutes.max(1) * 60);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+
+        let outcome = remove(&db, storage.as_ref(), leftovers, sweep.keep_hours)
+            .await
+            .map_err(|e| e.to_string());
+        match &outcome {
+            Ok(0) => {}
+            Ok(removed) => info!("janitor removed {} {}", removed, leftovers.name()),
+            Err(e) => warn!("janitor failed to sweep {}: {}", leftovers.name(), e),
+        }
+
+        if let Err(e) = janitor::record(&db, leftovers.name(), &outcome).await {
+            warn!("failed to record janitor sweep of {}: {}", leftovers.name(), e);
+        }
+    }
+}
+
+// Delete the leftovers finished with more than `keep_hours` ago, returning how many went
+async fn remove(
+    db: &Surreal<Any>,
+    storage: &dyn Storage,
+    leftovers: Leftovers,
+    keep_hours: i64,
+) -> anyhow::Result<u64> {
+    let cutoff = Utc::now() - chrono::Duration::hours(keep_hours);
+
+    let queries: Vec<String> = match leftovers {
+        Leftovers::Sessions => {
+            vec!["DELETE session WHERE <datetime>expires_at < $cutoff RETURN BEFORE;".to_string()]
+        }
+        // Sign ins started before we kept created_at are long abandoned
+        Leftovers::OAuthStates => vec!["DELETE oauth2_state \
+             WHERE created_at = NONE OR <datetime>created_at < $cutoff RETURN BEFORE;"
+            .to_string()],
+        // Once sent to Square someone may still be paying, and bookings from before we kept
+        // created_at can't be told apart from ones made a moment ago, so both are left alone
+        Leftovers::DraftBookings => vec!["DELETE booking \
+             WHERE status = 'Draft' AND array::len(payments) = 0 \
+             AND (square_order = NONE OR square_order = NULL) \
+             AND created_at != NONE AND <datetime>created_at < $cutoff RETURN BEFORE;"
+            .to_string()],
+        Leftovers::OneTimeTokens => ONE_TIME_TOKEN_TABLES
+            .iter()
+            .map(|table| {
+                format!("DELETE {} WHERE <datetime>expires_at < $cutoff RETURN BEFORE;", table)
+            })
+            .collect(),
+        Leftovers::Uploads => return remove_uploads(db, storage, cutoff).await,
+    };
+
+    let mut removed = 0;
+    for query in queries {
+        let deleted: Vec<Record> = db
+            .query(query)
+            .bind(("cutoff", Datetime::from(cutoff)))
+            .await?
+            .take(0)?;
+        removed += deleted.len() as u64;
+    }
+    Ok(removed)
+}
+
+#[derive(Deserialize)]
+struct Images {
+    hero_image: Option<String>,
+    #[serde(default)]
+    gallery: Vec<String>,
+    #[serde(default)]
+    description: String,
+}
+
+// Uploads stored before the cutoff that no event or series template uses. Newer ones are left
+// alone, they may be in an event that hasn't been saved yet.
+async fn remove_uploads(
+    db: &Surreal<Any>,
+    storage: &dyn Storage,
+    cutoff: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let mut response = db
+        .query("SELECT hero_image, gallery, description FROM event;")
+        .query(
+            "SELECT template.hero_image AS hero_image, template.gallery AS gallery, \
+             template.description AS description FROM event_series;",
+        )
+        .await?;
+    let mut images: Vec<Images> = response.take(0)?;
+    images.extend(response.take::<Vec<Images>>(1)?);
+
+    let in_use: HashSet<&str> = images
+        .iter()
+        .flat_map(|i| i.hero_image.iter().chain(&i.gallery))
+        .map(String::as_str)
+        .collect();
+    // Descriptions can link to an upload too
+    let linked = |key: &str| images.iter().any(|i| i.description.contains(key));
+
+    let mut removed = 0;
+    for (key, stored_at) in storage.list().await? {
+        if stored_at < cutoff && !in_use.contains(key.as_str()) && !linked(&key) {
+            storage.delete(&key).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -1,14 +1,19 @@
+mod bootstrap;
+mod janitor;
 mod middleware;
 mod server;
+mod uploads;
 
-use axum::{body::Body, extract::{Host, Path, Request, State}, http::{header, StatusCode}, response::IntoResponse, routing::{get, post}, Router};
+use axum::{body::Body, extract::{DefaultBodyLimit, Host, Path, Request, State}, http::{header, StatusCode}, response::{IntoResponse, Response}, routing::{get, post}, Router};
 use axum_extra::extract::CookieJar;
 use common::config::Config;
+use common::storage::Storage;
 use common::{axum::LoggedInUser, AppState};
 use dotenv::dotenv;
 use figment::{providers::{Env, Format, Serialized, Toml}, Figment};
 use leptos::provide_context;
 use rust_embed::RustEmbed;
+use std::sync::Arc;
 use surrealdb::{engine::any::{connect, Any}, opt::auth::Root, Surreal};
 use tracing::*;
 
@@ -19,9 +24,14 @@ async fn main() -> anyhow::Result<()> {
     let config = load_config()?;
     setup_logging();
     let db = connect_db(&config).await?;
-    let app = build_app(db, config).layer(axum::middleware::from_fn(middleware::log_errors));
+    bootstrap::ensure_admin(&db, &config).await?;
+    let storage = common::storage::from_config(&config.uploads);
+    let janitor = janitor::spawn(db.clone(), storage.clone(), config.janitor.clone());
+    let app = build_app(db, config, storage)
+        .layer(axum::middleware::from_fn(middleware::log_errors));
 
     server::serve(app).await;
+    janitor.await?;
     info!("graceful shutdown complete");
     Ok(())
 }
@@ -83,25 +93,60 @@ pub async fn my_handler(
 
     leptos_axum::handle_server_fns_with_context(additional_context, req).await
 }
-fn build_app(db: Surreal<Any>, config: Config) -> Router {
+fn build_app(db: Surreal<Any>, config: Config, storage: Arc<dyn Storage>) -> Router {
+    let max_upload = config.uploads.max_kib * 1024;
+    let state = common::AppState { db, config, storage };
+
+    let api = Router::new()
+        .route("/api/*fn_name", post(my_handler))
+        .route("/api/*fn_name", get(my_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::csrf,
+        ));
+
+    let uploads = Router::new()
+        .route("/uploads", post(uploads::upload_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::csrf,
+        ))
+        .layer(DefaultBodyLimit::max(max_upload));
+
     Router::new()
         .route("/", get(root_handler))
         .route("/app.wasm", get(wasm_handler))
         .route("/app.js", get(js_handler))
         .route("/static/*path", get(static_handler))
-        .route("/api/*fn_name", post(my_handler))
-        .route("/api/*fn_name", get(my_handler))
+        .route("/uploads/:key", get(uploads::download_handler))
+        .route("/calendar.ics", get(calendar_handler))
+        .merge(api)
+        .merge(uploads)
         .fallback(get(root_handler))
-        .with_state(common::AppState { db, config })
+        .with_state(state)
+}
+
+// A file's contents, with the content type worked out from its name
+pub fn file_response(path: &str, data: impl Into<Body>) -> Response {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    ([(header::CONTENT_TYPE, mime.as_ref())], data.into()).into_response()
 }
 
 async fn root_handler() -> impl IntoResponse {
     let path = "index.html";
     match Static::get(path) {
         None => (StatusCode::NOT_FOUND, "404 Not Found").into_response(),
-        Some(content) => {
-            let mime = mime_guess::from_path(path).first_or_octet_stream();
-            ([(header::CONTENT_TYPE, mime.as_ref())], content.data).into_response()
+        Some(content) => file_response(path, content.data),
+    }
+}
+
+// The public event list, for calendar apps to subscribe to
+async fn calendar_handler(State(state): State<AppState>) -> Response {
+    match common::calendar::feed(&state.db, &state.config).await {
+        Ok(ics) => ([(header::CONTENT_TYPE, "text/calendar; charset=utf-8")], ics).into_response(),
+        Err(e) => {
+            warn!("failed to build the calendar feed: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to build the calendar").into_response()
         }
     }
 }
@@ -137,10 +182,7 @@ struct Static;
 async fn static_handler(Path(path): Path<String>) -> impl IntoResponse {
     match Static::get(&path) {
         None => (StatusCode::NOT_FOUND, "404 Not Found").into_response(),
-        Some(content) => {
-            let mime = mime_guess::from_path(path).first_or_octet_stream();
-            ([(header::CONTENT_TYPE, mime.as_ref())], content.data).into_response()
-        }
+        Some(content) => file_response(&path, content.data),
     }
 }
 
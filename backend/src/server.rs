@@ -1,4 +1,4 @@
-use axum::extract::Request;
+use axum::extract::{ConnectInfo, Request};
 use hyper::{body::Incoming, server::conn::http1, service::service_fn};
 use hyper_util::rt::TokioIo;
 use tokio::{net::TcpListener, signal};
@@ -22,8 +22,11 @@ pub async fn serve(app: axum::Router) {
         let tower_service = app.clone();
 
         tokio::spawn(async move {
-            let hyper_service =
-                service_fn(move |request: Request<Incoming>| tower_service.clone().call(request));
+            // Let handlers see who they're talking to, e.g. for throttling sign in attempts
+            let hyper_service = service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote_addr));
+                tower_service.clone().call(request)
+            });
 
             let conn = http1::Builder::new().serve_connection(socket, hyper_service);
             let mut conn = std::pin::pin!(conn);
@@ -48,7 +51,7 @@ pub async fn serve(app: axum::Router) {
     }
 }
 
-async fn shutdown_signal() {
+pub async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -0,0 +1,103 @@
+use axum::{body::Bytes, extract::{Path, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use common::{axum::LoggedInUser, role::RoleId, AppState};
+use tracing::*;
+
+// Only images browsers show without running anything, so no svg
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+];
+
+// What the file itself says it is, going by the bytes each format starts with
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+// Organisers upload an image as the request body, with its Content-Type set. The response is
+// the key it was stored under, which goes in the event.
+pub async fn upload_handler(
+    logged_in_user: Option<LoggedInUser>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match logged_in_user {
+        None => return (StatusCode::UNAUTHORIZED, "you need to sign in to do that").into_response(),
+        Some(user) if !user.has_role(&RoleId::organiser()) => {
+            let msg = format!("the '{}' role is required to do that", RoleId::organiser());
+            return (StatusCode::FORBIDDEN, msg).into_response();
+        }
+        Some(_) => {}
+    }
+
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|ct| ct.to_str().ok());
+    let Some((mime, extension)) = IMAGE_TYPES.iter().find(|(mime, _)| Some(*mime) == content_type)
+    else {
+        let msg = "only png, jpeg, gif and webp images can be uploaded";
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response();
+    };
+    // Otherwise anything could be uploaded with an image's Content-Type
+    if sniff(&body) != Some(*mime) {
+        let msg = format!("the file isn't a {} image", extension);
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response();
+    }
+
+    let key = format!("{:032x}.{}", rand::random::<u128>(), extension);
+    if let Err(e) = state.storage.put(&key, &body).await {
+        error!("failed to store upload {}: {:?}", key, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "failed to store the upload").into_response();
+    }
+
+    info!("stored upload {} ({} bytes)", key, body.len());
+    (StatusCode::CREATED, key).into_response()
+}
+
+pub async fn download_handler(Path(key): Path<String>, State(state): State<AppState>) -> Response {
+    match state.storage.get(&key).await {
+        Ok(Some(data)) => {
+            // Keys are never reused, so what's behind one never changes. Browsers are told to
+            // go by the content type rather than guessing from what's in the file.
+            let headers = [
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            ];
+            (headers, crate::file_response(&key, data)).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "404 Not Found").into_response(),
+        Err(e) => {
+            warn!("failed to read upload {}: {:?}", key, e);
+            (StatusCode::NOT_FOUND, "404 Not Found").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_each_image_type() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF87a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+    }
+
+    #[test]
+    fn refuses_anything_else() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"), None);
+        assert_eq!(sniff(b"<html><script>alert(1)</script>"), None);
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff(b"\x89PNG"), None);
+    }
+}
@@ -0,0 +1,263 @@
+use chrono::{DateTime, Utc};
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+// A record of who did what to which record. Entries are only ever added, never changed or
+// removed. `before` and `after` are JSON, and only hold the fields that changed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    pub id: String,
+    pub at: DateTime<Utc>,
+    pub action: String,
+    pub actor: Option<String>,
+    pub actor_email: Option<String>,
+    pub target: String,
+    pub changes: Vec<Change>,
+    pub address: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Change {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+// Everything left as None matches anything. `actor` and `target` match part of the email
+// address or record id.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuditFilter {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[leptos::server(ListAudit, "/api", "Url", "list_audit")]
+pub async fn list_audit(filter: AuditFilter) -> Result<Vec<AuditEntry>, ServerFnError> {
+    backend::list(filter).await
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use std::collections::BTreeSet;
+use leptos::use_context;
+use serde_json::{Map, Value};
+use surrealdb::engine::any::Any;
+use surrealdb::sql::Thing;
+use surrealdb::Surreal;
+use tracing::warn;
+use crate::auth::{authz, throttle};
+use crate::AppState;
+
+const TABLE: &str = "audit";
+
+// Don't send the whole history to the page at once
+const LIMIT: usize = 500;
+
+#[derive(Serialize)]
+struct NewAuditEntry<'a> {
+    at: DateTime<Utc>,
+    action: &'a str,
+    actor: Option<String>,
+    actor_email: Option<String>,
+    target: String,
+    changes: Vec<Change>,
+    address: Option<String>,
+}
+
+// Note an action by whoever is signed in. Something has already happened by the time this is
+// called, so failing to write the entry is logged rather than failing the request.
+pub async fn record(db: &Surreal<Any>, action: &str, target: &Thing, changes: Vec<Change>) {
+    let actor = authz::current_user()
+        .ok()
+        .map(|u| (Thing::from(&u.person.id), u.person.email));
+    write(db, action, actor, target, changes).await
+}
+
+// For when the actor isn't signed in yet, i.e. signing in
+pub async fn record_as(
+    db: &Surreal<Any>,
+    actor: &Thing,
+    actor_email: &str,
+    action: &str,
+    target: &Thing,
+) {
+    let actor = Some((actor.clone(), actor_email.to_string()));
+    write(db, action, actor, target, Vec::new()).await
+}
+
+async fn write(
+    db: &Surreal<Any>,
+    action: &str,
+    actor: Option<(Thing, String)>,
+    target: &Thing,
+    changes: Vec<Change>,
+) {
+    let address = use_context::<AppState>()
+        .and_then(|s| throttle::client_address(&s.config.login.throttle));
+    let (actor, actor_email) = match actor {
+        Some((id, email)) => (Some(id.to_string()), Some(email)),
+        None => (None, None),
+    };
+
+    let entry = NewAuditEntry {
+        at: Utc::now(),
+        action,
+        actor,
+        actor_email,
+        target: target.to_string(),
+        changes,
+        address,
+    };
+
+    let created: Result<Vec<crate::surreal::Record>, _> = db.create(TABLE).content(entry).await;
+    if let Err(e) = created {
+        warn!("failed to write audit entry for {} on {}: {:?}", action, target, e);
+    }
+}
+
+// The top level fields that differ between two versions of a record. Pass None for `before`
+// when something is created, and for `after` when it is deleted.
+pub fn diff<T: Serialize>(before: Option<&T>, after: Option<&T>) -> Vec<Change> {
+    let fields = |v: Option<&T>| match v.map(serde_json::to_value) {
+        Some(Ok(Value::Object(map))) => map,
+        _ => Map::new(),
+    };
+    let (before, after) = (fields(before), fields(after));
+
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| Change {
+            field: name.clone(),
+            before: before.get(name).map(|v| v.to_string()),
+            after: after.get(name).map(|v| v.to_string()),
+        })
+        .collect()
+}
+
+enum Fail {
+    NoAppState,
+    DbError(surrealdb::Error),
+}
+
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let msg = match fail {
+            Fail::NoAppState => "no app state in context".to_string(),
+            Fail::DbError(e) => format!("database error: {:?}", e),
+        };
+        ServerFnError::ServerError(msg)
+    }
+}
+
+mod backend {
+    use super::*;
+    use crate::role::RoleId;
+
+    pub async fn list(filter: AuditFilter) -> Result<Vec<AuditEntry>, ServerFnError> {
+        authz::require_role(RoleId::admin())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let mut conditions = vec!["true"];
+        if filter.action.is_some() {
+            conditions.push("action = $action");
+        }
+        if filter.actor.is_some() {
+            conditions.push("string::contains(string::lowercase(actor_email ?? ''), $actor)");
+        }
+        if filter.target.is_some() {
+            conditions.push("string::contains(target, $target)");
+        }
+        if filter.since.is_some() {
+            conditions.push("<datetime>at >= <datetime>$since");
+        }
+        if filter.until.is_some() {
+            conditions.push("<datetime>at < <datetime>$until");
+        }
+
+        let query = format!(
+            "SELECT *, meta::id(id) AS id FROM {} WHERE {} ORDER BY at DESC LIMIT {};",
+            TABLE,
+            conditions.join(" AND "),
+            LIMIT
+        );
+
+        let entries: Vec<AuditEntry> = app_state
+            .db
+            .query(query)
+            .bind(("action", filter.action))
+            .bind(("actor", filter.actor.map(|a| a.to_lowercase())))
+            .bind(("target", filter.target))
+            .bind(("since", filter.since))
+            .bind(("until", filter.until))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Venue {
+        name: &'static str,
+        postcode: &'static str,
+        capacity: Option<u32>,
+    }
+
+    fn change(field: &str, before: Option<&str>, after: Option<&str>) -> Change {
+        Change {
+            field: field.to_string(),
+            before: before.map(str::to_string),
+            after: after.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn only_changed_fields_are_recorded() {
+        let before = Venue {
+            name: "Hall",
+            postcode: "AB1 2CD",
+            capacity: None,
+        };
+        let after = Venue {
+            name: "Village Hall",
+            capacity: Some(80),
+            ..before
+        };
+        assert_eq!(
+            diff(Some(&before), Some(&after)),
+            [
+                change("capacity", Some("null"), Some("80")),
+                change("name", Some("\"Hall\""), Some("\"Village Hall\"")),
+            ]
+        );
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn creating_and_deleting_record_every_field() {
+        let venue = Venue {
+            name: "Hall",
+            postcode: "AB1 2CD",
+            capacity: None,
+        };
+        let created = diff(None, Some(&venue));
+        assert_eq!(created.len(), 3);
+        assert!(created.iter().all(|c| c.before.is_none() && c.after.is_some()));
+        let deleted = diff(Some(&venue), None);
+        assert_eq!(deleted.len(), 3);
+        assert!(deleted.iter().all(|c| c.before.is_some() && c.after.is_none()));
+    }
+}
+
+}}
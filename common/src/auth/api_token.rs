@@ -0,0 +1,288 @@
+use chrono::{DateTime, Utc};
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+use crate::role::RoleId;
+
+// Tokens for scripts, sent as `Authorization: Bearer hap_<id>_<secret>`. A token can only do
+// what its scopes allow, and never more than the person who made it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<RoleId>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+// The token itself is only ever shown once, when it is created
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct NewApiToken {
+    pub info: ApiTokenInfo,
+    pub token: String,
+}
+
+#[leptos::server(CreateApiToken, "/api", "Url", "create_api_token")]
+pub async fn create_api_token(
+    name: String,
+    scopes: Vec<RoleId>,
+    expires_in_days: Option<i64>,
+) -> Result<NewApiToken, ServerFnError> {
+    backend::create(name, scopes, expires_in_days).await
+}
+
+#[leptos::server(ListApiTokens, "/api", "Url", "list_api_tokens")]
+pub async fn list_api_tokens() -> Result<Vec<ApiTokenInfo>, ServerFnError> {
+    backend::list().await
+}
+
+#[leptos::server(RevokeApiToken, "/api", "Url", "revoke_api_token")]
+pub async fn revoke_api_token(id: String) -> Result<(), ServerFnError> {
+    backend::revoke(id).await
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use chrono::Duration;
+use leptos::use_context;
+use leptos::ServerFnError::ServerError;
+use rand::distributions::{Alphanumeric, DistString};
+use sha256::Sha256Digest;
+use subtle::ConstantTimeEq;
+use surrealdb::engine::any::Any;
+use surrealdb::sql::Thing;
+use surrealdb::Surreal;
+use crate::axum::LoggedInUser;
+use crate::user::{db, User};
+
+const PREFIX: &str = "hap_";
+
+// Don't bother writing to the database on every request just to say we saw the token
+const TOUCH_AFTER: i64 = 5;
+
+// Kept in the person's `api_tokens`. Only a hash of the secret part is stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredApiToken {
+    id: String,
+    name: String,
+    hash: String,
+    scopes: Vec<RoleId>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<StoredApiToken> for ApiTokenInfo {
+    fn from(t: StoredApiToken) -> Self {
+        Self {
+            id: t.id,
+            name: t.name,
+            scopes: t.scopes,
+            created_at: t.created_at,
+            expires_at: t.expires_at,
+            last_used_at: t.last_used_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenOwner {
+    id: Thing,
+    #[serde(default)]
+    api_tokens: Vec<StoredApiToken>,
+}
+
+enum Fail {
+    NoAppState,
+    DbError(surrealdb::Error),
+    NoName,
+    NoScopes,
+    ScopeNotHeld(RoleId),
+    UnknownToken,
+}
+
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let msg = match fail {
+            Fail::NoAppState => "no app state in context".to_string(),
+            Fail::DbError(e) => format!("database error: {:?}", e),
+            Fail::NoName => "please give the token a name".to_string(),
+            Fail::NoScopes => "a token needs at least one scope".to_string(),
+            Fail::ScopeNotHeld(role) => format!("you don't have the '{}' role to give", role),
+            Fail::UnknownToken => "no such API token".to_string(),
+        };
+        ServerError(msg)
+    }
+}
+
+// The person and roles a bearer token acts as, or None if it isn't a valid, unexpired token
+pub async fn authenticate(
+    db: &Surreal<Any>,
+    token: &str,
+) -> Result<Option<LoggedInUser>, surrealdb::Error> {
+    let Some((id, secret)) = token.strip_prefix(PREFIX).and_then(|t| t.split_once('_')) else {
+        return Ok(None);
+    };
+
+    let mut owners: Vec<TokenOwner> = db
+        .query("SELECT id, api_tokens FROM person WHERE count(api_tokens[WHERE id=$id]) > 0;")
+        .bind(("id", id))
+        .await?
+        .take(0)?;
+    let Some(mut owner) = owners.pop() else {
+        return Ok(None);
+    };
+
+    let now = Utc::now();
+    let Some(stored) = owner.api_tokens.iter_mut().find(|t| t.id == id) else {
+        return Ok(None);
+    };
+    // Compared in constant time, so how long it takes doesn't say how much of it was right
+    let hash = Sha256Digest::digest(secret);
+    let matches: bool = stored.hash.as_bytes().ct_eq(hash.as_bytes()).into();
+    if !matches || stored.expires_at.is_some_and(|e| e < now) {
+        return Ok(None);
+    }
+
+    let scopes = stored.scopes.clone();
+    let stale = match stored.last_used_at {
+        Some(t) => now - t > Duration::minutes(TOUCH_AFTER),
+        None => true,
+    };
+    if stale {
+        stored.last_used_at = Some(now);
+        save_tokens(db, &owner.id, &owner.api_tokens).await?;
+    }
+
+    let Some(user) = db::find_by_id(db, &owner.id).await? else {
+        return Ok(None);
+    };
+    let user: User = user.into();
+
+    // Scopes the person has since lost don't count
+    let roles = scopes
+        .into_iter()
+        .filter(|scope| user.roles.iter().any(|r| r.grants(scope)))
+        .collect();
+
+    Ok(Some(LoggedInUser {
+        person: user.person,
+        roles,
+        session: None,
+    }))
+}
+
+async fn tokens(db: &Surreal<Any>, person: &Thing) -> Result<Vec<StoredApiToken>, Fail> {
+    let owner: Option<TokenOwner> = db
+        .query("SELECT id, api_tokens FROM $person;")
+        .bind(("person", person))
+        .await
+        .map_err(Fail::DbError)?
+        .take(0)
+        .map_err(Fail::DbError)?;
+    Ok(owner.map(|o| o.api_tokens).unwrap_or_default())
+}
+
+async fn save_tokens(
+    db: &Surreal<Any>,
+    person: &Thing,
+    tokens: &[StoredApiToken],
+) -> Result<(), surrealdb::Error> {
+    db.query("UPDATE $person SET api_tokens=$tokens;")
+        .bind(("person", person))
+        .bind(("tokens", tokens))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+mod backend {
+    use super::*;
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::AppState;
+    use tracing::info;
+
+    pub async fn create(
+        name: String,
+        scopes: Vec<RoleId>,
+        expires_in_days: Option<i64>,
+    ) -> Result<NewApiToken, ServerFnError> {
+        let user = authz::require_session()?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err(Fail::NoName.into());
+        }
+        if scopes.is_empty() {
+            return Err(Fail::NoScopes.into());
+        }
+        if let Some(scope) = scopes.iter().find(|s| !user.has_role(s)) {
+            return Err(Fail::ScopeNotHeld(scope.clone()).into());
+        }
+
+        let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 12);
+        let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+        let now = Utc::now();
+        let stored = StoredApiToken {
+            id: id.clone(),
+            name,
+            hash: Sha256Digest::digest(&secret),
+            scopes,
+            created_at: now,
+            expires_at: expires_in_days.map(|d| now + Duration::days(d)),
+            last_used_at: None,
+        };
+
+        let person = Thing::from(&user.person.id);
+        let mut all = tokens(&app_state.db, &person).await?;
+        all.push(stored.clone());
+        save_tokens(&app_state.db, &person, &all)
+            .await
+            .map_err(Fail::DbError)?;
+
+        info!("{} created API token {} ({})", user.person.email, stored.name, id);
+        let info: ApiTokenInfo = stored.into();
+        let changes = audit::diff(None, Some(&info));
+        audit::record(&app_state.db, "create_api_token", &person, changes).await;
+        Ok(NewApiToken {
+            info,
+            token: format!("{}{}_{}", PREFIX, id, secret),
+        })
+    }
+
+    pub async fn list() -> Result<Vec<ApiTokenInfo>, ServerFnError> {
+        let user = authz::require_session()?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let all = tokens(&app_state.db, &Thing::from(&user.person.id)).await?;
+        Ok(all.into_iter().map(|t| t.into()).collect())
+    }
+
+    pub async fn revoke(id: String) -> Result<(), ServerFnError> {
+        let user = authz::require_session()?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let person = Thing::from(&user.person.id);
+        let mut all = tokens(&app_state.db, &person).await?;
+        let index = all
+            .iter()
+            .position(|t| t.id == id)
+            .ok_or(Fail::UnknownToken)?;
+        let revoked: ApiTokenInfo = all.remove(index).into();
+
+        save_tokens(&app_state.db, &person, &all)
+            .await
+            .map_err(Fail::DbError)?;
+        info!("{} revoked API token {}", user.person.email, id);
+        let changes = audit::diff(Some(&revoked), None);
+        audit::record(&app_state.db, "revoke_api_token", &person, changes).await;
+        Ok(())
+    }
+}
+
+}}
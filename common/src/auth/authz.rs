@@ -0,0 +1,95 @@
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use axum::http::StatusCode;
+use leptos::use_context;
+use leptos::ServerFnError::{self, ServerError};
+use leptos_axum::ResponseOptions;
+use crate::axum::LoggedInUser;
+use crate::role::RoleId;
+
+pub enum Fail {
+    NotSignedIn,
+    MissingRole(RoleId),
+    EmailNotVerified,
+    NeedsSession,
+}
+
+// Authorization failures are surfaced with a proper status code rather than the 500 that
+// leptos gives a server function error by default.
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let (status, msg) = match fail {
+            Fail::NotSignedIn => (StatusCode::UNAUTHORIZED, "you need to sign in to do that".to_string()),
+            Fail::MissingRole(role) => (StatusCode::FORBIDDEN, format!("the '{}' role is required to do that", role)),
+            Fail::EmailNotVerified => (StatusCode::FORBIDDEN, "please verify your email address first".to_string()),
+            Fail::NeedsSession => (StatusCode::FORBIDDEN, "that can only be done when signed in through the website".to_string()),
+        };
+        if let Some(res) = use_context::<ResponseOptions>() {
+            res.set_status(status);
+        }
+        ServerError(msg)
+    }
+}
+
+// The user making the current request, as extracted by the server function handler.
+pub fn current_user() -> Result<LoggedInUser, Fail> {
+    use_context::<Option<LoggedInUser>>()
+        .flatten()
+        .ok_or(Fail::NotSignedIn)
+}
+
+// For anything that changes how someone signs in. API tokens can't be used for these, otherwise
+// a leaked one could give itself a way in that outlives its revocation.
+pub fn require_session() -> Result<LoggedInUser, Fail> {
+    let user = current_user()?;
+    if user.session.is_none() {
+        return Err(Fail::NeedsSession);
+    }
+    Ok(user)
+}
+
+// Use at the top of a server function to declare the role it needs. Everyone who is signed in
+// is an attendee, so require_role(RoleId::attendee()) just checks that there is a user.
+pub fn require_role(role: RoleId) -> Result<LoggedInUser, Fail> {
+    let user = current_user()?;
+    if !user.has_role(&role) {
+        return Err(Fail::MissingRole(role));
+    }
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::person::Person;
+    use leptos::{create_runtime, provide_context};
+
+    fn signed_in(session: Option<&str>) -> Result<LoggedInUser, Fail> {
+        let runtime = create_runtime();
+        provide_context(Some(LoggedInUser {
+            person: Person {
+                id: "person:someone".into(),
+                given_name: "Some".to_string(),
+                family_name: "One".to_string(),
+                picture: None,
+                email: "someone@example.com".to_string(),
+                email_verified: true,
+                phone: None,
+            },
+            roles: vec![RoleId::admin()],
+            session: session.map(Into::into),
+        }));
+        let user = require_session();
+        runtime.dispose();
+        user
+    }
+
+    #[test]
+    fn bearer_tokens_cant_change_credentials() {
+        assert!(matches!(signed_in(None), Err(Fail::NeedsSession)));
+        assert!(signed_in(Some("session:abc")).is_ok());
+    }
+}
+
+}}
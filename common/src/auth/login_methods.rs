@@ -0,0 +1,165 @@
+use crate::user::LoginMethods;
+use leptos::ServerFnError;
+
+// The signed in person's password and linked provider accounts. New provider accounts are
+// linked through oauth_redirect with link set.
+#[leptos::server(GetLoginMethods, "/api", "Url", "get_login_methods")]
+pub async fn get_login_methods() -> Result<LoginMethods, ServerFnError> { backend::get().await }
+
+#[leptos::server(UnlinkIdentity, "/api", "Url", "unlink_identity")]
+pub async fn unlink_identity(
+    provider: String,
+    subject: String,
+) -> Result<LoginMethods, ServerFnError> {
+    backend::unlink(provider, subject).await
+}
+
+// Adds a password to the account, or changes it in which case the current one is needed too
+#[leptos::server(SetPassword, "/api", "Url", "set_password")]
+pub async fn set_password(
+    current_password: Option<String>,
+    new_password: String,
+) -> Result<LoginMethods, ServerFnError> {
+    backend::set_password(current_password, new_password).await
+}
+
+#[leptos::server(RemovePassword, "/api", "Url", "remove_password")]
+pub async fn remove_password() -> Result<LoginMethods, ServerFnError> {
+    backend::remove_password().await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use super::*;
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::auth::password::hashing::{self, Verified};
+    use crate::user::{db, DbUser, PasswordHash};
+    use crate::AppState;
+    use leptos::use_context;
+    use leptos::ServerFnError::ServerError;
+    use surrealdb::sql::Thing;
+    use tracing::*;
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        PersonNotFound,
+        NotLinked,
+        NoPassword,
+        IncorrectPassword,
+        LastLoginMethod,
+        HashFailed(String),
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::PersonNotFound => "account no longer exists".to_string(),
+                Fail::NotLinked => "that account isn't linked".to_string(),
+                Fail::NoPassword => "you don't have a password".to_string(),
+                Fail::IncorrectPassword => "current password is incorrect".to_string(),
+                Fail::LastLoginMethod => {
+                    "you need some other way to sign in before removing this one".to_string()
+                }
+                Fail::HashFailed(e) => format!("failed to hash password: {}", e),
+            };
+            ServerError(msg)
+        }
+    }
+
+    async fn current() -> Result<(AppState, DbUser), ServerFnError> {
+        let person = Thing::from(&authz::require_session()?.person.id);
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        let user = db::find_by_id(&app_state.db, &person)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::PersonNotFound)?;
+        Ok((app_state, user))
+    }
+
+    async fn save(
+        app_state: &AppState,
+        user: &DbUser,
+        before: &LoginMethods,
+        action: &str,
+    ) -> Result<LoginMethods, ServerFnError> {
+        db::save_credentials(&app_state.db, &user.person.id, &user.credentials)
+            .await
+            .map_err(Fail::DbError)?;
+
+        let after = (&user.credentials).into();
+        let changes = audit::diff(Some(before), Some(&after));
+        audit::record(&app_state.db, action, &user.person.id, changes).await;
+        Ok(after)
+    }
+
+    pub async fn get() -> Result<LoginMethods, ServerFnError> {
+        let (_, user) = current().await?;
+        Ok((&user.credentials).into())
+    }
+
+    pub async fn unlink(provider: String, subject: String) -> Result<LoginMethods, ServerFnError> {
+        let (app_state, mut user) = current().await?;
+        let before = LoginMethods::from(&user.credentials);
+
+        if user.credentials.login_methods() <= 1 {
+            return Err(Fail::LastLoginMethod.into());
+        }
+
+        let identities = &mut user.credentials.identities;
+        let linked = identities.len();
+        identities.retain(|i| !(i.provider == provider && i.subject == subject));
+        if identities.len() == linked {
+            return Err(Fail::NotLinked.into());
+        }
+
+        info!("unlinked {} account from {}", provider, user.person.id);
+        save(&app_state, &user, &before, "unlink_identity").await
+    }
+
+    pub async fn set_password(
+        current_password: Option<String>,
+        new_password: String,
+    ) -> Result<LoginMethods, ServerFnError> {
+        let (app_state, mut user) = current().await?;
+        let before = LoginMethods::from(&user.credentials);
+        let cfg = &app_state.config.login.password_hashing;
+
+        if let Some(PasswordHash { hash, salt }) = user.credentials.password.clone() {
+            let current_password = current_password.ok_or(Fail::IncorrectPassword)?;
+            let verified = hashing::verify_blocking(cfg, current_password, hash, salt)
+                .await
+                .map_err(Fail::HashFailed)?;
+            if let Verified::No = verified {
+                return Err(Fail::IncorrectPassword.into());
+            }
+        }
+
+        let hash = hashing::hash_blocking(cfg, new_password)
+            .await
+            .map_err(Fail::HashFailed)?;
+        user.credentials.password = Some(PasswordHash { hash, salt: None });
+
+        info!("password set for {}", user.person.id);
+        save(&app_state, &user, &before, "set_password").await
+    }
+
+    pub async fn remove_password() -> Result<LoginMethods, ServerFnError> {
+        let (app_state, mut user) = current().await?;
+        let before = LoginMethods::from(&user.credentials);
+
+        if user.credentials.password.is_none() {
+            return Err(Fail::NoPassword.into());
+        }
+        if user.credentials.login_methods() <= 1 {
+            return Err(Fail::LastLoginMethod.into());
+        }
+
+        user.credentials.password = None;
+        info!("password removed for {}", user.person.id);
+        save(&app_state, &user, &before, "remove_password").await
+    }
+}
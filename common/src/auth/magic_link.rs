@@ -0,0 +1,232 @@
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::two_factor::SignInStep;
+
+// Emails a single-use link to /magic_link that signs in whoever opens it. Like password resets,
+// this succeeds whether or not the email belongs to anyone. There doesn't need to be an account
+// yet, one is made when the link is first used.
+#[leptos::server(RequestMagicLink, "/api", "Url", "request_magic_link")]
+pub async fn request_magic_link(
+    email: String,
+    return_url: Option<String>,
+) -> Result<(), ServerFnError> {
+    backend::request(email, return_url).await
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MagicLinkSignedIn {
+    pub return_url: String,
+    pub next: SignInStep,
+}
+
+// Sets the session cookie, creating the person first if there isn't one with the email address
+#[leptos::server(RedeemMagicLink, "/api", "Url", "redeem_magic_link")]
+pub async fn redeem_magic_link(token: String) -> Result<MagicLinkSignedIn, ServerFnError> {
+    backend::redeem(token).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use super::{MagicLinkSignedIn, SignInStep};
+    use crate::audit;
+    use crate::auth::session::create_session;
+    use crate::auth::{one_time_token, throttle};
+    use crate::axum::safe_return_url;
+    use crate::person::db::NewDbPerson;
+    use crate::user::{db, Credentials, DbUser, NewDbUser};
+    use crate::{mail, surreal, AppState};
+    use leptos::use_context;
+    use leptos::ServerFnError::{self, ServerError};
+    use serde::{Deserialize, Serialize};
+    use tracing::*;
+
+    const TABLE: &str = "magic_link";
+
+    #[derive(Serialize, Deserialize)]
+    struct MagicLink {
+        email: String,
+        return_url: String,
+    }
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        InvalidToken,
+        UserNotCreated,
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::InvalidToken => {
+                    "this sign in link is invalid, has expired or has already been used"
+                        .to_string()
+                }
+                Fail::UserNotCreated => "failed to create new user".to_string(),
+            };
+            ServerError(msg)
+        }
+    }
+
+    pub async fn request(email: String, return_url: Option<String>) -> Result<(), ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let email = email.trim().to_string();
+        if email.is_empty() {
+            return Ok(());
+        }
+
+        // Somebody locked out of guessing passwords doesn't get to send emails either. Every
+        // request counts as a failure until the link is used, so nobody can flood an inbox.
+        let throttle_cfg = &app_state.config.login.throttle;
+        let keys = throttle::keys(throttle_cfg, &email);
+        throttle::check(&app_state.db, &keys).await?;
+        throttle::record_failure(&app_state.db, throttle_cfg, &keys).await?;
+
+        // Only the most recent link should work
+        app_state
+            .db
+            .query(format!("DELETE {} WHERE email=$email;", TABLE))
+            .bind(("email", &email))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let minutes = app_state.config.login.magic_link_minutes;
+        let token = one_time_token::issue(
+            &app_state.db,
+            TABLE,
+            MagicLink {
+                email: email.clone(),
+                return_url: safe_return_url(return_url),
+            },
+            chrono::Duration::minutes(minutes),
+        )
+        .await
+        .map_err(Fail::DbError)?;
+
+        info!("sign in link requested for {}", email);
+        let link = app_state.config.public_link(&format!("/magic_link?token={}", token));
+        let body = format!(
+            "Hi,\n\n\
+             To sign in, follow this link within the next {} minutes:\n\n\
+             {}\n\n\
+             The link only works once. If you didn't ask for it you can ignore this email.\n",
+            minutes, link
+        );
+
+        mail::send(
+            &app_state.config.mail,
+            mail::Mail {
+                to: email,
+                subject: "Your sign in link".to_string(),
+                body,
+                attachments: Vec::new(),
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn redeem(token: String) -> Result<MagicLinkSignedIn, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let link: MagicLink = one_time_token::redeem(&app_state.db, TABLE, &token)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::InvalidToken)?;
+
+        let keys = throttle::keys(&app_state.config.login.throttle, &link.email);
+        throttle::record_success(&app_state.db, &keys).await?;
+
+        let existing = db::find_by_email(&app_state.db, &link.email)
+            .await
+            .map_err(Fail::DbError)?;
+
+        let user = match existing {
+            Some(user) => claim(&app_state, user).await?,
+            None => create(&app_state, link.email).await?,
+        };
+
+        let person = &user.person;
+        audit::record_as(&app_state.db, &person.id, &person.email, "sign_in_link", &person.id)
+            .await;
+
+        let next = SignInStep::after_first_factor(&user.credentials);
+        create_session(user.person.id.into(), next == SignInStep::Done).await?;
+        Ok(MagicLinkSignedIn {
+            return_url: link.return_url,
+            next,
+        })
+    }
+
+    async fn create(app_state: &AppState, email: String) -> Result<DbUser, Fail> {
+        info!("creating account for {} from sign in link", email);
+        let record: Option<surreal::Record> = app_state
+            .db
+            .create("person")
+            .content(NewDbUser {
+                person: NewDbPerson {
+                    given_name: "".to_string(),
+                    family_name: "".to_string(),
+                    picture: None,
+                    phone: None,
+                    email,
+                    email_verified: true,
+                },
+                credentials: Credentials::default(),
+            })
+            .await
+            .map_err(Fail::DbError)?
+            .pop();
+        let record = record.ok_or(Fail::UserNotCreated)?;
+
+        db::find_by_id(&app_state.db, &record.id)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::UserNotCreated)
+    }
+
+    // Opening the link proves the email address is theirs. As with OAuth, a password set before
+    // anyone had proved that could have been set by anyone, so it goes along with its sessions.
+    async fn claim(app_state: &AppState, user: DbUser) -> Result<DbUser, Fail> {
+        if user.person.email_verified {
+            return Ok(user);
+        }
+
+        let person = user.person.id.clone();
+        let mut credentials = user.credentials.clone();
+        if credentials.password.take().is_some() {
+            warn!("dropping unverified password for {} on link sign in", person);
+            app_state
+                .db
+                .query("DELETE session WHERE user=$person;")
+                .bind(("person", &person))
+                .await
+                .map_err(Fail::DbError)?
+                .check()
+                .map_err(Fail::DbError)?;
+            db::save_credentials(&app_state.db, &person, &credentials)
+                .await
+                .map_err(Fail::DbError)?;
+        }
+
+        app_state
+            .db
+            .query("UPDATE $person SET email_verified=true;")
+            .bind(("person", &person))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        Ok(DbUser {
+            credentials,
+            ..user
+        })
+    }
+}
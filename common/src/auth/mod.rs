@@ -1,4 +1,13 @@
+pub mod api_token;
+pub mod authz;
+pub mod login_methods;
+pub mod magic_link;
 pub mod oauth;
+pub mod oidc;
+pub mod one_time_token;
 pub mod password;
+pub mod reset;
 pub mod session;
-
+pub mod throttle;
+pub mod two_factor;
+pub mod verify;
@@ -1,103 +1,254 @@
 use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::two_factor::SignInStep;
+
+// What the sign in page needs to know to show a button for a provider
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct OAuthProviderInfo {
+    pub name: String,
+    pub label: String,
+    pub icon_url: String,
+}
+
+#[leptos::server(ListOAuthProviders, "/api", "Url", "list_oauth_providers")]
+pub async fn list_providers() -> Result<Vec<OAuthProviderInfo>, ServerFnError> {
+    backend::list_providers().await
+}
+
 // This guy generates an OAuth link by making a PkceCodeChallenge and storing it in the database
-// then generating a link to your OAuth provider (currently only one is supported, it would be
-// easy to expand it to multiple providers)
+// then generating a link to the named OAuth provider.
 //
 // Make this server function GetJSON so that it listens to GET requests, not POST as we directly
 // visit this endpoint in the browser.
+//
+// return_url is where to send people once they're signed in, it must be a path on this site.
+// With link set, the provider account is added to the signed in person rather than signing in.
 #[leptos::server(OAuthRedirect, "/api", "GetJson", "oauth_redirect")]
 #[allow(unused_braces)]
-pub async fn redirect() -> Result<String, ServerFnError> { backend::redirect().await }
+pub async fn redirect(
+    provider: String,
+    return_url: Option<String>,
+    link: Option<bool>,
+) -> Result<String, ServerFnError> {
+    backend::redirect(provider, return_url, link.unwrap_or(false)).await
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct OAuthSignedIn {
+    pub return_url: String,
+    pub next: SignInStep,
+}
 
 // Once the login is completed the OAuth provider will navigate us to this return page.
 // with a csrf token in the 'state' and an authorization code. We use the authorization code
 // to query the oauth provider to get OpenID identity information like email address and name.
 //
-// We create 'person' if one doesn't exist, then generate a session token and pop it in the
-// database.
+// We find the person with this provider account, or failing that the person with its (verified)
+// email address, or create a 'person' if there isn't one. Then generate a session token and pop
+// it in the database and the session cookie.
 #[leptos::server(OAuthCheck, "/api", "Url", "oauth_check")]
-pub async fn check(ouath_state: String, oauth_code: String) -> Result<String, ServerFnError> {
+pub async fn check(ouath_state: String, oauth_code: String) -> Result<OAuthSignedIn, ServerFnError> {
     backend::check(ouath_state, oauth_code).await
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 mod backend {
+    use super::{OAuthProviderInfo, OAuthSignedIn, SignInStep};
+    use crate::audit;
     use crate::auth::session::create_session;
+    use crate::auth::{authz, oidc};
+    use crate::axum::safe_return_url;
     use crate::person::db::NewDbPerson;
-    use crate::schema::Schema;
-    use crate::user::{Credentials, DbUser, NewDbUser, User};
+    use crate::user::{db, Credentials, DbUser, Identity, NewDbUser};
     use crate::{config, surreal, AppState};
-    use axum::extract::Host;
-    use leptos::logging::warn;
+    use tracing::{debug, warn};
     use leptos::use_context;
     use leptos::ServerFnError::{self, ServerError};
     use leptos_axum;
+    use oauth2::basic::{BasicErrorResponse, BasicRevocationErrorResponse};
+    use oauth2::basic::{BasicTokenIntrospectionResponse, BasicTokenType};
     use oauth2::reqwest::async_http_client;
-    use oauth2::{basic::BasicClient, CsrfToken, PkceCodeChallenge, RedirectUrl};
-    use oauth2::{AuthorizationCode, PkceCodeVerifier, Scope, TokenResponse};
-    use serde::{Deserialize, Serialize};
+    use oauth2::{AuthUrl, AuthorizationCode, PkceCodeVerifier, Scope, TokenResponse, TokenUrl};
+    use oauth2::{Client, CsrfToken, PkceCodeChallenge, RedirectUrl};
+    use oauth2::{ExtraTokenFields, StandardRevocableToken, StandardTokenResponse};
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize};
+    use surrealdb::sql::Thing;
 
     #[derive(Debug, Serialize, Deserialize)]
     struct OAuth2State {
         pkce_code_verifier: String,
         return_url: String,
+        provider: String,
+        nonce: String,
+        #[serde(default)]
+        link_to: Option<Thing>,
+        // For the janitor to clear out sign ins that never came back
+        #[serde(default)]
+        created_at: Option<DateTime<Utc>>,
+    }
+
+    // OpenID Connect providers hand back an ID token alongside the access token
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct IdTokenFields {
+        id_token: Option<String>,
+    }
+
+    impl ExtraTokenFields for IdTokenFields {}
+
+    type OidcClient = Client<
+        BasicErrorResponse,
+        StandardTokenResponse<IdTokenFields, BasicTokenType>,
+        BasicTokenType,
+        BasicTokenIntrospectionResponse,
+        StandardRevocableToken,
+        BasicRevocationErrorResponse,
+    >;
+
+    struct Endpoints {
+        auth_url: AuthUrl,
+        token_url: TokenUrl,
+        user_info_url: Option<String>,
     }
 
+    // Providers don't agree on what userinfo looks like, this takes the OpenID Connect claims
+    // and accepts GitHub's names for the ones it has too.
     #[derive(Debug, Deserialize)]
     #[allow(dead_code)]
-    struct OpenIDUserInfo {
-        given_name: String,
-        family_name: String,
-        picture: String,
-        email: String,
-        email_verified: bool,
+    struct UserInfo {
+        #[serde(alias = "id", deserialize_with = "string_or_number")]
         sub: String,
+        email: Option<String>,
+        email_verified: Option<bool>,
+        given_name: Option<String>,
+        family_name: Option<String>,
+        name: Option<String>,
+        #[serde(alias = "avatar_url")]
+        picture: Option<String>,
+    }
+
+    fn string_or_number<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum StringOrNumber {
+            String(String),
+            Number(i64),
+        }
+        Ok(match StringOrNumber::deserialize(d)? {
+            StringOrNumber::String(s) => s,
+            StringOrNumber::Number(n) => n.to_string(),
+        })
+    }
+
+    impl UserInfo {
+        // Fall back to splitting the full name if the provider doesn't give us the parts
+        fn names(&self) -> (String, String) {
+            match (&self.given_name, &self.family_name, &self.name) {
+                (Some(given), Some(family), _) => (given.clone(), family.clone()),
+                (_, _, Some(name)) => match name.split_once(' ') {
+                    Some((given, family)) => (given.to_string(), family.to_string()),
+                    None => (name.clone(), "".to_string()),
+                },
+                (given, family, None) => (
+                    given.clone().unwrap_or_default(),
+                    family.clone().unwrap_or_default(),
+                ),
+            }
+        }
     }
 
     #[derive(Debug)]
     enum Fail {
         NoServerState,
-        NoHostname,
         DbError(surrealdb::Error),
         NotCreated,
-        NoProviders,
+        UnknownProvider(String),
+        Misconfigured(String),
+        DiscoveryFailed(oidc::Fail),
         UnknownCsrfId,
+        NoIdToken,
+        InvalidIdToken(oidc::Fail),
+        UserInfoSubjectMismatch,
         UserInfoQueryError(reqwest::Error),
         UserInfoParseError(reqwest::Error),
+        UserEmailMissing,
         UserEmailNotVerified(String),
         UserNotCreated,
+        PersonNotFound,
+        IdentityInUse,
+        LinkedByOtherPerson,
     }
 
     impl From<Fail> for ServerFnError {
         fn from(fail: Fail) -> Self {
             let msg = match fail {
                 Fail::NoServerState => "no server state".to_string(),
-                Fail::NoHostname => "no hostname".to_string(),
                 Fail::DbError(e) => format!("database error: {:?}", e),
                 Fail::NotCreated => "oauth state record not created".to_string(),
-                Fail::NoProviders => "no oauth providers configured".to_string(),
+                Fail::UnknownProvider(p) => format!("no oauth provider called '{}' configured", p),
+                Fail::Misconfigured(e) => format!("oauth provider misconfigured: {}", e),
+                Fail::DiscoveryFailed(e) => format!("openid discovery failed: {:?}", e),
                 Fail::UnknownCsrfId => "unknown oauth csrf id".to_string(),
+                Fail::NoIdToken => "provider did not give us an id token".to_string(),
+                Fail::InvalidIdToken(e) => format!("invalid id token: {:?}", e),
+                Fail::UserInfoSubjectMismatch => "userinfo is for a different user".to_string(),
                 Fail::UserInfoQueryError(e) => format!("failed to query userinfo {:?}", e),
                 Fail::UserInfoParseError(e) => format!("failed to query userinfo {:?}", e),
+                Fail::UserEmailMissing => "provider did not give us an email address".to_string(),
                 Fail::UserEmailNotVerified(e) => format!("email address not verified : {:?}", e),
                 Fail::UserNotCreated => "failed to create new user".to_string(),
+                Fail::PersonNotFound => "account no longer exists".to_string(),
+                Fail::IdentityInUse => {
+                    "that account is already linked to somebody else".to_string()
+                }
+                Fail::LinkedByOtherPerson => {
+                    "sign in as the person who started linking this account first".to_string()
+                }
             };
             ServerError(msg)
         }
     }
 
-    pub async fn redirect() -> Result<String, ServerFnError> {
+    pub async fn list_providers() -> Result<Vec<OAuthProviderInfo>, ServerFnError> {
         let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
-        let hostname = use_context::<Host>().ok_or(Fail::NoHostname)?.0;
 
-        let cfg = first_config(&app_state)?;
-        let client = client(&cfg, hostname);
+        Ok(app_state
+            .config
+            .login
+            .oauth_providers
+            .iter()
+            .map(|p| OAuthProviderInfo {
+                name: p.name.clone(),
+                label: p.label.clone().unwrap_or(p.name.clone()),
+                icon_url: p.icon_url.clone(),
+            })
+            .collect())
+    }
+
+    pub async fn redirect(
+        provider: String,
+        return_url: Option<String>,
+        link: bool,
+    ) -> Result<String, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let link_to = match link {
+            true => Some(Thing::from(&authz::current_user()?.person.id)),
+            false => None,
+        };
+
+        let cfg = provider_config(&app_state, &provider)?;
+        let endpoints = endpoints(&cfg).await?;
+        let client = client(&app_state, &cfg, &endpoints)?;
 
         let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
+        let nonce = CsrfToken::new_random();
 
         let (authorize_url, csrf_state) = client
             .authorize_url(CsrfToken::new_random)
-            .add_scope(Scope::new("openid profile email".to_string()))
+            .add_scopes(cfg.scopes.iter().map(|s| Scope::new(s.clone())))
+            .add_extra_param("nonce", nonce.secret())
             .set_pkce_challenge(pkce_code_challenge)
             .url();
 
@@ -106,7 +257,11 @@ mod backend {
             .create(("oauth2_state", csrf_state.secret().clone()))
             .content(OAuth2State {
                 pkce_code_verifier: pkce_code_verifier.secret().clone(),
-                return_url: "/".to_string(),
+                return_url: safe_return_url(return_url),
+                provider,
+                nonce: nonce.secret().clone(),
+                link_to,
+                created_at: Some(Utc::now()),
             })
             .await
             .map_err(Fail::DbError)?
@@ -116,25 +271,31 @@ mod backend {
         Ok("redirecting!".to_string())
     }
 
-    pub async fn check(state: String, code: String) -> Result<String, ServerFnError> {
+    pub async fn check(state: String, code: String) -> Result<OAuthSignedIn, ServerFnError> {
         let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
-        let hostname = use_context::<Host>().ok_or(Fail::NoHostname)?.0;
 
         let state = CsrfToken::new(state);
         let code = AuthorizationCode::new(code);
 
-        let oauth_state: Result<Option<OAuth2State>, _> = app_state
+        let oauth_state: OAuth2State = app_state
             .db
             .delete(("oauth2_state", state.secret()))
             .await
-            .map_err(Fail::DbError);
-        warn!("oauth state: {:?}", &oauth_state);
-        let oauth_state = oauth_state?;
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::UnknownCsrfId)?;
+        debug!("oauth callback from {}", oauth_state.provider);
 
-        let oauth_state = oauth_state.ok_or(Fail::UnknownCsrfId)?;
+        // The state isn't tied to a browser, so without this someone could start linking on
+        // their own account and have somebody else finish it with theirs
+        if let Some(person) = &oauth_state.link_to {
+            if Thing::from(&authz::current_user()?.person.id) != *person {
+                return Err(Fail::LinkedByOtherPerson.into());
+            }
+        }
 
-        let cfg = first_config(&app_state)?;
-        let client = client(&cfg, hostname);
+        let cfg = provider_config(&app_state, &oauth_state.provider)?;
+        let endpoints = endpoints(&cfg).await?;
+        let client = client(&app_state, &cfg, &endpoints)?;
 
         let token_response = client
             .exchange_code(code)
@@ -144,81 +305,259 @@ mod backend {
 
         let access_token = token_response.access_token().secret();
 
-        let url =
-            "https://www.googleapis.com/oauth2/v3/userinfo?oauth_token=".to_owned() + access_token;
-        let user_info = reqwest::get(url)
-            .await
-            .map_err(Fail::UserInfoQueryError)?
-            .json::<OpenIDUserInfo>()
-            .await
-            .map_err(Fail::UserInfoParseError)?;
-        dbg!(&user_info);
+        // For OpenID Connect providers the signed ID token tells us who this is. Plain OAuth2
+        // providers only have their userinfo endpoint to go on.
+        let user_info = match &cfg.issuer {
+            Some(issuer) => {
+                let id_token = token_response
+                    .extra_fields()
+                    .id_token
+                    .as_ref()
+                    .ok_or(Fail::NoIdToken)?;
+                let claims: UserInfo = oidc::validate_id_token(
+                    issuer,
+                    cfg.client_id.as_str(),
+                    id_token,
+                    &oauth_state.nonce,
+                )
+                .await
+                .map_err(Fail::InvalidIdToken)?;
 
-        if !user_info.email_verified {
-            return Err(Fail::UserEmailNotVerified(user_info.email).into());
+                // Not every provider puts the profile in the ID token, fill it in from userinfo
+                // as long as it's about the same person.
+                match (&claims.email, &endpoints.user_info_url) {
+                    (None, Some(url)) => {
+                        let info = fetch_user_info(url, access_token).await?;
+                        if info.sub != claims.sub {
+                            return Err(Fail::UserInfoSubjectMismatch.into());
+                        }
+                        info
+                    }
+                    _ => claims,
+                }
+            }
+            None => {
+                let url = endpoints
+                    .user_info_url
+                    .as_ref()
+                    .ok_or(Fail::Misconfigured("no user_info_url".to_string()))?;
+                fetch_user_info(url, access_token).await?
+            }
+        };
+
+        let identity = Identity {
+            provider: oauth_state.provider.clone(),
+            subject: user_info.sub.clone(),
+            email: user_info.email.clone(),
+        };
+
+        if let Some(person) = oauth_state.link_to {
+            link(&app_state, &person, identity).await?;
+            audit::record(&app_state.db, "link_identity", &person, Vec::new()).await;
+            return Ok(OAuthSignedIn {
+                return_url: oauth_state.return_url,
+                next: SignInStep::Done,
+            });
         }
 
-        let mut result = app_state
-            .db
-            .query(format!(
-                "SELECT {} FROM {} where email=$email;",
-                User::SELECT,
-                User::TABLE
-            ))
-            .bind(("email", &user_info.email))
-            .await?;
+        let existing = db::find_by_identity(&app_state.db, &identity.provider, &identity.subject)
+            .await
+            .map_err(Fail::DbError)?;
 
-        let mut people: Vec<DbUser> = result.take(0)?;
+        let user = match existing {
+            Some(user) => user,
+            None => {
+                let email = user_info.email.clone().ok_or(Fail::UserEmailMissing)?;
+                if !user_info.email_verified.unwrap_or(cfg.trust_email) {
+                    return Err(Fail::UserEmailNotVerified(email).into());
+                }
 
-        let user = match people.pop() {
-            Some(person) => person,
-            None => app_state
-                .db
-                .create("person")
-                .content(NewDbUser {
-                    person: NewDbPerson {
-                        given_name: user_info.given_name,
-                        family_name: user_info.family_name,
-                        picture: Some(user_info.picture),
-                        phone: None,
-                        email: user_info.email,
-                    },
-                    credentials: Credentials::OAuth,
-                })
-                .await?
-                .pop()
-                .ok_or(Fail::UserNotCreated)?,
+                match db::find_by_email(&app_state.db, &email)
+                    .await
+                    .map_err(Fail::DbError)?
+                {
+                    Some(user) => adopt(&app_state, user, identity).await?,
+                    None => create(&app_state, email, user_info, identity).await?,
+                }
+            }
         };
 
+        let person = &user.person;
+        audit::record_as(&app_state.db, &person.id, &person.email, "sign_in_oauth", &person.id)
+            .await;
+
         // Create the session
-        let session = create_session(user.person.id.into()).await?;
-        Ok(session)
+        let next = SignInStep::after_first_factor(&user.credentials);
+        create_session(user.person.id.into(), next == SignInStep::Done).await?;
+        Ok(OAuthSignedIn {
+            return_url: oauth_state.return_url,
+            next,
+        })
+    }
+
+    async fn create(
+        app_state: &AppState,
+        email: String,
+        user_info: UserInfo,
+        identity: Identity,
+    ) -> Result<DbUser, Fail> {
+        let (given_name, family_name) = user_info.names();
+        app_state
+            .db
+            .create("person")
+            .content(NewDbUser {
+                person: NewDbPerson {
+                    given_name,
+                    family_name,
+                    picture: user_info.picture,
+                    phone: None,
+                    email,
+                    email_verified: true,
+                },
+                credentials: Credentials::identity(identity),
+            })
+            .await
+            .map_err(Fail::DbError)?
+            .pop()
+            .ok_or(Fail::UserNotCreated)
+    }
+
+    // The provider vouches for the email address, so this is the person who already has it.
+    // If they signed up with a password but never proved the address was theirs, that password
+    // could have been set by anyone, so it goes along with any sessions it got them.
+    async fn adopt(app_state: &AppState, user: DbUser, identity: Identity) -> Result<DbUser, Fail> {
+        let person = user.person.id.clone();
+        let mut credentials = user.credentials.clone();
+
+        if !user.person.email_verified {
+            if credentials.password.take().is_some() {
+                warn!("dropping unverified password for {} on oauth sign in", person);
+                app_state
+                    .db
+                    .query("DELETE session WHERE user=$person;")
+                    .bind(("person", &person))
+                    .await
+                    .map_err(Fail::DbError)?
+                    .check()
+                    .map_err(Fail::DbError)?;
+            }
+
+            app_state
+                .db
+                .query("UPDATE $person SET email_verified=true;")
+                .bind(("person", &person))
+                .await
+                .map_err(Fail::DbError)?
+                .check()
+                .map_err(Fail::DbError)?;
+        }
+
+        credentials.identities.push(identity);
+        db::save_credentials(&app_state.db, &person, &credentials)
+            .await
+            .map_err(Fail::DbError)?;
+        Ok(user)
     }
 
-    fn client(cfg: &config::OAuthProvider, hostname: String) -> BasicClient {
-        let scheme = if hostname.starts_with("localhost") {
-            "http"
-        } else {
-            "https"
+    async fn link(app_state: &AppState, person: &Thing, identity: Identity) -> Result<(), Fail> {
+        let existing = db::find_by_identity(&app_state.db, &identity.provider, &identity.subject)
+            .await
+            .map_err(Fail::DbError)?;
+
+        match existing {
+            Some(user) if &user.person.id == person => return Ok(()),
+            Some(_) => return Err(Fail::IdentityInUse),
+            None => {}
+        }
+
+        let user = db::find_by_id(&app_state.db, person)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::PersonNotFound)?;
+
+        let mut credentials = user.credentials;
+        credentials.identities.push(identity);
+        db::save_credentials(&app_state.db, person, &credentials)
+            .await
+            .map_err(Fail::DbError)
+    }
+
+    async fn fetch_user_info(url: &str, access_token: &str) -> Result<UserInfo, Fail> {
+        reqwest::Client::new()
+            .get(url)
+            .bearer_auth(access_token)
+            .header(reqwest::header::ACCEPT, "application/json")
+            // GitHub refuses requests without one
+            .header(reqwest::header::USER_AGENT, "happenings")
+            .send()
+            .await
+            .map_err(Fail::UserInfoQueryError)?
+            .error_for_status()
+            .map_err(Fail::UserInfoQueryError)?
+            .json::<UserInfo>()
+            .await
+            .map_err(Fail::UserInfoParseError)
+    }
+
+    // Anything set explicitly in the config wins over what the issuer tells us
+    async fn endpoints(cfg: &config::OAuthProvider) -> Result<Endpoints, Fail> {
+        let discovered = match &cfg.issuer {
+            Some(issuer) => Some(oidc::metadata(issuer).await.map_err(Fail::DiscoveryFailed)?),
+            None => None,
+        };
+        let bad_url = |e: oauth2::url::ParseError| Fail::Misconfigured(e.to_string());
+
+        let auth_url = match (&cfg.auth_url, &discovered) {
+            (Some(url), _) => url.clone(),
+            (None, Some(d)) => AuthUrl::new(d.authorization_endpoint.clone()).map_err(bad_url)?,
+            (None, None) => return Err(Fail::Misconfigured("no auth_url or issuer".to_string())),
+        };
+
+        let token_url = match (&cfg.token_url, &discovered) {
+            (Some(url), _) => url.clone(),
+            (None, Some(d)) => TokenUrl::new(d.token_endpoint.clone()).map_err(bad_url)?,
+            (None, None) => return Err(Fail::Misconfigured("no token_url or issuer".to_string())),
         };
-        let redirect_url = format!("{}://{}/oauth_return", scheme, hostname);
-        BasicClient::new(
+
+        let user_info_url = cfg
+            .user_info_url
+            .clone()
+            .or(discovered.and_then(|d| d.userinfo_endpoint));
+
+        Ok(Endpoints {
+            auth_url,
+            token_url,
+            user_info_url,
+        })
+    }
+
+    fn client(
+        app_state: &AppState,
+        cfg: &config::OAuthProvider,
+        endpoints: &Endpoints,
+    ) -> Result<OidcClient, ServerFnError> {
+        let redirect_url = RedirectUrl::new(app_state.config.public_link("/oauth_return"))
+            .map_err(|e| Fail::Misconfigured(format!("bad public_url: {}", e)))?;
+        Ok(OidcClient::new(
             cfg.client_id.clone(),
             Some(cfg.client_secret.clone()),
-            cfg.auth_url.clone(),
-            Some(cfg.token_url.clone()),
+            endpoints.auth_url.clone(),
+            Some(endpoints.token_url.clone()),
         )
-        .set_redirect_uri(RedirectUrl::new(redirect_url).unwrap())
+        .set_redirect_uri(redirect_url))
     }
 
-    fn first_config(state: &AppState) -> Result<config::OAuthProvider, Fail> {
+    fn provider_config(
+        state: &AppState,
+        name: &str,
+    ) -> Result<config::OAuthProvider, ServerFnError> {
         state
             .config
             .login
             .oauth_providers
-            .first()
+            .iter()
+            .find(|p| p.name == name)
             .cloned()
-            .ok_or(Fail::NoProviders)
+            .ok_or_else(|| Fail::UnknownProvider(name.to_string()).into())
     }
 }
-
@@ -0,0 +1,323 @@
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+// OpenID Connect discovery and ID token validation. The provider metadata and its signing keys
+// are fetched from the issuer the first time we need them and cached for a while after that.
+
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+const CACHE_FOR: Duration = Duration::from_secs(60 * 60);
+const REFETCH_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Metadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: String,
+}
+
+struct Provider {
+    metadata: Metadata,
+    jwks: JwkSet,
+    fetched_at: Instant,
+}
+
+static PROVIDERS: Lazy<RwLock<HashMap<String, Arc<Provider>>>> = Lazy::new(Default::default);
+
+#[derive(Debug)]
+pub enum Fail {
+    FetchError(String, reqwest::Error),
+    IssuerMismatch(String),
+    BadHeader(jsonwebtoken::errors::Error),
+    UnsupportedAlgorithm(Algorithm),
+    UnknownKey(Option<String>),
+    BadKey(jsonwebtoken::errors::Error),
+    InvalidToken(jsonwebtoken::errors::Error),
+    NonceMismatch,
+}
+
+#[derive(Deserialize)]
+struct WithNonce<T> {
+    nonce: Option<String>,
+    #[serde(flatten)]
+    claims: T,
+}
+
+pub async fn metadata(issuer: &str) -> Result<Metadata, Fail> {
+    Ok(provider(issuer, false).await?.metadata.clone())
+}
+
+// Check the signature, issuer, audience, expiry and nonce of an ID token and return its claims
+pub async fn validate_id_token<T: DeserializeOwned>(
+    issuer: &str,
+    client_id: &str,
+    id_token: &str,
+    nonce: &str,
+) -> Result<T, Fail> {
+    let header = jsonwebtoken::decode_header(id_token).map_err(Fail::BadHeader)?;
+
+    // Only accept signatures made with the provider's published keys
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(Fail::UnsupportedAlgorithm(header.alg));
+    }
+
+    let mut provider = provider(issuer, false).await?;
+
+    // The provider may have rotated its keys since we cached them. Don't let a stream of
+    // made up key ids have us hammering the issuer though.
+    let stale = provider.fetched_at.elapsed() > REFETCH_AFTER;
+    if find_key(&provider.jwks, &header.kid).is_none() && stale {
+        provider = self::provider(issuer, true).await?;
+    }
+
+    let jwk = find_key(&provider.jwks, &header.kid).ok_or(Fail::UnknownKey(header.kid.clone()))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(Fail::BadKey)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[client_id]);
+    validation.set_issuer(&[&provider.metadata.issuer]);
+
+    let token = jsonwebtoken::decode::<WithNonce<T>>(id_token, &key, &validation)
+        .map_err(Fail::InvalidToken)?;
+
+    if token.claims.nonce.as_deref() != Some(nonce) {
+        return Err(Fail::NonceMismatch);
+    }
+
+    Ok(token.claims.claims)
+}
+
+fn find_key<'a>(jwks: &'a JwkSet, kid: &Option<String>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        // Without a key id we can only be sure which key was meant if there is just the one
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+}
+
+async fn provider(issuer: &str, refresh: bool) -> Result<Arc<Provider>, Fail> {
+    if !refresh {
+        if let Some(p) = PROVIDERS.read().await.get(issuer) {
+            if p.fetched_at.elapsed() < CACHE_FOR {
+                return Ok(p.clone());
+            }
+        }
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+    let metadata: Metadata = get_json(&url).await?;
+
+    // The discovery document has to be for the issuer we asked about, otherwise anyone able
+    // to serve it could vouch for tokens from elsewhere.
+    if metadata.issuer != issuer {
+        return Err(Fail::IssuerMismatch(metadata.issuer));
+    }
+
+    let jwks: JwkSet = get_json(&metadata.jwks_uri).await?;
+
+    let provider = Arc::new(Provider {
+        metadata,
+        jwks,
+        fetched_at: Instant::now(),
+    });
+
+    PROVIDERS
+        .write()
+        .await
+        .insert(issuer.to_string(), provider.clone());
+    Ok(provider)
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, Fail> {
+    let fail = |e| Fail::FetchError(url.to_string(), e);
+    reqwest::get(url)
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(fail)?
+        .json()
+        .await
+        .map_err(fail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing::get, Json, Router};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const CLIENT_ID: &str = "happenings";
+    const NONCE: &str = "the-nonce";
+    const KEY_ID: &str = "test-key";
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        iss: &'a str,
+        aud: &'a str,
+        sub: &'a str,
+        exp: i64,
+        nonce: &'a str,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Subject {
+        sub: String,
+    }
+
+    #[derive(Clone)]
+    struct Issuer {
+        url: String,
+        jwks_fetches: Arc<AtomicUsize>,
+    }
+
+    // Serves the discovery document and keys from a port of its own, so each test gets an
+    // issuer nothing else has cached
+    async fn issuer() -> Issuer {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = Issuer {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            jwks_fetches: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let discovery = |State(issuer): State<Issuer>| async move {
+            Json(serde_json::json!({
+                "issuer": issuer.url,
+                "authorization_endpoint": format!("{}/authorize", issuer.url),
+                "token_endpoint": format!("{}/token", issuer.url),
+                "jwks_uri": format!("{}/jwks", issuer.url),
+            }))
+        };
+        let jwks = |State(issuer): State<Issuer>| async move {
+            issuer.jwks_fetches.fetch_add(1, Ordering::SeqCst);
+            include_str!("testdata/oidc_jwks.json")
+        };
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .with_state(issuer.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        issuer
+    }
+
+    fn claims(issuer: &str) -> TestClaims<'_> {
+        TestClaims {
+            iss: issuer,
+            aud: CLIENT_ID,
+            sub: "someone",
+            exp: chrono::Utc::now().timestamp() + 600,
+            nonce: NONCE,
+        }
+    }
+
+    fn sign(claims: &TestClaims) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(KEY_ID.to_string());
+        let key = EncodingKey::from_rsa_pem(include_bytes!("testdata/oidc_key.pem")).unwrap();
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    async fn validate(issuer: &Issuer, token: &str) -> Result<Subject, Fail> {
+        validate_id_token(&issuer.url, CLIENT_ID, token, NONCE).await
+    }
+
+    #[tokio::test]
+    async fn accepts_a_good_token() {
+        let issuer = issuer().await;
+        let subject = validate(&issuer, &sign(&claims(&issuer.url))).await.unwrap();
+        assert_eq!(subject.sub, "someone");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_tampered_token() {
+        let issuer = issuer().await;
+        let token = sign(&claims(&issuer.url));
+        let other = sign(&TestClaims {
+            sub: "someone else",
+            ..claims(&issuer.url)
+        });
+
+        // Their claims with our signature
+        let parts: Vec<&str> = token.split('.').collect();
+        let other_claims = other.split('.').nth(1).unwrap();
+        let tampered = format!("{}.{}.{}", parts[0], other_claims, parts[2]);
+        let result = validate(&issuer, &tampered).await;
+        assert!(matches!(result, Err(Fail::InvalidToken(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_audience() {
+        let issuer = issuer().await;
+        let token = sign(&TestClaims {
+            aud: "someone-else",
+            ..claims(&issuer.url)
+        });
+        let result = validate(&issuer, &token).await;
+        assert!(matches!(result, Err(Fail::InvalidToken(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_expired_token() {
+        let issuer = issuer().await;
+        let token = sign(&TestClaims {
+            exp: chrono::Utc::now().timestamp() - 600,
+            ..claims(&issuer.url)
+        });
+        let result = validate(&issuer, &token).await;
+        assert!(matches!(result, Err(Fail::InvalidToken(_))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_nonce() {
+        let issuer = issuer().await;
+        let token = sign(&TestClaims {
+            nonce: "another-nonce",
+            ..claims(&issuer.url)
+        });
+        let result = validate(&issuer, &token).await;
+        assert!(matches!(result, Err(Fail::NonceMismatch)), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn rejects_shared_secret_signatures() {
+        let issuer = issuer().await;
+        for alg in [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512] {
+            let key = EncodingKey::from_secret(b"anyone could know this");
+            let token = jsonwebtoken::encode(&Header::new(alg), &claims(&issuer.url), &key)
+                .unwrap();
+            let result = validate(&issuer, &token).await;
+            assert!(matches!(result, Err(Fail::UnsupportedAlgorithm(_))), "{:?}", result);
+        }
+        assert_eq!(issuer.jwks_fetches.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn caches_the_keys() {
+        let issuer = issuer().await;
+        let token = sign(&claims(&issuer.url));
+        validate(&issuer, &token).await.unwrap();
+        validate(&issuer, &token).await.unwrap();
+        assert_eq!(issuer.jwks_fetches.load(Ordering::SeqCst), 1);
+
+        // A key id we've never heard of so soon after fetching doesn't send us back for more
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("made-up".to_string());
+        let key = EncodingKey::from_rsa_pem(include_bytes!("testdata/oidc_key.pem")).unwrap();
+        let token = jsonwebtoken::encode(&header, &claims(&issuer.url), &key).unwrap();
+        let result = validate(&issuer, &token).await;
+        assert!(matches!(result, Err(Fail::UnknownKey(_))), "{:?}", result);
+        assert_eq!(issuer.jwks_fetches.load(Ordering::SeqCst), 1);
+    }
+}
+
+}}
@@ -0,0 +1,60 @@
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+// Random tokens that we email to people, e.g. in password reset links. Only a hash of the
+// token is kept, as the record id, and redeeming it deletes the record so it can't be used twice.
+
+use chrono::{DateTime, Duration, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha256::Sha256Digest;
+use surrealdb::{engine::any::Any, Surreal};
+
+use crate::surreal;
+
+#[derive(Serialize, Deserialize)]
+struct Stored<T> {
+    expires_at: DateTime<Utc>,
+    #[serde(flatten)]
+    payload: T,
+}
+
+pub async fn issue<T>(
+    db: &Surreal<Any>,
+    table: &str,
+    payload: T,
+    ttl: Duration,
+) -> Result<String, surrealdb::Error>
+where
+    T: Serialize,
+{
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+    let _: Option<surreal::Record> = db
+        .create((table, Sha256Digest::digest(&token)))
+        .content(Stored {
+            expires_at: Utc::now() + ttl,
+            payload,
+        })
+        .await?;
+
+    Ok(token)
+}
+
+// Returns the payload the token was issued with, or None if it is unknown, used or expired
+pub async fn redeem<T>(
+    db: &Surreal<Any>,
+    table: &str,
+    token: &str,
+) -> Result<Option<T>, surrealdb::Error>
+where
+    T: DeserializeOwned,
+{
+    let stored: Option<Stored<T>> = db.delete((table, Sha256Digest::digest(token))).await?;
+
+    Ok(stored
+        .filter(|s| s.expires_at > Utc::now())
+        .map(|s| s.payload))
+}
+
+}}
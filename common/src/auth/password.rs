@@ -1,5 +1,7 @@
 use leptos::ServerFnError;
 
+use crate::auth::two_factor::SignInStep;
+
 #[leptos::server(SignUpPassword, "/api", "Url", "signup_password")]
 pub async fn signup_password(
     email: String,
@@ -11,32 +13,167 @@ pub async fn signup_password(
     backend::signup(email, password, given_name, family_name, phone).await
 }
 
+// Sets the session cookie on success. Whether the email has no account, has no password or the
+// password is wrong, the answer is the same so this can't be used to find out who has an account.
 #[leptos::server(SignInPassword, "/api", "Url", "signin_password")]
-pub async fn signin(email: String, password: String) -> Result<String, ServerFnError> {
+pub async fn signin(email: String, password: String) -> Result<SignInStep, ServerFnError> {
     backend::signin(email, password).await
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod hashing {
+    use crate::config::PasswordHashing;
+    use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+    use argon2::{Algorithm, Argon2, Params, Version};
+    use sha256::Sha256Digest;
+
+    #[derive(Debug)]
+    pub enum Verified {
+        No,
+        Yes { needs_rehash: bool },
+    }
+
+    fn argon2(cfg: &PasswordHashing) -> Result<Argon2<'static>, argon2::Error> {
+        let params = Params::new(cfg.memory_kib, cfg.iterations, cfg.parallelism, None)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    // Hash a password into an Argon2id PHC string, e.g. $argon2id$v=19$m=19456,t=2,p=1$...
+    pub fn hash(cfg: &PasswordHashing, password: &str) -> Result<String, argon2::password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2(cfg)?.hash_password(password.as_bytes(), &salt)?;
+        Ok(hash.to_string())
+    }
+
+    // Argon2 is deliberately slow, so keep it off the async executor
+    pub async fn hash_blocking(cfg: &PasswordHashing, password: String) -> Result<String, String> {
+        let cfg = cfg.clone();
+        tokio::task::spawn_blocking(move || hash(&cfg, &password))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())
+    }
+
+    pub async fn verify_blocking(
+        cfg: &PasswordHashing,
+        password: String,
+        hash: String,
+        legacy_salt: Option<String>,
+    ) -> Result<Verified, String> {
+        let cfg = cfg.clone();
+        tokio::task::spawn_blocking(move || verify(&cfg, &password, &hash, legacy_salt.as_deref()))
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Check a password against a stored hash. `legacy_salt` is only present for accounts still
+    // on the old salted sha256 scheme, which always need rehashing.
+    pub fn verify(
+        cfg: &PasswordHashing,
+        password: &str,
+        hash: &str,
+        legacy_salt: Option<&str>,
+    ) -> Verified {
+        if let Some(salt) = legacy_salt {
+            let salted = format!("{}{}", salt, password);
+            return match Sha256Digest::digest(salted) == hash {
+                true => Verified::Yes { needs_rehash: true },
+                false => Verified::No,
+            };
+        }
+
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return Verified::No;
+        };
+
+        let Ok(argon2) = argon2(cfg) else {
+            return Verified::No;
+        };
+
+        if argon2.verify_password(password.as_bytes(), &parsed).is_err() {
+            return Verified::No;
+        }
+
+        let current = Params::try_from(&parsed).ok();
+        let needs_rehash = parsed.algorithm != Algorithm::Argon2id.ident()
+            || !current.is_some_and(|p| {
+                (p.m_cost(), p.t_cost(), p.p_cost())
+                    == (cfg.memory_kib, cfg.iterations, cfg.parallelism)
+            });
+
+        Verified::Yes { needs_rehash }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Small enough to keep the tests quick
+        fn cfg(iterations: u32) -> PasswordHashing {
+            PasswordHashing {
+                memory_kib: 64,
+                iterations,
+                parallelism: 1,
+            }
+        }
+
+        fn is_yes(verified: Verified, rehash: bool) -> bool {
+            matches!(verified, Verified::Yes { needs_rehash } if needs_rehash == rehash)
+        }
+
+        #[test]
+        fn verifies_a_current_hash() {
+            let hash = hash(&cfg(1), "correct horse").unwrap();
+            assert!(hash.starts_with("$argon2id$"));
+            assert!(is_yes(verify(&cfg(1), "correct horse", &hash, None), false));
+            assert!(matches!(verify(&cfg(1), "wrong horse", &hash, None), Verified::No));
+        }
+
+        #[test]
+        fn rehashes_when_the_parameters_change() {
+            let hash = hash(&cfg(1), "correct horse").unwrap();
+            assert!(is_yes(verify(&cfg(2), "correct horse", &hash, None), true));
+        }
+
+        #[test]
+        fn verifies_and_rehashes_legacy_hashes() {
+            let legacy = Sha256Digest::digest("saltcorrect horse");
+            let verified = verify(&cfg(1), "correct horse", &legacy, Some("salt"));
+            assert!(is_yes(verified, true));
+            let verified = verify(&cfg(1), "wrong horse", &legacy, Some("salt"));
+            assert!(matches!(verified, Verified::No));
+        }
+
+        #[test]
+        fn refuses_garbage() {
+            assert!(matches!(verify(&cfg(1), "anything", "not a hash", None), Verified::No));
+            assert!(matches!(verify(&cfg(1), "", "", None), Verified::No));
+        }
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 mod backend {
+    use super::hashing::{self, Verified};
+    use super::SignInStep;
+    use crate::audit;
     use crate::auth::session::create_session;
+    use crate::auth::throttle;
+    use crate::auth::verify::send_verification;
     use crate::person::db::NewDbPerson;
-    use crate::schema::Schema;
-    use crate::user::{Credentials, DbUser, NewDbUser, User};
+    use crate::user::{db, Credentials, DbUser, NewDbUser, PasswordHash};
     use leptos::{use_context, ServerFnError};
-    use rand::distributions::{Alphanumeric, DistString};
-    use sha256::Sha256Digest;
 
     use tracing::*;
 
-    use crate::{surreal, AppState};
+    use crate::{mail, surreal, AppState};
 
     enum Fail {
         NoServerState,
         DbError(surrealdb::Error),
-        NoUser(String),
-        WrongCreds(String),
         UserCreateFailed,
-        IncorrectPassword(String),
+        BadCredentials,
+        HashFailed(String),
     }
 
     // TODO: status codes for unauthorized..
@@ -45,17 +182,9 @@ mod backend {
             let msg = match fail {
                 Fail::NoServerState => "no server state".to_string(),
                 Fail::DbError(e) => format!("database error: {:?}", e),
-                Fail::NoUser(email) => {
-                    format!("user with email {} does not exist in database", email)
-                }
-                Fail::WrongCreds(email) => format!(
-                    "user with email {} exists, but associated with oauth login",
-                    email
-                ),
                 Fail::UserCreateFailed => "user not created in db".to_string(),
-                Fail::IncorrectPassword(email) => {
-                    format!("incorrect password for account with email {}", email)
-                }
+                Fail::BadCredentials => "incorrect email address or password".to_string(),
+                Fail::HashFailed(e) => format!("failed to hash password: {}", e),
             };
             ServerFnError::ServerError(msg)
         }
@@ -71,66 +200,135 @@ mod backend {
         info!("new user: {:?}", email);
         let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
 
-        let salt = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-        let hash = make_hash(&salt, password);
+        let hash = hash_password(&app_state, password).await?;
 
-        let _foo: surreal::Record = app_state
+        // Answer just the same as for a new account, and let the owner know by email instead
+        if db::find_by_email(&app_state.db, &email)
+            .await
+            .map_err(Fail::DbError)?
+            .is_some()
+        {
+            return already_registered(&app_state, &email).await;
+        }
+
+        let record: surreal::Record = app_state
             .db
             .create("person")
             .content(NewDbUser {
                 person: NewDbPerson {
-                    given_name,
+                    given_name: given_name.clone(),
                     family_name,
                     picture: None,
-                    email,
+                    email: email.clone(),
+                    email_verified: false,
                     phone,
                 },
-                credentials: Credentials::Password { hash, salt },
+                credentials: Credentials::password(hash),
             })
             .await
             .map_err(Fail::DbError)?
             .pop()
             .ok_or(Fail::UserCreateFailed)?;
-        Ok(())
+
+        audit::record_as(&app_state.db, &record.id, &email, "sign_up", &record.id).await;
+        send_verification(&app_state, record.id, &given_name, &email).await
     }
 
-    pub async fn signin(email: String, password: String) -> Result<String, ServerFnError> {
+    pub async fn signin(email: String, password: String) -> Result<SignInStep, ServerFnError> {
         let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        let throttle_cfg = &app_state.config.login.throttle;
+        let keys = throttle::keys(throttle_cfg, &email);
+        throttle::check(&app_state.db, &keys).await?;
 
-        let mut users: Vec<DbUser> = app_state
-            .db
-            .query(format!(
-                "select {} from {} where email=$email",
-                User::SELECT,
-                User::TABLE
-            ))
-            .bind(("email", &email))
+        let user = db::find_by_email(&app_state.db, &email)
             .await
-            .map_err(Fail::DbError)?
-            .take(0)?;
+            .map_err(Fail::DbError)?;
+
+        let cfg = &app_state.config.login.password_hashing;
+        let found = user.and_then(|u| u.credentials.password.clone().map(|p| (u, p)));
+        let Some((user, PasswordHash { hash, salt })) = found else {
+            // Spend about as long as checking a real password would, so the response time
+            // doesn't give away whether there's an account
+            hashing::hash_blocking(cfg, password)
+                .await
+                .map_err(Fail::HashFailed)?;
+            throttle::record_failure(&app_state.db, throttle_cfg, &keys).await?;
+            return Err(Fail::BadCredentials.into());
+        };
 
-        let user = users.pop().ok_or(Fail::NoUser(email.clone()))?;
+        let verified = hashing::verify_blocking(cfg, password.clone(), hash, salt)
+            .await
+            .map_err(Fail::HashFailed)?;
 
-        match user.credentials {
-            Credentials::OAuth => return Err(Fail::WrongCreds(email.clone()).into()),
-            Credentials::Password { hash, salt } => {
-                make_hash(salt, password)
-                    .eq(&hash)
-                    .then_some(())
-                    .ok_or(Fail::IncorrectPassword(email))?;
+        match verified {
+            Verified::No => {
+                info!("failed password sign in for {}", email);
+                throttle::record_failure(&app_state.db, throttle_cfg, &keys).await?;
+                return Err(Fail::BadCredentials.into());
+            }
+            Verified::Yes { needs_rehash: false } => {}
+            Verified::Yes { needs_rehash: true } => {
+                // A failed upgrade shouldn't stop anyone signing in, we'll try again next time
+                if let Err(e) = rehash(&app_state, &user, password).await {
+                    warn!("failed to upgrade password hash for {}: {:?}", email, e);
+                }
             }
         }
 
-        Ok(create_session(user.person.id.into()).await?)
+        throttle::record_success(&app_state.db, &keys).await?;
+
+        let person = &user.person;
+        audit::record_as(&app_state.db, &person.id, &person.email, "sign_in_password", &person.id)
+            .await;
+
+        let next = SignInStep::after_first_factor(&user.credentials);
+        create_session(user.person.id.into(), next == SignInStep::Done).await?;
+        Ok(next)
     }
 
-    fn make_hash<T, S>(salt: T, pw: S) -> String
-    where
-        T: AsRef<str>,
-        S: AsRef<str>,
-    {
-        let salted = format!("{}{}", salt.as_ref(), pw.as_ref());
-        Sha256Digest::digest(salted)
+    async fn already_registered(app_state: &AppState, email: &str) -> Result<(), ServerFnError> {
+        info!("sign up attempted for existing account {}", email);
+        let body = format!(
+            "Hi,\n\n\
+             Someone tried to create a new account with this email address, but you already \
+             have one. If it was you, you can sign in at {} and use \"Forgotten your \
+             password?\" if you need to.\n\n\
+             If it wasn't you, you can ignore this email.\n",
+            app_state.config.public_link("/")
+        );
+
+        mail::send(
+            &app_state.config.mail,
+            mail::Mail {
+                to: email.to_string(),
+                subject: "You already have an account".to_string(),
+                body,
+                attachments: Vec::new(),
+            },
+        )
+        .await?;
+        Ok(())
     }
-}
 
+    async fn rehash(
+        app_state: &AppState,
+        user: &DbUser,
+        password: String,
+    ) -> Result<(), ServerFnError> {
+        info!("upgrading password hash for {}", user.person.id);
+        let hash = hash_password(app_state, password).await?;
+
+        let mut credentials = user.credentials.clone();
+        credentials.password = Some(PasswordHash { hash, salt: None });
+        db::save_credentials(&app_state.db, &user.person.id, &credentials)
+            .await
+            .map_err(Fail::DbError)?;
+        Ok(())
+    }
+
+    async fn hash_password(app_state: &AppState, password: String) -> Result<String, Fail> {
+        hashing::hash_blocking(&app_state.config.login.password_hashing, password)
+            .await
+            .map_err(Fail::HashFailed)
+    }
+}
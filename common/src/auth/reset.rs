@@ -0,0 +1,181 @@
+use leptos::ServerFnError;
+
+// Emails a single-use link to /reset_password. This succeeds whether or not the email belongs
+// to anyone, so it can't be used to find out who has an account.
+#[leptos::server(RequestPasswordReset, "/api", "Url", "request_password_reset")]
+pub async fn request_password_reset(email: String) -> Result<(), ServerFnError> {
+    backend::request(email).await
+}
+
+#[leptos::server(CompletePasswordReset, "/api", "Url", "complete_password_reset")]
+pub async fn complete_password_reset(
+    token: String,
+    new_password: String,
+) -> Result<(), ServerFnError> {
+    backend::complete(token, new_password).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use crate::audit;
+    use crate::auth::one_time_token;
+    use crate::auth::password::hashing;
+    use crate::schema::Schema;
+    use crate::user::{db, DbUser, PasswordHash, User};
+    use crate::{mail, AppState};
+    use leptos::use_context;
+    use leptos::ServerFnError::{self, ServerError};
+    use serde::{Deserialize, Serialize};
+    use surrealdb::sql::Thing;
+    use tracing::*;
+
+    const TABLE: &str = "password_reset";
+
+    #[derive(Serialize, Deserialize)]
+    struct PasswordReset {
+        person: Thing,
+    }
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        InvalidToken,
+        HashFailed(String),
+        PersonNotFound,
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::InvalidToken => {
+                    "this reset link is invalid, has expired or has already been used".to_string()
+                }
+                Fail::HashFailed(e) => format!("failed to hash password: {}", e),
+                Fail::PersonNotFound => "account no longer exists".to_string(),
+            };
+            ServerError(msg)
+        }
+    }
+
+    // The lookup and the email happen after we've answered, so how long the answer takes
+    // doesn't give away whether there's an account
+    pub async fn request(email: String) -> Result<(), ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        tokio::spawn(async move {
+            if let Err(e) = send_reset(&app_state, email).await {
+                error!("failed to send password reset: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    async fn send_reset(app_state: &AppState, email: String) -> Result<(), ServerFnError> {
+        let mut users: Vec<DbUser> = app_state
+            .db
+            .query(format!(
+                "SELECT {} FROM {} WHERE email=$email;",
+                User::SELECT,
+                User::TABLE
+            ))
+            .bind(("email", &email))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)?;
+
+        let Some(user) = users.pop() else {
+            info!("password reset requested for unknown email {}", email);
+            return Ok(());
+        };
+
+        if user.credentials.password.is_none() {
+            info!("password reset requested for oauth account {}", email);
+            return Ok(());
+        }
+
+        // Only the most recent link should work
+        app_state
+            .db
+            .query(format!("DELETE {} WHERE person=$person;", TABLE))
+            .bind(("person", &user.person.id))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let ttl = chrono::Duration::minutes(app_state.config.login.password_reset_minutes);
+        let token = one_time_token::issue(
+            &app_state.db,
+            TABLE,
+            PasswordReset {
+                person: user.person.id,
+            },
+            ttl,
+        )
+        .await
+        .map_err(Fail::DbError)?;
+
+        let link = app_state.config.public_link(&format!("/reset_password?token={}", token));
+        let body = format!(
+            "Hi {},\n\n\
+             Somebody (hopefully you) asked to reset the password for your account. \
+             To choose a new password, follow this link within the next {} minutes:\n\n\
+             {}\n\n\
+             If you didn't ask for this you can ignore this email.\n",
+            user.person.given_name, app_state.config.login.password_reset_minutes, link
+        );
+
+        mail::send(
+            &app_state.config.mail,
+            mail::Mail {
+                to: email,
+                subject: "Reset your password".to_string(),
+                body,
+                attachments: Vec::new(),
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn complete(token: String, new_password: String) -> Result<(), ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let reset: PasswordReset = one_time_token::redeem(&app_state.db, TABLE, &token)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::InvalidToken)?;
+
+        let hash = hashing::hash_blocking(&app_state.config.login.password_hashing, new_password)
+            .await
+            .map_err(Fail::HashFailed)?;
+
+        let user = db::find_by_id(&app_state.db, &reset.person)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::PersonNotFound)?;
+
+        let mut credentials = user.credentials;
+        credentials.password = Some(PasswordHash { hash, salt: None });
+        db::save_credentials(&app_state.db, &reset.person, &credentials)
+            .await
+            .map_err(Fail::DbError)?;
+
+        // Whoever knew the old password shouldn't stay signed in
+        app_state
+            .db
+            .query("DELETE session WHERE user=$person;")
+            .bind(("person", &reset.person))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        info!("password reset for {}", reset.person);
+        let email = &user.person.email;
+        audit::record_as(&app_state.db, &reset.person, email, "reset_password", &reset.person)
+            .await;
+        Ok(())
+    }
+}
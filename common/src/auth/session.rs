@@ -1,30 +1,94 @@
+use chrono::{DateTime, Utc};
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+// A session as shown to the person it belongs to. Session ids are as good as a password, so
+// the page only gets a hash of each one to refer to it by.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SessionInfo {
+    pub handle: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub current: bool,
+}
+
+#[leptos::server(Logout, "/api", "Url", "logout")]
+pub async fn logout() -> Result<(), ServerFnError> { backend::logout().await }
+
+#[leptos::server(LogoutEverywhere, "/api", "Url", "logout_everywhere")]
+pub async fn logout_everywhere() -> Result<(), ServerFnError> {
+    backend::logout_everywhere().await
+}
+
+#[leptos::server(ListSessions, "/api", "Url", "list_sessions")]
+pub async fn list_sessions() -> Result<Vec<SessionInfo>, ServerFnError> {
+    backend::list_sessions().await
+}
+
+#[leptos::server(RevokeSession, "/api", "Url", "revoke_session")]
+pub async fn revoke_session(handle: String) -> Result<(), ServerFnError> {
+    backend::revoke_session(handle).await
+}
+
 cfg_if::cfg_if! {
     if #[cfg(not(target_arch = "wasm32"))] {
 
+use axum::http::{header::SET_COOKIE, HeaderValue};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use axum_extra::extract::CookieJar;
 use chrono::Duration;
-use chrono::{DateTime, Utc};
 use leptos::use_context;
-use leptos::ServerFnError::{self, ServerError};
-use serde::{Deserialize, Serialize};
-use std::ops::Add;
+use leptos_axum::ResponseOptions;
+use leptos::ServerFnError::ServerError;
+use sha256::Sha256Digest;
+use surrealdb::engine::any::Any;
 use surrealdb::sql::Thing;
+use surrealdb::Surreal;
 use crate::AppState;
+use crate::axum::is_https;
+use crate::config;
 use crate::person::PersonId;
 use crate::generic_id::Id;
 use crate::schema::Schema;
 
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NewDbSession {
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
     pub user: Thing,
+    pub user_agent: Option<String>,
+    pub second_factor: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DbSession {
     pub id: Thing,
+    // Sessions from before we kept these will look ancient and be expired straight away
+    #[serde(default)]
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub last_seen_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
     pub user: Thing,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    // Whether the second factor was checked, by an authenticator code or recovery code
+    #[serde(default)]
+    pub second_factor: bool,
+}
+
+impl DbSession {
+    // Expiry slides along as the session is used, up to the absolute maximum
+    pub fn expiry(&self, cfg: &config::SessionLifetime, now: DateTime<Utc>) -> DateTime<Utc> {
+        let idle = now + Duration::hours(cfg.idle_hours);
+        let max = self.created_at + Duration::days(cfg.max_days);
+        idle.min(max)
+    }
+
+    pub fn handle(&self) -> String { Sha256Digest::digest(self.id.id.to_raw()) }
 }
 
 pub type SessionId = Id<Session>;
@@ -34,6 +98,7 @@ pub struct Session {
     pub id: SessionId,
     pub expires_at: DateTime<Utc>,
     pub user: PersonId,
+    pub second_factor: bool,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -43,6 +108,7 @@ impl From<DbSession> for Session {
             id: item.id.into(),
             expires_at: item.expires_at,
             user: item.user.into(),
+            second_factor: item.second_factor,
         }
     }
 }
@@ -51,10 +117,16 @@ impl Schema for Session {
     const TABLE: &'static str = "session";
 }
 
+// Don't bother writing to the database on every request just to say we saw the session
+const TOUCH_AFTER: i64 = 5;
+
+pub const COOKIE: &str = "session_id";
+
 pub enum Fail {
     NoAppState,
     DbError(surrealdb::Error),
     NotCreated,
+    UnknownSession,
 }
 
 impl From<Fail> for ServerFnError {
@@ -63,32 +135,265 @@ impl From<Fail> for ServerFnError {
             Fail::NoAppState => "no app state in context".to_string(),
             Fail::DbError(e) => format!("database error: {:?}", e),
             Fail::NotCreated => "session not created".to_string(),
+            Fail::UnknownSession => "no such session".to_string(),
         };
         ServerError(msg)
     }
 }
 
-pub async fn create_session(person_id: PersonId) -> Result<String, Fail> {
+// Starts a session and hands the browser its cookie. If the person has a second factor and it
+// hasn't been checked yet, the session is only good for checking it.
+pub async fn create_session(person_id: PersonId, second_factor: bool) -> Result<(), Fail> {
     let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+    let user: Thing = person_id.into();
+
+    let user_agent = use_context::<axum::http::request::Parts>()
+        .and_then(|p| p.headers.get(axum::http::header::USER_AGENT).cloned())
+        .and_then(|ua| ua.to_str().ok().map(|s| s.to_string()));
 
+    delete_expired(&app_state.db, Some(&user)).await?;
+
+    let now = Utc::now();
+    let cfg = &app_state.config.login.session;
     let session_record: crate::surreal::Record = app_state
         .db
         .create("session")
         .content(NewDbSession {
-            expires_at: chrono::Utc::now().add(Duration::hours(24)),
-            // expires_at: chrono::DateTime::from_naive_utc_and_offset(
-            //     chrono::Utc::now().naive_utc(),
-            //     chrono::Duration::hours(24),
-            // ),
-            user: person_id.into(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: (now + Duration::hours(cfg.idle_hours))
+                .min(now + Duration::days(cfg.max_days)),
+            user,
+            user_agent,
+            second_factor,
         })
         .await
         .map_err(Fail::DbError)?
         .pop()
         .ok_or(Fail::NotCreated)?;
 
-    Ok(session_record.id.id.to_string())
+    set_cookie(session_cookie(session_record.id.id.to_string()));
+    Ok(())
 }
 
-}}
+// The session id only ever lives in an HttpOnly cookie, so scripts on the page (including any
+// that shouldn't be there) can't read it.
+fn session_cookie(value: String) -> Cookie<'static> {
+    let host = use_context::<axum::http::request::Parts>()
+        .and_then(|p| p.headers.get(axum::http::header::HOST).cloned());
+    let secure = match host.as_ref().and_then(|h| h.to_str().ok()) {
+        Some(hostname) => is_https(hostname),
+        None => true,
+    };
+    Cookie::build((COOKIE, value))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+fn clear_cookie() {
+    let mut cookie = session_cookie("".to_string());
+    cookie.make_removal();
+    set_cookie(cookie);
+}
 
+fn set_cookie(cookie: Cookie<'static>) {
+    let Some(response) = use_context::<ResponseOptions>() else {
+        return;
+    };
+    if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+        response.append_header(SET_COOKIE, value);
+    }
+}
+
+// The session the current request's cookie refers to, if there is one and it hasn't expired
+pub async fn current_session(db: &Surreal<Any>) -> Result<Option<DbSession>, Fail> {
+    let Some(cookie) = use_context::<CookieJar>().and_then(|jar| jar.get(COOKIE).cloned()) else {
+        return Ok(None);
+    };
+    let session: Option<DbSession> = db
+        .select(SessionId::from(cookie.value()))
+        .await
+        .map_err(Fail::DbError)?;
+    Ok(session.filter(|s| s.expires_at > Utc::now()))
+}
+
+pub async fn mark_second_factor(db: &Surreal<Any>, session: &Thing) -> Result<(), Fail> {
+    db.query("UPDATE $session SET second_factor=true;")
+        .bind(("session", session))
+        .await
+        .map_err(Fail::DbError)?
+        .check()
+        .map_err(Fail::DbError)?;
+    Ok(())
+}
+
+// Note that we've seen the session, pushing its expiry along, if it's been a little while
+pub async fn touch(
+    db: &Surreal<Any>,
+    cfg: &config::SessionLifetime,
+    session: &DbSession,
+) -> Result<(), surrealdb::Error> {
+    let now = Utc::now();
+    if now - session.last_seen_at < Duration::minutes(TOUCH_AFTER) {
+        return Ok(());
+    }
+
+    db.query("UPDATE $session SET last_seen_at=$now, expires_at=$expires_at;")
+        .bind(("session", &session.id))
+        .bind(("now", now))
+        .bind(("expires_at", session.expiry(cfg, now)))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+// Clear out expired sessions, just the person's if one is given
+pub async fn delete_expired(db: &Surreal<Any>, person: Option<&Thing>) -> Result<(), Fail> {
+    let query = match person {
+        Some(_) => "DELETE session WHERE user=$person AND <datetime>expires_at < time::now();",
+        None => "DELETE session WHERE <datetime>expires_at < time::now();",
+    };
+    db.query(query)
+        .bind(("person", person))
+        .await
+        .map_err(Fail::DbError)?
+        .check()
+        .map_err(Fail::DbError)?;
+    Ok(())
+}
+
+mod backend {
+    use super::*;
+    use crate::audit;
+    use crate::auth::authz;
+
+    pub async fn logout() -> Result<(), ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+        clear_cookie();
+
+        // Signing out of a session that's already gone is fine
+        let Ok(user) = authz::current_user() else {
+            return Ok(());
+        };
+        if let Some(session) = user.session {
+            let _: Option<DbSession> = app_state
+                .db
+                .delete(Thing::from(&session))
+                .await
+                .map_err(Fail::DbError)?;
+            audit::record(&app_state.db, "sign_out", &Thing::from(&user.person.id), Vec::new())
+                .await;
+        }
+        Ok(())
+    }
+
+    pub async fn logout_everywhere() -> Result<(), ServerFnError> {
+        let user = authz::current_user()?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+        clear_cookie();
+
+        app_state
+            .db
+            .query("DELETE session WHERE user=$person;")
+            .bind(("person", Thing::from(&user.person.id)))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let person = Thing::from(&user.person.id);
+        audit::record(&app_state.db, "sign_out_everywhere", &person, Vec::new()).await;
+        Ok(())
+    }
+
+    async fn own_sessions(app_state: &AppState, person: &PersonId) -> Result<Vec<DbSession>, Fail> {
+        let sessions: Vec<DbSession> = app_state
+            .db
+            .query("SELECT * FROM session WHERE user=$person ORDER BY last_seen_at DESC;")
+            .bind(("person", Thing::from(person)))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+
+        let now = Utc::now();
+        Ok(sessions.into_iter().filter(|s| s.expires_at > now).collect())
+    }
+
+    pub async fn list_sessions() -> Result<Vec<SessionInfo>, ServerFnError> {
+        let user = authz::current_user()?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let current = user.session.map(|s| Thing::from(&s));
+        Ok(own_sessions(&app_state, &user.person.id)
+            .await?
+            .into_iter()
+            .map(|s| SessionInfo {
+                handle: s.handle(),
+                current: Some(&s.id) == current.as_ref(),
+                created_at: s.created_at,
+                last_seen_at: s.last_seen_at,
+                expires_at: s.expires_at,
+                user_agent: s.user_agent,
+            })
+            .collect())
+    }
+
+    pub async fn revoke_session(handle: String) -> Result<(), ServerFnError> {
+        let user = authz::current_user()?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let session = own_sessions(&app_state, &user.person.id)
+            .await?
+            .into_iter()
+            .find(|s| s.handle() == handle)
+            .ok_or(Fail::UnknownSession)?;
+
+        let _: Option<DbSession> = app_state
+            .db
+            .delete(&session.id)
+            .await
+            .map_err(Fail::DbError)?;
+        audit::record(&app_state.db, "revoke_session", &session.id, Vec::new()).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn session(created_at: DateTime<Utc>) -> DbSession {
+        DbSession {
+            id: Thing::from(("session", "test")),
+            created_at,
+            last_seen_at: created_at,
+            expires_at: created_at,
+            user: Thing::from(("person", "test")),
+            user_agent: None,
+            second_factor: false,
+        }
+    }
+
+    #[test]
+    fn expiry_slides_up_to_the_maximum() {
+        let cfg = config::SessionLifetime {
+            idle_hours: 24,
+            max_days: 7,
+        };
+        let created = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let s = session(created);
+
+        assert_eq!(s.expiry(&cfg, created), created + Duration::hours(24));
+        let later = created + Duration::days(3);
+        assert_eq!(s.expiry(&cfg, later), later + Duration::hours(24));
+        let nearly_done = created + Duration::days(6) + Duration::hours(12);
+        assert_eq!(s.expiry(&cfg, nearly_done), created + Duration::days(7));
+    }
+}
+
+}}
@@ -0,0 +1,285 @@
+use chrono::{DateTime, Utc};
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+// Failed sign ins are counted against both the account and the address they came from, so
+// guessing lots of passwords for one account or one password for lots of accounts both slow
+// down.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottleKind {
+    Account,
+    Address,
+}
+
+impl std::fmt::Display for ThrottleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThrottleKind::Account => write!(f, "account"),
+            ThrottleKind::Address => write!(f, "address"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Lockout {
+    pub kind: ThrottleKind,
+    pub key: String,
+    pub failures: u32,
+    pub locked_until: DateTime<Utc>,
+}
+
+#[leptos::server(ListLockouts, "/api", "Url", "list_lockouts")]
+pub async fn list_lockouts() -> Result<Vec<Lockout>, ServerFnError> {
+    backend::list_lockouts().await
+}
+
+#[leptos::server(ClearLockout, "/api", "Url", "clear_lockout")]
+pub async fn clear_lockout(kind: ThrottleKind, key: String) -> Result<(), ServerFnError> {
+    backend::clear_lockout(kind, key).await
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use std::net::SocketAddr;
+use axum::extract::ConnectInfo;
+use axum::http::StatusCode;
+use chrono::Duration;
+use leptos::use_context;
+use leptos::ServerFnError::ServerError;
+use leptos_axum::ResponseOptions;
+use surrealdb::engine::any::Any;
+use surrealdb::sql::Thing;
+use surrealdb::Surreal;
+use crate::config;
+
+const TABLE: &str = "login_throttle";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Counter {
+    kind: ThrottleKind,
+    key: String,
+    failures: u32,
+    last_failure_at: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+pub enum Fail {
+    NoAppState,
+    DbError(surrealdb::Error),
+    LockedOut(DateTime<Utc>),
+}
+
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let msg = match fail {
+            Fail::NoAppState => "no app state in context".to_string(),
+            Fail::DbError(e) => format!("database error: {:?}", e),
+            Fail::LockedOut(until) => {
+                if let Some(res) = use_context::<ResponseOptions>() {
+                    res.set_status(StatusCode::TOO_MANY_REQUESTS);
+                }
+                let minutes = (until - Utc::now()).num_minutes() + 1;
+                format!("too many failed attempts, please try again in {} minutes", minutes)
+            }
+        };
+        ServerError(msg)
+    }
+}
+
+// What a sign in attempt for `email` counts against. Emails are compared case insensitively so
+// varying the case doesn't buy extra guesses.
+pub fn keys(cfg: &config::LoginThrottle, email: &str) -> Vec<(ThrottleKind, String)> {
+    let mut keys = vec![(ThrottleKind::Account, email.trim().to_lowercase())];
+    if let Some(address) = client_address(cfg) {
+        keys.push((ThrottleKind::Address, address));
+    }
+    keys
+}
+
+pub fn client_address(cfg: &config::LoginThrottle) -> Option<String> {
+    let parts = use_context::<axum::http::request::Parts>()?;
+    if cfg.trust_forwarded_for {
+        // Our proxy appends the address it saw, anything before that is up to the client
+        let forwarded = parts
+            .headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .map(|v| v.trim().to_string());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+fn record_id(kind: ThrottleKind, key: &str) -> Thing {
+    Thing::from((TABLE, format!("{}:{}", kind, key).as_str()))
+}
+
+async fn counter(
+    db: &Surreal<Any>,
+    kind: ThrottleKind,
+    key: &str,
+) -> Result<Option<Counter>, Fail> {
+    db.select(record_id(kind, key)).await.map_err(Fail::DbError)
+}
+
+// Refuse to go any further while any of the keys is locked out
+pub async fn check(db: &Surreal<Any>, keys: &[(ThrottleKind, String)]) -> Result<(), Fail> {
+    let now = Utc::now();
+    for (kind, key) in keys {
+        let locked_until = counter(db, *kind, key).await?.and_then(|c| c.locked_until);
+        if let Some(until) = locked_until.filter(|until| *until > now) {
+            return Err(Fail::LockedOut(until));
+        }
+    }
+    Ok(())
+}
+
+// How long `failures` in a row lock the key out for. The first few are free, after that it
+// doubles each time up to the maximum.
+fn lockout(cfg: &config::LoginThrottle, kind: ThrottleKind, failures: u32) -> Option<Duration> {
+    let free = match kind {
+        ThrottleKind::Account => cfg.account_attempts,
+        ThrottleKind::Address => cfg.address_attempts,
+    };
+    failures.checked_sub(free).map(|over| {
+        let seconds = cfg.lockout_seconds.saturating_mul(1 << over.min(30));
+        Duration::seconds(seconds).min(Duration::minutes(cfg.max_lockout_minutes))
+    })
+}
+
+pub async fn record_failure(
+    db: &Surreal<Any>,
+    cfg: &config::LoginThrottle,
+    keys: &[(ThrottleKind, String)],
+) -> Result<(), Fail> {
+    let now = Utc::now();
+    for (kind, key) in keys {
+        let failures = match counter(db, *kind, key).await? {
+            Some(c) if now - c.last_failure_at < Duration::hours(cfg.forget_after_hours) => {
+                c.failures + 1
+            }
+            _ => 1,
+        };
+
+        let locked_until = lockout(cfg, *kind, failures).map(|lockout| now + lockout);
+
+        let _: Option<Counter> = db
+            .update(record_id(*kind, key))
+            .content(Counter {
+                kind: *kind,
+                key: key.clone(),
+                failures,
+                last_failure_at: now,
+                locked_until,
+            })
+            .await
+            .map_err(Fail::DbError)?;
+    }
+    Ok(())
+}
+
+// A correct password clears the account's count. The address keeps its count, otherwise
+// signing in to your own account every so often would reset it.
+pub async fn record_success(
+    db: &Surreal<Any>,
+    keys: &[(ThrottleKind, String)],
+) -> Result<(), Fail> {
+    for (kind, key) in keys.iter().filter(|(kind, _)| *kind == ThrottleKind::Account) {
+        let _: Option<Counter> = db.delete(record_id(*kind, key)).await.map_err(Fail::DbError)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> config::LoginThrottle {
+        config::LoginThrottle {
+            account_attempts: 5,
+            address_attempts: 20,
+            lockout_seconds: 30,
+            max_lockout_minutes: 60,
+            forget_after_hours: 24,
+            trust_forwarded_for: false,
+        }
+    }
+
+    #[test]
+    fn first_attempts_are_free() {
+        assert_eq!(lockout(&cfg(), ThrottleKind::Account, 0), None);
+        assert_eq!(lockout(&cfg(), ThrottleKind::Account, 4), None);
+        assert_eq!(lockout(&cfg(), ThrottleKind::Address, 19), None);
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let account = |failures| lockout(&cfg(), ThrottleKind::Account, failures);
+        assert_eq!(account(5), Some(Duration::seconds(30)));
+        assert_eq!(account(6), Some(Duration::seconds(60)));
+        assert_eq!(account(7), Some(Duration::seconds(120)));
+        assert_eq!(account(12), Some(Duration::minutes(60)));
+        assert_eq!(account(u32::MAX), Some(Duration::minutes(60)));
+        assert_eq!(lockout(&cfg(), ThrottleKind::Address, 20), Some(Duration::seconds(30)));
+    }
+}
+
+mod backend {
+    use super::*;
+    use crate::auth::authz;
+    use crate::role::RoleId;
+    use crate::AppState;
+
+    pub async fn list_lockouts() -> Result<Vec<Lockout>, ServerFnError> {
+        authz::require_role(RoleId::admin())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let counters: Vec<Counter> = app_state
+            .db
+            .query("SELECT * FROM type::table($table) ORDER BY last_failure_at DESC;")
+            .bind(("table", TABLE))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+
+        let now = Utc::now();
+        Ok(counters
+            .into_iter()
+            .filter_map(|c| {
+                let locked_until = c.locked_until.filter(|until| *until > now)?;
+                Some(Lockout {
+                    kind: c.kind,
+                    key: c.key,
+                    failures: c.failures,
+                    locked_until,
+                })
+            })
+            .collect())
+    }
+
+    pub async fn clear_lockout(kind: ThrottleKind, key: String) -> Result<(), ServerFnError> {
+        let admin = authz::require_role(RoleId::admin())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        tracing::info!("{} cleared the lockout on {} {}", admin.person.email, kind, key);
+        let _: Option<Counter> = app_state
+            .db
+            .delete(record_id(kind, &key))
+            .await
+            .map_err(Fail::DbError)?;
+        crate::audit::record(&app_state.db, "clear_lockout", &record_id(kind, &key), Vec::new())
+            .await;
+        Ok(())
+    }
+}
+
+}}
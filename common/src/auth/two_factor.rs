@@ -0,0 +1,419 @@
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+// What's left after the first step of signing in, e.g. a correct password
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SignInStep {
+    Done,
+    SecondFactor,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SignInStep {
+    pub fn after_first_factor(credentials: &crate::user::Credentials) -> Self {
+        match credentials.totp {
+            Some(_) => SignInStep::SecondFactor,
+            None => SignInStep::Done,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TwoFactorStatus {
+    pub enrolled: bool,
+    // One of the person's roles needs a second factor
+    pub required: bool,
+    pub recovery_codes_left: usize,
+}
+
+// For adding to an authenticator app, `qr_code` is a data: url of a PNG
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TotpEnrolment {
+    pub secret: String,
+    pub qr_code: String,
+}
+
+#[leptos::server(GetTwoFactorStatus, "/api", "Url", "two_factor_status")]
+pub async fn two_factor_status() -> Result<TwoFactorStatus, ServerFnError> {
+    backend::status().await
+}
+
+#[leptos::server(BeginTotpEnrolment, "/api", "Url", "begin_totp_enrolment")]
+pub async fn begin_totp_enrolment() -> Result<TotpEnrolment, ServerFnError> {
+    backend::begin_enrolment().await
+}
+
+// Returns the recovery codes, which are only shown this once
+#[leptos::server(ConfirmTotpEnrolment, "/api", "Url", "confirm_totp_enrolment")]
+pub async fn confirm_totp_enrolment(code: String) -> Result<Vec<String>, ServerFnError> {
+    backend::confirm_enrolment(code).await
+}
+
+#[leptos::server(DisableTotp, "/api", "Url", "disable_totp")]
+pub async fn disable_totp(code: String) -> Result<(), ServerFnError> {
+    backend::disable(code).await
+}
+
+// Whether the current session is waiting on a second factor
+#[leptos::server(SecondFactorPending, "/api", "Url", "second_factor_pending")]
+pub async fn second_factor_pending() -> Result<bool, ServerFnError> {
+    backend::pending().await
+}
+
+// Finishes signing in with a code from an authenticator app, or a recovery code
+#[leptos::server(VerifySecondFactor, "/api", "Url", "verify_second_factor")]
+pub async fn verify_second_factor(code: String) -> Result<(), ServerFnError> {
+    backend::verify(code).await
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use chrono::Utc;
+use leptos::use_context;
+use leptos::ServerFnError::ServerError;
+use rand::distributions::{Alphanumeric, DistString};
+use sha256::Sha256Digest;
+use surrealdb::sql::Thing;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::*;
+use crate::audit;
+use crate::auth::{authz, session, throttle};
+use crate::config;
+use crate::role::RoleId;
+use crate::user::{db, DbUser, Totp};
+use crate::AppState;
+
+const ISSUER: &str = "Happenings";
+const STEP: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+
+enum Fail {
+    NoServerState,
+    DbError(surrealdb::Error),
+    PersonNotFound,
+    BadSecret(String),
+    AlreadyEnrolled,
+    NotEnrolling,
+    NotEnrolled,
+    Required,
+    IncorrectCode,
+    NoPendingSignIn,
+}
+
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let msg = match fail {
+            Fail::NoServerState => "no server state".to_string(),
+            Fail::DbError(e) => format!("database error: {:?}", e),
+            Fail::PersonNotFound => "account no longer exists".to_string(),
+            Fail::BadSecret(e) => format!("bad authenticator secret: {}", e),
+            Fail::AlreadyEnrolled => "you already have an authenticator app set up".to_string(),
+            Fail::NotEnrolling => "start setting up an authenticator app first".to_string(),
+            Fail::NotEnrolled => "you don't have an authenticator app set up".to_string(),
+            Fail::Required => {
+                "your role needs a second factor, so it can't be removed".to_string()
+            }
+            Fail::IncorrectCode => "that code isn't right, please try again".to_string(),
+            Fail::NoPendingSignIn => "there's no sign in waiting for a code".to_string(),
+        };
+        ServerError(msg)
+    }
+}
+
+impl From<session::Fail> for Fail {
+    fn from(fail: session::Fail) -> Self {
+        match fail {
+            session::Fail::DbError(e) => Fail::DbError(e),
+            _ => Fail::NoPendingSignIn,
+        }
+    }
+}
+
+// Whether holding any of these roles needs a second factor
+pub fn is_required(cfg: &config::Login, roles: &[RoleId]) -> bool {
+    roles
+        .iter()
+        .any(|r| cfg.require_two_factor.iter().any(|required| r.grants(required)))
+}
+
+// The roles someone can use in a session. Without a second factor, that's only the ones that
+// don't need it.
+pub fn usable_roles(cfg: &config::Login, roles: Vec<RoleId>, second_factor: bool) -> Vec<RoleId> {
+    if second_factor {
+        return roles;
+    }
+    roles
+        .into_iter()
+        .filter(|r| !is_required(cfg, std::slice::from_ref(r)))
+        .collect()
+}
+
+fn totp(secret: &str, account: &str) -> Result<TOTP, String> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| format!("{:?}", e))?;
+    // Colons aren't allowed in the account name, they separate it from the issuer
+    let account = account.replace(':', "");
+    TOTP::new(Algorithm::SHA1, 6, 1, STEP, secret, Some(ISSUER.to_string()), account)
+        .map_err(|e| e.to_string())
+}
+
+// Accepts a code from the authenticator app, allowing for a clock that's a step out, or one of
+// the recovery codes. Either way it's used up, so `totp` needs saving afterwards.
+fn check_code(totp_cred: &mut Totp, account: &str, code: &str) -> Result<bool, String> {
+    let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    let code = code.to_lowercase();
+
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        let generator = totp(&totp_cred.secret, account)?;
+        let now = Utc::now().timestamp() as u64 / STEP;
+        let step = [now - 1, now, now + 1]
+            .into_iter()
+            .filter(|step| *step > totp_cred.last_step)
+            .find(|step| generator.generate(step * STEP) == code);
+        return Ok(match step {
+            Some(step) => {
+                totp_cred.last_step = step;
+                true
+            }
+            None => false,
+        });
+    }
+
+    let hash = Sha256Digest::digest(&code);
+    let before = totp_cred.recovery_codes.len();
+    totp_cred.recovery_codes.retain(|c| *c != hash);
+    Ok(totp_cred.recovery_codes.len() < before)
+}
+
+// Returns the codes to show and the hashes to keep
+fn recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = Alphanumeric
+                .sample_string(&mut rand::thread_rng(), 10)
+                .to_lowercase();
+            let hash = Sha256Digest::digest(&code);
+            (format!("{}-{}", &code[..5], &code[5..]), hash)
+        })
+        .unzip()
+}
+
+async fn save_totp(app_state: &AppState, user: &DbUser, totp: Option<Totp>) -> Result<(), Fail> {
+    let mut credentials = user.credentials.clone();
+    credentials.totp = totp;
+    credentials.pending_totp = None;
+    db::save_credentials(&app_state.db, &user.person.id, &credentials)
+        .await
+        .map_err(Fail::DbError)
+}
+
+mod backend {
+    use super::*;
+
+    async fn current() -> Result<(AppState, DbUser), ServerFnError> {
+        let person = Thing::from(&authz::require_session()?.person.id);
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        let user = db::find_by_id(&app_state.db, &person)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::PersonNotFound)?;
+        Ok((app_state, user))
+    }
+
+    fn roles(user: &DbUser) -> Vec<RoleId> {
+        user.roles.iter().cloned().map(|r| r.into()).collect()
+    }
+
+    pub async fn status() -> Result<TwoFactorStatus, ServerFnError> {
+        let (app_state, user) = current().await?;
+        let totp = user.credentials.totp.as_ref();
+        Ok(TwoFactorStatus {
+            enrolled: totp.is_some(),
+            required: is_required(&app_state.config.login, &roles(&user)),
+            recovery_codes_left: totp.map_or(0, |t| t.recovery_codes.len()),
+        })
+    }
+
+    pub async fn begin_enrolment() -> Result<TotpEnrolment, ServerFnError> {
+        let (app_state, user) = current().await?;
+        if user.credentials.totp.is_some() {
+            return Err(Fail::AlreadyEnrolled.into());
+        }
+
+        let Secret::Encoded(secret) = Secret::generate_secret().to_encoded() else {
+            unreachable!("to_encoded always gives an encoded secret");
+        };
+        let qr_code = totp(&secret, &user.person.email)
+            .and_then(|t| t.get_qr_base64())
+            .map_err(Fail::BadSecret)?;
+
+        let mut credentials = user.credentials.clone();
+        credentials.pending_totp = Some(secret.clone());
+        db::save_credentials(&app_state.db, &user.person.id, &credentials)
+            .await
+            .map_err(Fail::DbError)?;
+
+        Ok(TotpEnrolment {
+            secret,
+            qr_code: format!("data:image/png;base64,{}", qr_code),
+        })
+    }
+
+    pub async fn confirm_enrolment(code: String) -> Result<Vec<String>, ServerFnError> {
+        let signed_in = authz::require_session()?;
+        let (app_state, user) = current().await?;
+        let secret = user.credentials.pending_totp.clone().ok_or(Fail::NotEnrolling)?;
+
+        let mut totp = Totp {
+            secret,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        };
+        if !check_code(&mut totp, &user.person.email, &code).map_err(Fail::BadSecret)? {
+            return Err(Fail::IncorrectCode.into());
+        }
+
+        let (codes, hashes) = recovery_codes();
+        totp.recovery_codes = hashes;
+        save_totp(&app_state, &user, Some(totp)).await?;
+
+        // They've just shown they have the app, so this session counts as having used it
+        if let Some(session) = signed_in.session {
+            session::mark_second_factor(&app_state.db, &Thing::from(&session))
+                .await
+                .map_err(Fail::from)?;
+        }
+
+        info!("{} set up an authenticator app", user.person.email);
+        audit::record(&app_state.db, "enable_totp", &user.person.id, Vec::new()).await;
+        Ok(codes)
+    }
+
+    pub async fn disable(code: String) -> Result<(), ServerFnError> {
+        let (app_state, user) = current().await?;
+        let mut totp = user.credentials.totp.clone().ok_or(Fail::NotEnrolled)?;
+        if is_required(&app_state.config.login, &roles(&user)) {
+            return Err(Fail::Required.into());
+        }
+        if !check_code(&mut totp, &user.person.email, &code).map_err(Fail::BadSecret)? {
+            return Err(Fail::IncorrectCode.into());
+        }
+
+        save_totp(&app_state, &user, None).await?;
+        info!("{} removed their authenticator app", user.person.email);
+        audit::record(&app_state.db, "disable_totp", &user.person.id, Vec::new()).await;
+        Ok(())
+    }
+
+    pub async fn pending() -> Result<bool, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        let Some(session) = session::current_session(&app_state.db)
+            .await
+            .map_err(Fail::from)?
+        else {
+            return Ok(false);
+        };
+        if session.second_factor {
+            return Ok(false);
+        }
+
+        let user = db::find_by_id(&app_state.db, &session.user)
+            .await
+            .map_err(Fail::DbError)?;
+        Ok(user.is_some_and(|u| u.credentials.totp.is_some()))
+    }
+
+    pub async fn verify(code: String) -> Result<(), ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        let session = session::current_session(&app_state.db)
+            .await
+            .map_err(Fail::from)?
+            .ok_or(Fail::NoPendingSignIn)?;
+        let user = db::find_by_id(&app_state.db, &session.user)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::PersonNotFound)?;
+        let mut totp = user.credentials.totp.clone().ok_or(Fail::NotEnrolled)?;
+
+        // Codes are short, so guesses count against the account like passwords do
+        let keys = throttle::keys(&app_state.config.login.throttle, &user.person.email);
+        throttle::check(&app_state.db, &keys).await?;
+
+        if !check_code(&mut totp, &user.person.email, &code).map_err(Fail::BadSecret)? {
+            throttle::record_failure(&app_state.db, &app_state.config.login.throttle, &keys)
+                .await?;
+            return Err(Fail::IncorrectCode.into());
+        }
+        throttle::record_success(&app_state.db, &keys).await?;
+
+        save_totp(&app_state, &user, Some(totp)).await?;
+        session::mark_second_factor(&app_state.db, &session.id)
+            .await
+            .map_err(Fail::from)?;
+
+        let person = &user.person;
+        audit::record_as(&app_state.db, &person.id, &person.email, "second_factor", &person.id)
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(require_two_factor: Vec<RoleId>) -> config::Login {
+        config::Login {
+            require_two_factor,
+            ..config::Config::default().login
+        }
+    }
+
+    fn enrolled() -> Totp {
+        Totp {
+            secret: Secret::generate_secret().to_encoded().to_string(),
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn roles_needing_a_second_factor_wait_for_it() {
+        let cfg = login(vec![RoleId::organiser()]);
+        let roles = vec![RoleId::admin(), RoleId::organiser(), RoleId::attendee()];
+        assert_eq!(usable_roles(&cfg, roles.clone(), false), [RoleId::attendee()]);
+        assert_eq!(usable_roles(&cfg, roles.clone(), true), roles);
+        assert_eq!(usable_roles(&login(Vec::new()), roles.clone(), false), roles);
+    }
+
+    #[test]
+    fn authenticator_codes_only_work_once() {
+        let mut cred = enrolled();
+        let now = Utc::now().timestamp() as u64;
+        let code = totp(&cred.secret, "someone@example.com").unwrap().generate(now);
+
+        assert!(check_code(&mut cred, "someone@example.com", &code).unwrap());
+        assert!(!check_code(&mut cred, "someone@example.com", &code).unwrap());
+    }
+
+    #[test]
+    fn recovery_codes_only_work_once() {
+        let (codes, hashes) = recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        let mut cred = Totp {
+            recovery_codes: hashes,
+            ..enrolled()
+        };
+
+        // However it's typed in
+        let code = codes[3].to_uppercase().replace('-', " ");
+        assert!(check_code(&mut cred, "someone@example.com", &code).unwrap());
+        assert!(!check_code(&mut cred, "someone@example.com", &codes[3]).unwrap());
+        assert_eq!(cred.recovery_codes.len(), RECOVERY_CODES - 1);
+        assert!(!check_code(&mut cred, "someone@example.com", "nope-nope0").unwrap());
+    }
+}
+
+}}
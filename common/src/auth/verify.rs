@@ -0,0 +1,142 @@
+use leptos::ServerFnError;
+
+// Follows the link sent by send_verification below
+#[leptos::server(VerifyEmail, "/api", "Url", "verify_email")]
+pub async fn verify_email(token: String) -> Result<(), ServerFnError> { backend::verify(token).await }
+
+#[leptos::server(ResendVerificationEmail, "/api", "Url", "resend_verification_email")]
+pub async fn resend_verification_email() -> Result<(), ServerFnError> { backend::resend().await }
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use backend::send_verification;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use crate::audit;
+    use crate::auth::{authz, one_time_token};
+    use crate::role::RoleId;
+    use crate::{mail, AppState};
+    use leptos::use_context;
+    use leptos::ServerFnError::{self, ServerError};
+    use serde::{Deserialize, Serialize};
+    use surrealdb::sql::Thing;
+    use tracing::*;
+
+    const TABLE: &str = "email_verification";
+
+    // The address is kept too, so the link only verifies the address it was sent to
+    #[derive(Serialize, Deserialize)]
+    struct EmailVerification {
+        person: Thing,
+        email: String,
+    }
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        InvalidToken,
+        AlreadyVerified,
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::InvalidToken => {
+                    "this verification link is invalid, has expired or has already been used"
+                        .to_string()
+                }
+                Fail::AlreadyVerified => "email address is already verified".to_string(),
+            };
+            ServerError(msg)
+        }
+    }
+
+    pub async fn send_verification(
+        app_state: &AppState,
+        person: Thing,
+        given_name: &str,
+        email: &str,
+    ) -> Result<(), ServerFnError> {
+        let ttl = chrono::Duration::hours(app_state.config.login.email_verification_hours);
+        let token = one_time_token::issue(
+            &app_state.db,
+            TABLE,
+            EmailVerification {
+                person,
+                email: email.to_string(),
+            },
+            ttl,
+        )
+        .await
+        .map_err(Fail::DbError)?;
+
+        let link = app_state.config.public_link(&format!("/verify_email?token={}", token));
+        let body = format!(
+            "Hi {},\n\n\
+             Thanks for signing up! Please confirm this is your email address by following \
+             this link:\n\n\
+             {}\n\n\
+             If you didn't create an account you can ignore this email.\n",
+            given_name, link
+        );
+
+        mail::send(
+            &app_state.config.mail,
+            mail::Mail {
+                to: email.to_string(),
+                subject: "Confirm your email address".to_string(),
+                body,
+                attachments: Vec::new(),
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn verify(token: String) -> Result<(), ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let verification: EmailVerification =
+            one_time_token::redeem(&app_state.db, TABLE, &token)
+                .await
+                .map_err(Fail::DbError)?
+                .ok_or(Fail::InvalidToken)?;
+
+        let updated: Vec<crate::surreal::Record> = app_state
+            .db
+            .query("UPDATE $person SET email_verified=true WHERE email=$email;")
+            .bind(("person", &verification.person))
+            .bind(("email", &verification.email))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+
+        if updated.is_empty() {
+            return Err(Fail::InvalidToken.into());
+        }
+
+        info!("verified email {} for {}", verification.email, verification.person);
+        audit::record(&app_state.db, "verify_email", &verification.person, Vec::new()).await;
+        Ok(())
+    }
+
+    pub async fn resend() -> Result<(), ServerFnError> {
+        let user = authz::require_role(RoleId::attendee())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        if user.person.email_verified {
+            return Err(Fail::AlreadyVerified.into());
+        }
+
+        send_verification(
+            &app_state,
+            Thing::from(&user.person.id),
+            &user.person.given_name,
+            &user.person.email,
+        )
+        .await
+    }
+}
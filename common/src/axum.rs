@@ -9,8 +9,29 @@ use axum::body::Body;
 use axum::{async_trait, extract::{FromRequestParts}, http::request::Parts, http::StatusCode, response::{IntoResponse, Response}};
 use axum_extra::extract::CookieJar;
 
-use crate::{auth::session::{DbSession, Session, SessionId}, AppState};
-use crate::person::{Person, db::DbPerson};
+use crate::{auth::{api_token, session::{self, DbSession, Session, SessionId}, two_factor}, AppState};
+use crate::person::Person;
+use crate::role::RoleId;
+use crate::user::{DbUser, User};
+
+// Everything but local development is served over https
+pub fn is_https(hostname: &str) -> bool { !hostname.starts_with("localhost") }
+
+// Only allow paths on this site, so we can't be used to bounce people somewhere nasty.
+// Browsers treat '//host' and '/\host' as links to another site.
+pub fn safe_return_url(url: Option<String>) -> String {
+    match url {
+        Some(url)
+            if url.starts_with('/')
+                && !url.starts_with("//")
+                && !url.contains('\\')
+                && !url.chars().any(char::is_control) =>
+        {
+            url
+        }
+        _ => "/".to_string(),
+    }
+}
 
 pub enum Fail {
     BadServerPath(String),
@@ -19,31 +40,57 @@ pub enum Fail {
     NoSession,
     NoAuthCookie,
     SessionExpired,
+    SecondFactorPending,
+    BadToken,
     DbError(surrealdb::Error),
 }
 
 impl IntoResponse for Fail {
     fn into_response(self) -> Response {
-        let msg = match self {
+        let msg = match &self {
             Fail::BadServerPath(p) => format!("no server function '{p}' found"),
             Fail::JoinError(e) => e.to_string(),
             Fail::NoAuthCookie => "no authorization cookie found".to_string(),
             Fail::NoSession => "no session found".to_string(),
             Fail::SessionExpired => "session expired".to_string(),
+            Fail::SecondFactorPending => "sign in needs a second factor".to_string(),
+            Fail::BadToken => "invalid or expired API token".to_string(),
             Fail::DbError(e) => e.to_string(),
             Fail::NoUser => "session user not found".to_string(),
         };
 
-        // TODO - better codes for different errors
+        let status = match self {
+            Fail::NoAuthCookie
+            | Fail::NoSession
+            | Fail::SessionExpired
+            | Fail::SecondFactorPending
+            | Fail::NoUser
+            | Fail::BadToken => {
+                StatusCode::UNAUTHORIZED
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
         Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .status(status)
             .body(Body::from(msg))
             .unwrap()
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct LoggedInUser(pub Person);
+pub struct LoggedInUser {
+    pub person: Person,
+    pub roles: Vec<RoleId>,
+    pub session: Option<SessionId>,
+}
+
+impl LoggedInUser {
+    pub fn has_role(&self, role: &RoleId) -> bool {
+        *role == RoleId::attendee() || self.roles.iter().any(|r| r.grants(role))
+    }
+}
+
 #[async_trait]
 impl FromRequestParts<AppState> for LoggedInUser
 where
@@ -54,14 +101,22 @@ where
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
+        // Scripts sign in with an API token rather than a session cookie
+        if let Some(token) = bearer_token(parts) {
+            return api_token::authenticate(&state.db, token)
+                .await
+                .map_err(Fail::DbError)?
+                .ok_or(Fail::BadToken);
+        }
+
         let SessionWrapper(session) = SessionWrapper::from_request_parts(parts, state).await?;
 
-        let people: Vec<DbPerson> = state
+        let people: Vec<DbUser> = state
         .db
         .query(format!(
             "SELECT {} FROM {} where id=$id;",
-            Person::SELECT,
-            Person::TABLE,
+            User::SELECT,
+            User::TABLE,
         ))
         .bind(("id", Thing::from(&session.user)))
         .await
@@ -69,15 +124,31 @@ where
         .take(0)
         .map_err(Fail::DbError)?;
 
-        let person = people
-            .into_iter()
-            .next()
-            .ok_or(Fail::NoUser)?;
+        let user = people.into_iter().next().ok_or(Fail::NoUser)?;
+
+        // Half signed in, until verify_second_factor
+        if user.credentials.totp.is_some() && !session.second_factor {
+            return Err(Fail::SecondFactorPending);
+        }
 
-        Ok(LoggedInUser(person.into()))
+        let user: User = user.into();
+        Ok(LoggedInUser {
+            person: user.person,
+            roles: two_factor::usable_roles(&state.config.login, user.roles, session.second_factor),
+            session: Some(session.id),
+        })
     }
 }
 
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(axum::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
 pub struct SessionWrapper(Session);
 #[async_trait]
 impl FromRequestParts<AppState> for SessionWrapper
@@ -91,7 +162,7 @@ where
     ) -> Result<Self, Self::Rejection> {
         let jar = CookieJar::from_request_parts(parts, state).await.unwrap();
 
-        let session_cookie = jar.get("session_id").ok_or(Fail::NoAuthCookie)?;
+        let session_cookie = jar.get(session::COOKIE).ok_or(Fail::NoAuthCookie)?;
         let session_id: SessionId = session_cookie.value().into();
 
         let session: DbSession = state
@@ -102,12 +173,49 @@ where
             .ok_or(Fail::NoSession)?;
 
         if chrono::Utc::now() > session.expires_at {
+            let _: Option<DbSession> = state
+                .db
+                .delete(session.id)
+                .await
+                .map_err(Fail::DbError)?;
             return Err(Fail::SessionExpired);
         }
 
+        session::touch(&state.db, &state.config.login.session, &session)
+            .await
+            .map_err(Fail::DbError)?;
+
         Ok(SessionWrapper(session.into()))
     }
 }
 
-}}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn returns_to(url: &str) -> String { safe_return_url(Some(url.to_string())) }
 
+    #[test]
+    fn keeps_paths_on_this_site() {
+        assert_eq!(returns_to("/events"), "/events");
+        assert_eq!(returns_to("/booking/123?paid=true#tickets"), "/booking/123?paid=true#tickets");
+    }
+
+    #[test]
+    fn sends_anything_else_home() {
+        assert_eq!(safe_return_url(None), "/");
+        for url in [
+            "",
+            "events",
+            "https://evil.example.com/",
+            "//evil.example.com/",
+            "/\\evil.example.com/",
+            "/\tevil",
+            "/events\r\nLocation: https://evil.example.com/",
+        ] {
+            assert_eq!(returns_to(url), "/", "{:?}", url);
+        }
+    }
+}
+
+}}
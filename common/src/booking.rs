@@ -73,6 +73,7 @@ pub struct NewDbBooking {
     pub square_order: Option<String>,
     pub contact_id: surrealdb::sql::Thing,
     pub event_id: surrealdb::sql::Thing,
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
@@ -112,7 +113,7 @@ impl Payment {
 
 #[leptos::server(endpoint = "get_booking")]
 pub async fn get_booking(booking_id: BookingId) -> Result<Booking, ServerFnError> {
-    backend::get(booking_id).await
+    backend::get_authorized(booking_id).await
 }
 
 #[leptos::server(endpoint = "list_bookings")]
@@ -145,7 +146,13 @@ pub async fn check_payment(booking_id: BookingId) -> Result<Booking, ServerFnErr
 #[cfg(not(target_arch = "wasm32"))]
 mod backend {
     use super::*;
-    use crate::event::EventId;
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::calendar;
+    use crate::event::{DbEvent, EventId, Sales};
+    use crate::mail;
+    use crate::role::RoleId;
+    use crate::venue::{get_venue, Venue};
     use crate::AppState;
     use crate::{square_api, surreal};
     use leptos::logging::warn;
@@ -156,6 +163,29 @@ mod backend {
     use surrealdb::sql::Thing;
     use tracing::info;
 
+    #[derive(Deserialize)]
+    struct PaymentStatus {
+        status: Status,
+    }
+
+    // The parts of a booking that paying for it changes, for the audit log
+    #[derive(Serialize)]
+    struct PaymentState<'a> {
+        status: &'a Status,
+        payments: &'a [Payment],
+        square_order: &'a Option<String>,
+    }
+
+    impl<'a> From<&'a Booking> for PaymentState<'a> {
+        fn from(b: &'a Booking) -> Self {
+            Self {
+                status: &b.status,
+                payments: &b.payments,
+                square_order: &b.square_order,
+            }
+        }
+    }
+
     enum Fail {
         NoState,
         DBError(surrealdb::Error),
@@ -178,6 +208,16 @@ mod backend {
         }
     }
 
+    // Bookings can be seen and paid for by their contact, and by organisers
+    pub async fn get_authorized(booking_id: BookingId) -> Result<Booking, ServerFnError> {
+        let user = authz::require_role(RoleId::attendee())?;
+        let booking = get(booking_id).await?;
+        if booking.contact.id != user.person.id {
+            authz::require_role(RoleId::organiser())?;
+        }
+        Ok(booking)
+    }
+
     pub async fn get(booking_id: BookingId) -> Result<Booking, ServerFnError> {
         let app_state = use_context::<AppState>().ok_or(Fail::NoState)?;
         let mut bookings: Vec<DbBooking> = app_state
@@ -200,6 +240,7 @@ mod backend {
     }
 
     pub async fn list(event_id: EventId) -> Result<Vec<Booking>, ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
         let app_state = use_context::<AppState>().ok_or(Fail::NoState)?;
 
         let bookings: Vec<DbBooking> = app_state
@@ -223,6 +264,29 @@ mod backend {
 
         let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
 
+        // Only organisers can book on behalf of somebody else
+        let user = authz::require_role(RoleId::attendee())?;
+        if contact != user.person.id {
+            authz::require_role(RoleId::organiser())?;
+        }
+
+        if !user.person.email_verified && !app_state.config.login.unverified_can_book {
+            return Err(authz::Fail::EmailNotVerified.into());
+        }
+
+        let db_event: Option<DbEvent> = app_state.db.select(&event).await?;
+        let db_event = db_event.ok_or(ServerFnError::new("no event found"))?;
+        match Event::from(db_event).sales(chrono::Utc::now()) {
+            Sales::Open => {}
+            Sales::NotOpenYet(open) => {
+                let msg = format!("booking for this event opens at {}", open.to_rfc2822());
+                return Err(ServerFnError::new(msg));
+            }
+            Sales::Unpublished | Sales::Closed => {
+                return Err(ServerFnError::new("booking for this event is closed"));
+            }
+        }
+
         let b = NewDbBooking {
             contact_id: contact.into(),
             event_id: event.into(),
@@ -230,22 +294,25 @@ mod backend {
             status: Status::Draft,
             payments: Vec::new(),
             square_order: None,
+            created_at: chrono::Utc::now(),
         };
 
         let mut bs: Vec<crate::surreal::Record> = app_state
             .db
             .create("booking")
-            .content(b)
+            .content(&b)
             .await
             .map_err(
             |e| ServerFnError::new(format!("failed to create new booking: {}", e))
         )?;
 
-        let b = bs
+        let record = bs
             .pop()
             .ok_or(ServerFnError::new("failed to create new booking"))?;
 
-        get(b.id.into()).await
+        let changes = audit::diff(None, Some(&b));
+        audit::record(&app_state.db, "create_booking", &record.id, changes).await;
+        get(record.id.into()).await
     }
 
     pub async fn create_payment_link(
@@ -254,8 +321,8 @@ mod backend {
     ) -> Result<String, ServerFnError> {
         info!("creating payment link for booking: {:?}", booking_id);
         let app_state = use_context::<AppState>().ok_or(Fail::NoState)?;
-        let booking = get(booking_id.clone()).await?;
-        let contact = booking.contact;
+        let booking = get_authorized(booking_id.clone()).await?;
+        let contact = booking.contact.clone();
         let phone = match contact.phone.as_ref() {
             Some(phone_str) => {
                 match phonenumber::parse(Some(phonenumber::country::Id::GB), phone_str) {
@@ -288,7 +355,7 @@ mod backend {
 
         let req = square_api::CreatePaymentLinkRequest {
             idempotency_key: uuid::Uuid::new_v4().to_string(),
-            description: booking.event.name,
+            description: booking.event.name.clone(),
             order: new_order,
             checkout_options: Some(square_api::CheckoutOptions {
                 allow_tipping: false,
@@ -319,21 +386,31 @@ mod backend {
 
         let parsed_res = res.json::<square_api::Welcome>().await?;
 
+        let square_order = Some(parsed_res.payment_link.order_id);
         let _: surreal::Record = app_state
             .db
-            .update(booking.id)
-            .patch(PatchOp::replace("/square_order", parsed_res.payment_link.order_id))
+            .update(&booking.id)
+            .patch(PatchOp::replace("/square_order", &square_order))
             .await
             .map_err(Fail::DBError)?
             .ok_or(Fail::NotFound(booking_id.into()))?;
 
+        let before = PaymentState::from(&booking);
+        let after = PaymentState {
+            square_order: &square_order,
+            ..PaymentState::from(&booking)
+        };
+        let changes = audit::diff(Some(&before), Some(&after));
+        audit::record(&app_state.db, "create_payment_link", &Thing::from(&booking.id), changes)
+            .await;
+
         Ok(parsed_res.payment_link.long_url)
     }
 
     pub async fn check_payment(booking_id: BookingId) -> Result<Booking, ServerFnError> {
         let app_state = use_context::<AppState>().ok_or(Fail::NoState)?;
 
-        let booking = get(booking_id.clone()).await?;
+        let booking = get_authorized(booking_id.clone()).await?;
 
         // Call Square API and check status of payment on the order
         let order_id = booking.square_order.clone().ok_or(Fail::NoSquareOrder)?;
@@ -373,19 +450,96 @@ mod backend {
         } else if total_paid > Decimal::ZERO && booking.status != Status::Cancelled {
             Status::PartiallyPaid
         } else {
-            booking.status
+            booking.status.clone()
         };
 
-        let _: surreal::Record = app_state
+        // Checks can overlap, so what the status was is taken from the update itself. Only the
+        // one that actually moves the booking to paid sends the confirmation.
+        let before: Option<PaymentStatus> = app_state
             .db
-            .update(&booking.id)
-            .patch(PatchOp::replace("/payments", payments))
-            .patch(PatchOp::replace("/status", status))
+            .query("UPDATE $booking SET payments=$payments, status=$status RETURN BEFORE;")
+            .bind(("booking", Thing::from(&booking.id)))
+            .bind(("payments", &payments))
+            .bind(("status", &status))
             .await
             .map_err(Fail::DBError)?
-            .ok_or(Fail::NotFound(booking.id.into()))?;
+            .take(0)
+            .map_err(Fail::DBError)?;
+        let before = before.ok_or(Fail::NotFound(booking.id.clone().into()))?;
+
+        // Checking is often just to see there's nothing new yet
+        let after = PaymentState {
+            status: &status,
+            payments: &payments,
+            ..PaymentState::from(&booking)
+        };
+        let changes = audit::diff(Some(&PaymentState::from(&booking)), Some(&after));
+        if !changes.is_empty() {
+            audit::record(&app_state.db, "payment_update", &Thing::from(&booking.id), changes)
+                .await;
+        }
+
+        let newly_paid = status == Status::Paid && before.status != Status::Paid;
+        let booking = get(booking_id.clone()).await?;
+        if newly_paid {
+            send_confirmation(&app_state, &booking).await;
+        }
+        Ok(booking)
+    }
+
+    // The venue's address a line at a time, and a map if it has a location
+    fn directions(venue: &Venue) -> String {
+        let address = venue.address.lines().map(str::trim).filter(|l| !l.is_empty());
+        let lines: Vec<&str> = std::iter::once(venue.name.as_str())
+            .chain(address)
+            .chain(std::iter::once(venue.postcode.as_str()).filter(|p| !p.is_empty()))
+            .collect();
+        let mut text = format!("It's at:\n\n{}\n\n", lines.join("\n"));
+        if let Some(map) = venue.map_url() {
+            text.push_str(&format!("Find it on a map here:\n\n{}\n\n", map));
+        }
+        text
+    }
 
-        get(booking_id.clone()).await
+    // The booking is paid for whether or not this gets through, so failures are only logged
+    async fn send_confirmation(app_state: &AppState, booking: &Booking) {
+        let event = &booking.event;
+        let venue = match event.venue.clone() {
+            Some(id) => get_venue(id).await.ok(),
+            None => None,
+        };
+        let body = format!(
+            "Hi {},\n\n\
+             Thanks for booking {} for {}, which starts {}. The attached calendar file has \
+             the times for your tickets.\n\n\
+             {}\
+             You can see your booking at any time here:\n\n\
+             {}\n",
+            booking.contact.given_name,
+            match booking.tickets.len() {
+                1 => "a ticket".to_string(),
+                n => format!("{} tickets", n),
+            },
+            event.name,
+            event.start_local().format("%A %-d %B %Y at %H:%M"),
+            venue.as_ref().map(directions).unwrap_or_default(),
+            app_state.config.public_link(&format!("/booking/{}", booking.id)),
+        );
+        let ics = calendar::for_booking(&app_state.config, booking).await;
+
+        let confirmation = mail::Mail {
+            to: booking.contact.email.clone(),
+            subject: format!("Your booking for {}", event.name),
+            body,
+            attachments: vec![mail::Attachment {
+                filename: "booking.ics".to_string(),
+                content_type: "text/calendar; charset=utf-8; method=PUBLISH".to_string(),
+                data: ics.into_bytes(),
+            }],
+        };
+        if let Err(e) = mail::send(&app_state.config.mail, confirmation).await {
+            warn!("failed to send the confirmation for booking {}: {:?}", booking.id, e);
+        }
     }
 
     // TODO - common code between this guy and below
@@ -412,5 +566,42 @@ mod backend {
                 format!("Bearer {}", app_state.config.square.api_key),
             )
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rust_decimal_macros::dec;
+
+        fn venue() -> Venue {
+            Venue {
+                id: "venue:hall".into(),
+                name: "Village Hall".to_string(),
+                address: "1 High Street\n  \nLittle Town\n".to_string(),
+                postcode: "AB1 2CD".to_string(),
+                latitude: None,
+                longitude: None,
+                accessibility: "".to_string(),
+                parking: "".to_string(),
+            }
+        }
+
+        #[test]
+        fn directions_give_the_address_a_line_at_a_time() {
+            let text = directions(&venue());
+            let expected = "It's at:\n\nVillage Hall\n1 High Street\nLittle Town\nAB1 2CD\n\n";
+            assert_eq!(text, expected);
+        }
+
+        #[test]
+        fn directions_have_a_map_when_the_venue_has_a_location() {
+            let located = Venue {
+                latitude: Some(dec!(51.5)),
+                longitude: Some(dec!(-0.1)),
+                ..venue()
+            };
+            let text = directions(&located);
+            assert!(text.ends_with(&format!("{}\n\n", located.map_url().unwrap())), "{}", text);
+        }
+    }
 }
 
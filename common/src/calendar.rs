@@ -0,0 +1,250 @@
+use crate::booking::BookingId;
+use leptos::ServerFnError;
+
+// A booking as an RFC 5545 .ics file, to add to a calendar. Only whoever can see the booking
+// can have it.
+#[leptos::server(BookingCalendar, "/api", "Url", "booking_calendar")]
+pub async fn booking_calendar(booking_id: BookingId) -> Result<String, ServerFnError> {
+    let app_state = use_context::<AppState>().ok_or(ServerFnError::new("no server state"))?;
+    let booking = crate::booking::get_booking(booking_id).await?;
+    Ok(backend::for_booking(&app_state.config, &booking).await)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use backend::{feed, for_booking};
+
+#[cfg(not(target_arch = "wasm32"))]
+use {crate::AppState, leptos::use_context};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use crate::booking::Booking;
+    use crate::config::Config;
+    use crate::event::{self, Event};
+    use crate::ticket::Ticket;
+    use crate::venue::{get_venue, Venue, VenueId};
+    use chrono::{DateTime, Utc};
+    use leptos::ServerFnError;
+    use std::collections::HashMap;
+    use surrealdb::{engine::any::Any, Surreal};
+
+    // Lines longer than this many bytes are folded onto the next
+    const LINE_OCTETS: usize = 75;
+
+    fn escape(text: &str) -> String {
+        text.replace('\\', "\\\\")
+            .replace(';', "\\;")
+            .replace(',', "\\,")
+            .replace("\r\n", "\\n")
+            .replace('\n', "\\n")
+    }
+
+    fn time(t: DateTime<Utc>) -> String { t.format("%Y%m%dT%H%M%SZ").to_string() }
+
+    // UIDs are what calendars match on to update an event rather than add another, so they
+    // only depend on ids that never change
+    fn uid(kind: &str, id: impl std::fmt::Display, config: &Config) -> String {
+        format!("{}-{}@{}", kind, id, config.public_domain())
+    }
+
+    fn location(venue: &Venue) -> String {
+        let address = venue.address.lines().map(str::trim).filter(|l| !l.is_empty());
+        let parts = std::iter::once(venue.name.as_str())
+            .chain(address)
+            .chain(std::iter::once(venue.postcode.as_str()).filter(|p| !p.is_empty()));
+        parts.collect::<Vec<_>>().join(", ")
+    }
+
+    struct VEvent {
+        uid: String,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        summary: String,
+        description: String,
+        location: Option<String>,
+        url: String,
+    }
+
+    #[derive(Default)]
+    struct Calendar(String);
+
+    impl Calendar {
+        fn new(name: Option<&str>) -> Self {
+            let mut cal = Calendar::default();
+            cal.line("BEGIN", "VCALENDAR");
+            cal.line("VERSION", "2.0");
+            cal.line("PRODID", "-//Happenings//Happenings//EN");
+            cal.line("CALSCALE", "GREGORIAN");
+            cal.line("METHOD", "PUBLISH");
+            if let Some(name) = name {
+                cal.line("X-WR-CALNAME", &escape(name));
+            }
+            cal
+        }
+
+        // Content lines end in CRLF, and long ones carry on after a CRLF and a space without
+        // splitting a character
+        fn line(&mut self, name: &str, value: &str) {
+            let line = format!("{}:{}", name, value);
+            let mut octets = 0;
+            for c in line.chars() {
+                if octets + c.len_utf8() > LINE_OCTETS {
+                    self.0.push_str("\r\n ");
+                    octets = 1;
+                }
+                self.0.push(c);
+                octets += c.len_utf8();
+            }
+            self.0.push_str("\r\n");
+        }
+
+        fn event(&mut self, e: &VEvent, stamp: DateTime<Utc>) {
+            self.line("BEGIN", "VEVENT");
+            self.line("UID", &e.uid);
+            self.line("DTSTAMP", &time(stamp));
+            self.line("DTSTART", &time(e.start));
+            self.line("DTEND", &time(e.end));
+            self.line("SUMMARY", &escape(&e.summary));
+            if !e.description.is_empty() {
+                self.line("DESCRIPTION", &escape(&e.description));
+            }
+            if let Some(location) = &e.location {
+                self.line("LOCATION", &escape(location));
+            }
+            self.line("URL", &e.url);
+            self.line("STATUS", "CONFIRMED");
+            self.line("END", "VEVENT");
+        }
+
+        fn finish(mut self) -> String {
+            self.line("END", "VCALENDAR");
+            self.0
+        }
+    }
+
+    fn for_event(config: &Config, event: &Event, venue: Option<&Venue>) -> VEvent {
+        VEvent {
+            uid: uid("event", &event.id, config),
+            start: event.start,
+            end: event.end,
+            summary: event.name.clone(),
+            description: event.tagline.clone(),
+            location: venue.map(location),
+            url: config.public_link(&format!("/events/{}/book", event.id)),
+        }
+    }
+
+    // One calendar event for each slot with tickets in it, starting when the slot does
+    pub async fn for_booking(config: &Config, booking: &Booking) -> String {
+        let event = &booking.event;
+        let venue = match event.venue.clone() {
+            Some(id) => get_venue(id).await.ok(),
+            None => None,
+        };
+
+        let whole = for_event(config, event, venue.as_ref());
+        let url = config.public_link(&format!("/booking/{}", booking.id));
+        let description = |tickets: Vec<&Ticket>| {
+            let names: Vec<_> = tickets.iter().map(|t| t.ticket_type.name.as_str()).collect();
+            format!("{}\n\n{} tickets: {}", event.tagline, names.len(), names.join(", "))
+        };
+
+        let mut by_slot: HashMap<&str, Vec<&Ticket>> = HashMap::new();
+        for ticket in &booking.tickets {
+            if let Some(slot) = &ticket.slot_name {
+                by_slot.entry(slot.as_str()).or_default().push(ticket);
+            }
+        }
+        let mut vevents = Vec::new();
+        for (index, slot) in event.slots.list.iter().enumerate() {
+            if let Some(tickets) = by_slot.get(slot.name.as_str()) {
+                vevents.push(VEvent {
+                    uid: uid("booking", format!("{}-slot{}", booking.id, index), config),
+                    start: slot.start.unwrap_or(event.start),
+                    end: event.end,
+                    summary: format!("{} ({})", event.name, slot.name),
+                    description: description(tickets.clone()),
+                    location: whole.location.clone(),
+                    url: url.clone(),
+                });
+            }
+        }
+        if vevents.is_empty() {
+            vevents.push(VEvent {
+                uid: uid("booking", &booking.id, config),
+                description: description(booking.tickets.iter().collect()),
+                url,
+                ..whole
+            });
+        }
+
+        let now = Utc::now();
+        let mut cal = Calendar::new(None);
+        for e in &vevents {
+            cal.event(e, now);
+        }
+        cal.finish()
+    }
+
+    // Everything on the public event list, along with what has recently finished, for calendars
+    // to subscribe to
+    pub async fn feed(db: &Surreal<Any>, config: &Config) -> Result<String, ServerFnError> {
+        let now = Utc::now();
+        let events = event::list(db, true).await?;
+        let events: Vec<&Event> = events.iter().filter(|e| e.in_calendar_feed(now)).collect();
+        let venues: Vec<Venue> = db
+            .query("SELECT meta::id(id) as id, * FROM venue;")
+            .await?
+            .take(0)?;
+        let venues: HashMap<VenueId, Venue> =
+            venues.into_iter().map(|v| (v.id.clone(), v)).collect();
+
+        let mut cal = Calendar::new(Some("Happenings"));
+        for e in events {
+            let venue = e.venue.as_ref().and_then(|id| venues.get(id));
+            cal.event(&for_event(config, e, venue), now);
+        }
+        Ok(cal.finish())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn folded(name: &str, value: &str) -> String {
+            let mut cal = Calendar::default();
+            cal.line(name, value);
+            cal.0
+        }
+
+        #[test]
+        fn escapes_text() {
+            assert_eq!(escape("a, b; c\\d"), "a\\, b\\; c\\\\d");
+            assert_eq!(escape("one\r\ntwo\nthree"), "one\\ntwo\\nthree");
+        }
+
+        #[test]
+        fn short_lines_are_left_alone() {
+            assert_eq!(folded("SUMMARY", "Quiz night"), "SUMMARY:Quiz night\r\n");
+        }
+
+        #[test]
+        fn long_lines_are_folded_at_75_octets() {
+            let ics = folded("DESCRIPTION", &"x".repeat(200));
+            let lines: Vec<&str> = ics.trim_end_matches("\r\n").split("\r\n").collect();
+            assert!(lines.iter().all(|l| l.len() <= LINE_OCTETS), "{:?}", lines);
+            assert!(lines[1..].iter().all(|l| l.starts_with(' ')));
+            let unfolded = ics.replace("\r\n ", "");
+            assert_eq!(unfolded, format!("DESCRIPTION:{}\r\n", "x".repeat(200)));
+        }
+
+        #[test]
+        fn folding_never_splits_a_character() {
+            let ics = folded("SUMMARY", &"é".repeat(100));
+            for line in ics.trim_end_matches("\r\n").split("\r\n") {
+                assert!(line.len() <= LINE_OCTETS, "{:?}", line);
+            }
+            assert_eq!(ics.replace("\r\n ", ""), format!("SUMMARY:{}\r\n", "é".repeat(100)));
+        }
+    }
+}
@@ -1,21 +1,88 @@
 use oauth2::{AuthUrl, ClientId, ClientSecret, TokenUrl};
 use serde::{Deserialize, Serialize};
 
+use crate::role::RoleId;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Login {
     pub admin_email: String,
     pub admin_password: String,
     pub oauth_providers: Vec<OAuthProvider>,
+    pub password_hashing: PasswordHashing,
+    pub session: SessionLifetime,
+    pub throttle: LoginThrottle,
+    pub password_reset_minutes: i64,
+    // How long an emailed sign in link works for
+    pub magic_link_minutes: i64,
+    pub email_verification_hours: i64,
+    // Whether people who signed up with a password can book before verifying their email
+    pub unverified_can_book: bool,
+    // Holding any of these roles (or one that grants them) needs a second factor. Until they've
+    // set one up, people only get the roles that don't.
+    pub require_two_factor: Vec<RoleId>,
+}
+
+// Argon2id cost parameters. Existing hashes are upgraded when these change, the next time
+// their owner signs in.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PasswordHashing {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+// Sessions last `idle_hours` from when they were last used, but never more than `max_days`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionLifetime {
+    pub idle_hours: i64,
+    pub max_days: i64,
+}
+
+// Failed password sign ins are counted per account and per client address. Once past the free
+// attempts, each further failure locks out for twice as long as the one before, up to
+// max_lockout_minutes. Counts are forgotten after forget_after_hours without a failure.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoginThrottle {
+    pub account_attempts: u32,
+    pub address_attempts: u32,
+    pub lockout_seconds: i64,
+    pub max_lockout_minutes: i64,
+    pub forget_after_hours: i64,
+    // Only set this when running behind a reverse proxy that sets X-Forwarded-For, otherwise
+    // anyone can pick the address they're counted against.
+    pub trust_forwarded_for: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OAuthProvider {
     pub name: String,
+    // Shown on the sign in button, defaults to the name
+    #[serde(default)]
+    pub label: Option<String>,
     pub icon_url: String,
     pub client_id: ClientId,
     pub client_secret: ClientSecret,
-    pub auth_url: AuthUrl,
-    pub token_url: TokenUrl,
+    // For OpenID Connect providers. The endpoints below are discovered from the issuer and the
+    // ID token is verified against its published keys.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // Required for plain OAuth2 providers, optional overrides of the discovered ones otherwise
+    #[serde(default)]
+    pub auth_url: Option<AuthUrl>,
+    #[serde(default)]
+    pub token_url: Option<TokenUrl>,
+    #[serde(default)]
+    pub user_info_url: Option<String>,
+    #[serde(default = "default_oauth_scopes")]
+    pub scopes: Vec<String>,
+    // Some providers (e.g. GitHub, Microsoft) don't say whether an email address is verified.
+    // Set this if you're happy to trust the addresses they give us anyway.
+    #[serde(default)]
+    pub trust_email: bool,
+}
+
+fn default_oauth_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string(), "email".to_string()]
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -33,6 +100,66 @@ pub struct Square {
     pub location_id: String,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Mail {
+    pub from: String,
+    pub transport: MailTransport,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum MailTransport {
+    // Just write messages to the log, handy for development
+    Log,
+    Smtp {
+        host: String,
+        port: u16,
+        username: String,
+        password: String,
+    },
+}
+
+// Server functions are refused when a browser says the request came from another site. Those
+// named here are left alone, e.g. oauth_redirect which is a plain link followed by the browser.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Csrf {
+    pub exempt: Vec<String>,
+}
+
+// The janitor clears leftovers out of the database in the background. Each sweep runs every
+// `interval_minutes` and removes rows that have been finished with for more than `keep_hours`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Janitor {
+    // Sessions past their expiry
+    pub sessions: Sweep,
+    // Sign ins that went off to an OAuth provider and never came back
+    pub oauth_states: Sweep,
+    // Bookings that never got as far as a payment
+    pub draft_bookings: Sweep,
+    // Emailed links, e.g. for password resets, past their expiry
+    pub one_time_tokens: Sweep,
+    // Uploaded images no event or series uses any more
+    pub uploads: Sweep,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sweep {
+    pub interval_minutes: u64,
+    pub keep_hours: i64,
+}
+
+// Images organisers upload for events, which are served back from /uploads
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Uploads {
+    pub storage: UploadStorage,
+    pub max_kib: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum UploadStorage {
+    // Files in a directory on the server, created if it isn't there
+    Local { dir: String },
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Credentials {
     Root { username: String, password: String },
@@ -40,18 +167,64 @@ pub enum Credentials {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
+    // Where people reach the site, e.g. "https://happenings.example.com". Links in emails are
+    // built from this rather than the Host header of whatever request caused them, which
+    // anyone can set to anything.
+    pub public_url: String,
     pub login: Login,
     pub db: DB,
     pub square: Square,
+    pub mail: Mail,
+    pub csrf: Csrf,
+    pub janitor: Janitor,
+    pub uploads: Uploads,
+}
+
+impl Config {
+    // An absolute link to somewhere on the site, for emails and anything else that leaves it
+    pub fn public_link(&self, path: &str) -> String {
+        format!("{}{}", self.public_url.trim_end_matches('/'), path)
+    }
+
+    // Just the host name from public_url, e.g. for calendar UIDs
+    pub fn public_domain(&self) -> &str {
+        let url = self.public_url.as_str();
+        let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+        rest.split(['/', ':']).next().unwrap_or(rest)
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            public_url: "http://localhost:3000".to_string(),
             login: Login {
                 admin_email: "admin@admin.com".to_string(),
                 admin_password: "admin".to_string(),
                 oauth_providers: Vec::new(),
+                // OWASP's recommended minimum for Argon2id
+                password_hashing: PasswordHashing {
+                    memory_kib: 19 * 1024,
+                    iterations: 2,
+                    parallelism: 1,
+                },
+                session: SessionLifetime {
+                    idle_hours: 24,
+                    max_days: 30,
+                },
+                throttle: LoginThrottle {
+                    account_attempts: 5,
+                    address_attempts: 20,
+                    lockout_seconds: 30,
+                    max_lockout_minutes: 60,
+                    forget_after_hours: 24,
+                    trust_forwarded_for: false,
+                },
+                password_reset_minutes: 60,
+                magic_link_minutes: 15,
+                email_verification_hours: 48,
+                unverified_can_book: false,
+                require_two_factor: Vec::new(),
             },
             db: DB {
                 endpoint: "file:/happenings.db".to_string(),
@@ -65,7 +238,70 @@ impl Default for Config {
                 api_key: "".to_string(),
                 location_id: "".to_string(),
             },
+            mail: Mail {
+                from: "Happenings <happenings@localhost>".to_string(),
+                transport: MailTransport::Log,
+            },
+            csrf: Csrf {
+                exempt: vec!["oauth_redirect".to_string()],
+            },
+            janitor: Janitor {
+                sessions: Sweep {
+                    interval_minutes: 60,
+                    keep_hours: 0,
+                },
+                oauth_states: Sweep {
+                    interval_minutes: 60,
+                    keep_hours: 1,
+                },
+                draft_bookings: Sweep {
+                    interval_minutes: 360,
+                    keep_hours: 72,
+                },
+                one_time_tokens: Sweep {
+                    interval_minutes: 60,
+                    keep_hours: 0,
+                },
+                uploads: Sweep {
+                    interval_minutes: 1440,
+                    keep_hours: 24,
+                },
+            },
+            uploads: Uploads {
+                storage: UploadStorage::Local {
+                    dir: "uploads".to_string(),
+                },
+                max_kib: 5 * 1024,
+            },
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(public_url: &str) -> Config {
+        Config {
+            public_url: public_url.to_string(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn links_are_built_on_the_public_url() {
+        let path = "/reset_password?token=abc";
+        let expected = "https://example.com/reset_password?token=abc";
+        assert_eq!(config("https://example.com").public_link(path), expected);
+        assert_eq!(config("https://example.com/").public_link(path), expected);
+    }
+
+    #[test]
+    fn domain_leaves_out_the_scheme_port_and_path() {
+        assert_eq!(config("https://example.com").public_domain(), "example.com");
+        assert_eq!(config("http://localhost:3000").public_domain(), "localhost");
+        assert_eq!(config("https://example.com/happenings/").public_domain(), "example.com");
+        assert_eq!(config("example.com").public_domain(), "example.com");
+    }
+}
@@ -1,12 +1,17 @@
 use crate::schema::Schema;
+use crate::series::SeriesId;
+use crate::venue::VenueId;
 use crate::{generic_id::Id, ticket::{TicketType, TicketTypes}};
-use chrono::{DateTime, Local, Utc};
+use chrono::{DateTime, Duration, Local, Utc};
 use leptos::server_fn::codec::Json;
 use leptos::ServerFnError;
 use macros::generate_new;
 use serde::{Deserialize, Serialize};
 
 pub type EventId = Id<Event>;
+
+// How long finished events stay in the calendar feed
+pub const CALENDAR_FEED_KEEPS_DAYS: i64 = 90;
 impl Schema for Event {
     const TABLE: &'static str = "event";
 }
@@ -17,17 +22,86 @@ pub struct Event {
     pub id: EventId,
     pub name: String,
     pub tagline: String,
+    // Markdown, only ever shown once the server has turned it into safe HTML
+    #[serde(default)]
+    pub description: String,
+    // Keys of uploaded images, see `image_url`
+    #[serde(default)]
+    pub hero_image: Option<String>,
+    #[serde(default)]
+    pub gallery: Vec<String>,
+    #[serde(default)]
+    pub venue: Option<VenueId>,
     pub default_ticket_type: TicketType,
     pub additional_ticket_types: Vec<TicketType>,
     pub slots: Slots,
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub status: EventStatus,
+    // Booking opens straight away and closes when the event ends unless these say otherwise
+    #[serde(default)]
+    pub sales_open: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub sales_close: Option<DateTime<Utc>>,
+    // Set on events generated from a series, along with which of its events this is
+    #[serde(default)]
+    pub series: Option<SeriesId>,
+    #[serde(default)]
+    pub occurrence: Option<u32>,
+    // Archived events are hidden from the event list but kept for their bookings
+    #[not_in_new]
+    #[serde(default)]
+    pub archived: bool,
+}
+
+// Drafts are only seen by organisers. Closed events stay on the list but can't be booked,
+// whatever the sales window says.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EventStatus {
+    Draft,
+    // Events from before there were statuses were all public
+    #[default]
+    Published,
+    Closed,
+}
+
+impl EventStatus {
+    pub const ALL: &'static [EventStatus] =
+        &[EventStatus::Draft, EventStatus::Published, EventStatus::Closed];
+}
+
+impl std::fmt::Display for EventStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "{:?}", self) }
+}
+
+impl std::str::FromStr for EventStatus {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventStatus::ALL
+            .iter()
+            .find(|status| status.to_string() == s)
+            .copied()
+            .ok_or(format!("unknown event status '{}'", s))
+    }
+}
+
+// Whether an event can be booked right now
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Sales {
+    Unpublished,
+    NotOpenYet(DateTime<Utc>),
+    Open,
+    Closed,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct Slot {
     pub name: String,
     pub capacity: Option<i64>,
+    // When people in this slot should arrive, if it's not the start of the event
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
@@ -49,12 +123,32 @@ pub struct DbEvent {
     pub id: surrealdb::sql::Thing,
     pub name: String,
     pub tagline: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub hero_image: Option<String>,
+    #[serde(default)]
+    pub gallery: Vec<String>,
+    #[serde(default)]
+    pub venue: Option<VenueId>,
     pub default_ticket_type: TicketType,
     pub additional_ticket_types: Vec<TicketType>,
     pub slots: Slots,
     pub slots_description: Option<String>,
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub status: EventStatus,
+    #[serde(default)]
+    pub sales_open: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub sales_close: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub series: Option<SeriesId>,
+    #[serde(default)]
+    pub occurrence: Option<u32>,
+    #[serde(default)]
+    pub archived: bool,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -64,15 +158,51 @@ impl From<DbEvent> for Event {
             id: item.id.into(),
             name: item.name,
             tagline: item.tagline,
+            description: item.description,
+            hero_image: item.hero_image,
+            gallery: item.gallery,
+            venue: item.venue,
             default_ticket_type: item.default_ticket_type,
             additional_ticket_types: item.additional_ticket_types,
             slots: item.slots,
             start: item.start,
             end: item.end,
+            status: item.status,
+            sales_open: item.sales_open,
+            sales_close: item.sales_close,
+            series: item.series,
+            occurrence: item.occurrence,
+            archived: item.archived,
         }
     }
 }
 
+impl From<Event> for NewEvent {
+    fn from(item: Event) -> Self {
+        Self {
+            name: item.name,
+            tagline: item.tagline,
+            description: item.description,
+            hero_image: item.hero_image,
+            gallery: item.gallery,
+            venue: item.venue,
+            default_ticket_type: item.default_ticket_type,
+            additional_ticket_types: item.additional_ticket_types,
+            slots: item.slots,
+            start: item.start,
+            end: item.end,
+            status: item.status,
+            sales_open: item.sales_open,
+            sales_close: item.sales_close,
+            series: item.series,
+            occurrence: item.occurrence,
+        }
+    }
+}
+
+// Where an uploaded image is served from
+pub fn image_url(key: &str) -> String { format!("/uploads/{}", key) }
+
 impl Event {
     pub fn start_local(&self) -> DateTime<Local> { self.start.into() }
     pub fn end_local(&self) -> DateTime<Local> { self.end.into() }
@@ -82,6 +212,56 @@ impl Event {
         all.extend(self.additional_ticket_types.clone());
         all
     }
+
+    pub fn sales_close_or_end(&self) -> DateTime<Utc> { self.sales_close.unwrap_or(self.end) }
+
+    pub fn sales(&self, now: DateTime<Utc>) -> Sales {
+        match self.status {
+            EventStatus::Draft => Sales::Unpublished,
+            EventStatus::Closed => Sales::Closed,
+            EventStatus::Published => match self.sales_open {
+                Some(open) if now < open => Sales::NotOpenYet(open),
+                _ if now >= self.sales_close_or_end() => Sales::Closed,
+                _ => Sales::Open,
+            },
+        }
+    }
+
+    // Whether the public event list shows it, which it does until it's over
+    pub fn is_listed(&self, now: DateTime<Utc>) -> bool {
+        !self.archived && self.status != EventStatus::Draft && now < self.end
+    }
+
+    // Whether the calendar feed has it. Subscribed calendars delete whatever leaves the feed, so
+    // finished events stay a while rather than vanishing as soon as they're over.
+    pub fn in_calendar_feed(&self, now: DateTime<Utc>) -> bool {
+        self.is_listed(now - Duration::days(CALENDAR_FEED_KEEPS_DAYS))
+    }
+}
+
+impl NewEvent {
+    pub fn ticket_types(&self) -> TicketTypes {
+        let mut all = Vec::new();
+        all.push(self.default_ticket_type.clone());
+        all.extend(self.additional_ticket_types.clone());
+        all
+    }
+
+    // The same event `offset` later, sales window, slots and all
+    pub fn shifted(&self, offset: Duration) -> NewEvent {
+        let mut slots = self.slots.clone();
+        for slot in &mut slots.list {
+            slot.start = slot.start.map(|t| t + offset);
+        }
+        NewEvent {
+            start: self.start + offset,
+            end: self.end + offset,
+            sales_open: self.sales_open.map(|t| t + offset),
+            sales_close: self.sales_close.map(|t| t + offset),
+            slots,
+            ..self.clone()
+        }
+    }
 }
 
 ////////////////////////// Functions that run on the server //////////////////////////////////////
@@ -89,7 +269,10 @@ impl Event {
 #[cfg(not(target_arch = "wasm32"))]
 cfg_if::cfg_if! {
 if #[cfg(not(target_arch = "wasm32"))] {
+    use crate::audit;
+    use crate::auth::authz;
     use crate::booking::GOOD_STATUSES;
+    use crate::role::RoleId;
     use crate::{surreal, AppState};
     use leptos::use_context;
     use surrealdb::sql::Thing;
@@ -97,49 +280,89 @@ if #[cfg(not(target_arch = "wasm32"))] {
 
 #[leptos::server(name=CreateEvent, prefix="/api", endpoint="create_event", input = Json, output = Json)]
 pub async fn new_event(e: NewEvent) -> Result<String, ServerFnError> {
+    authz::require_role(RoleId::organiser())?;
     let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
+    backend::validate(&e)?;
 
     let r: surreal::Record = app_state
         .db
         .create("event")
-        .content(e)
+        .content(&e)
         .await?
         .pop()
         .ok_or(ServerFnError::new("failed to create new event"))?;
+
+    audit::record(&app_state.db, "create_event", &r.id, audit::diff(None, Some(&e))).await;
     Ok(r.id.to_string())
 }
 
+// Replaces everything about an event except whether it's archived. Slots and ticket types that
+// have been booked can't be removed, and slots can't shrink below what has been sold.
+#[leptos::server(name=UpdateEvent, prefix="/api", endpoint="update_event", input = Json, output = Json)]
+pub async fn update_event(id: EventId, e: NewEvent) -> Result<(), ServerFnError> {
+    backend::update(id, e).await
+}
+
+// Archiving hides an event from the event list without touching its bookings, pass false to
+// bring it back
+#[leptos::server(ArchiveEvent, "/api", "Url", "archive_event")]
+pub async fn archive_event(id: EventId, archived: bool) -> Result<(), ServerFnError> {
+    backend::archive(id, archived).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use backend::{check_booked, list, validate};
+
+// A new draft copy of an event `offset_days` later, with `suffix` added to its name. Nothing
+// about bookings is copied, only what the event editor shows.
+#[leptos::server(CloneEvent, "/api", "Url", "clone_event")]
+pub async fn clone_event(
+    id: EventId,
+    offset_days: i64,
+    suffix: String,
+) -> Result<EventId, ServerFnError> {
+    backend::clone(id, offset_days, suffix).await
+}
+
+// Organisers can ask for everything, including drafts and finished or archived events
 #[leptos::server(ListEvents, "/api", "Url", "list_events")]
-pub async fn list_events() -> Result<Vec<Event>, ServerFnError> {
+pub async fn list_events(everything: bool) -> Result<Vec<Event>, ServerFnError> {
     let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
+    if everything {
+        authz::require_role(RoleId::organiser())?;
+    }
+    backend::list(&app_state.db, everything).await
+}
 
-    // TODO - get DbEvent then into Event?
-    let events: Vec<Event> = app_state
-        .db
-        .query("SELECT meta::id(id) as id, * FROM event;")
-        .await
-        .map_err(|e| ServerFnError::new(format!("db query failed: {e:?}")))?
-        .take(0)?;
-
-    Ok(events)
+// The listed events with no tickets left to book
+#[leptos::server(ListSoldOut, "/api", "Url", "list_sold_out")]
+pub async fn list_sold_out() -> Result<Vec<EventId>, ServerFnError> {
+    backend::list_sold_out().await
 }
 
 #[leptos::server(GetEvent, "/api", "Url", "get_event")]
 pub async fn get_event(id: EventId) -> Result<Event, ServerFnError> {
     let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
+    backend::visible(&app_state, &id).await
+}
 
-    let event: DbEvent = app_state
-        .db
-        .select(id)
-        .await?
-        .ok_or(ServerFnError::new("no event found"))?;
+// The event's description as HTML that's safe to put straight into the page
+#[leptos::server(GetEventDescription, "/api", "Url", "get_event_description")]
+pub async fn get_event_description(id: EventId) -> Result<String, ServerFnError> {
+    backend::description(id).await
+}
 
-    Ok(event.into())
+// How a description will look, for the event editor
+#[leptos::server(PreviewDescription, "/api", "Url", "preview_description")]
+pub async fn preview_description(markdown: String) -> Result<String, ServerFnError> {
+    authz::require_role(RoleId::organiser())?;
+    Ok(backend::render(&markdown))
 }
 
 #[leptos::server(GetSlotDetails, "/api", "Url", "get_slot_details")]
 pub async fn get_slot_details(id: EventId) -> Result<Vec<SlotDetail>, ServerFnError> {
     let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
+    backend::visible(&app_state, &id).await?;
 
     let query = "
     select name,
@@ -161,6 +384,351 @@ pub async fn get_slot_details(id: EventId) -> Result<Vec<SlotDetail>, ServerFnEr
     Ok(details)
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use super::{DbEvent, Event, EventId, EventStatus, NewEvent};
+    use crate::schema::Schema;
+    use crate::surreal;
+    use chrono::{Duration, Utc};
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::role::RoleId;
+    use crate::ticket::Ticket;
+    use crate::AppState;
+    use leptos::use_context;
+    use leptos::ServerFnError::{self, ServerError};
+    use pulldown_cmark::{html, Options, Parser};
+    use rust_decimal::Decimal;
+    use std::collections::{HashMap, HashSet};
+    use surrealdb::sql::Thing;
+    use surrealdb::{engine::any::Any, Surreal};
+    use tracing::info;
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        NotFound(EventId),
+        Invalid(String),
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::NotFound(id) => format!("no event with id '{}'", id),
+                Fail::Invalid(msg) => msg,
+            };
+            ServerError(msg)
+        }
+    }
+
+    fn invalid(msg: impl Into<String>) -> ServerFnError { Fail::Invalid(msg.into()).into() }
+
+    fn unique<'a>(
+        what: &str,
+        names: impl Iterator<Item = &'a String>,
+    ) -> Result<(), ServerFnError> {
+        let mut seen = HashSet::new();
+        for name in names {
+            if name.trim().is_empty() {
+                return Err(invalid(format!("every {} needs a name", what)));
+            }
+            if !seen.insert(name) {
+                return Err(invalid(format!("there is more than one {} called '{}'", what, name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(e: &NewEvent) -> Result<(), ServerFnError> {
+        if e.name.trim().is_empty() {
+            return Err(invalid("the event needs a name"));
+        }
+        if e.end <= e.start {
+            return Err(invalid("the event must end after it starts"));
+        }
+        if let Some(open) = e.sales_open {
+            if open >= e.sales_close.unwrap_or(e.end) {
+                return Err(invalid("sales must open before they close"));
+            }
+        }
+
+        let ticket_types = e.ticket_types();
+        unique("ticket type", ticket_types.iter().map(|tt| &tt.name))?;
+        for tt in &ticket_types {
+            if tt.price < Decimal::ZERO {
+                return Err(invalid(format!("the price of '{}' can't be negative", tt.name)));
+            }
+            if tt.available.unwrap_or(0) < 0 {
+                return Err(invalid(format!("the number of '{}' can't be negative", tt.name)));
+            }
+        }
+
+        unique("slot", e.slots.list.iter().map(|s| &s.name))?;
+        for slot in &e.slots.list {
+            if slot.capacity.unwrap_or(0) < 0 {
+                return Err(invalid(format!("the capacity of '{}' can't be negative", slot.name)));
+            }
+            if slot.start.is_some_and(|t| t < e.start || t >= e.end) {
+                return Err(invalid(format!("'{}' must start while the event is on", slot.name)));
+            }
+        }
+
+        // Image keys come back from uploading, anything else didn't come from us
+        let mut images = e.hero_image.iter().chain(&e.gallery);
+        if images.any(|key| key.is_empty() || key.contains(['/', '\\'])) {
+            return Err(invalid("the event has an image that wasn't uploaded here"));
+        }
+        Ok(())
+    }
+
+    // Markdown to HTML, with anything that could run script or restyle the page stripped out
+    pub fn render(markdown: &str) -> String {
+        let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+        let mut unsafe_html = String::new();
+        html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+        ammonia::clean(&unsafe_html)
+    }
+
+    pub async fn description(id: EventId) -> Result<String, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        let event = visible(&app_state, &id).await?;
+        Ok(render(&event.description))
+    }
+
+    // Drafts and archived or finished events are only for organisers, anyone else is told
+    // there's no such event
+    pub async fn visible(app_state: &AppState, id: &EventId) -> Result<Event, ServerFnError> {
+        let event = get(app_state, id).await?;
+        let organiser = authz::current_user().is_ok_and(|u| u.has_role(&RoleId::organiser()));
+        if !organiser && !event.is_listed(Utc::now()) {
+            return Err(Fail::NotFound(id.clone()).into());
+        }
+        Ok(event)
+    }
+
+    // Tickets that have been booked, by slot and by ticket type. Cancelled bookings and drafts
+    // that haven't gone to payment can be ignored, anything else has been or is being paid for.
+    // The janitor keeps the same drafts.
+    async fn booked(
+        app_state: &AppState,
+        id: &EventId,
+    ) -> Result<(HashMap<String, i64>, HashMap<String, i64>), Fail> {
+        let tickets: Vec<Vec<Ticket>> = app_state
+            .db
+            .query(
+                "SELECT VALUE tickets FROM booking \
+                 WHERE event_id=$event AND status != 'Cancelled' \
+                 AND (status != 'Draft' OR array::len(payments) > 0 \
+                 OR (square_order != NONE AND square_order != NULL));",
+            )
+            .bind(("event", Thing::from(id)))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+
+        let mut by_slot = HashMap::new();
+        let mut by_type = HashMap::new();
+        for ticket in tickets.into_iter().flatten() {
+            if let Some(slot) = ticket.slot_name {
+                *by_slot.entry(slot).or_insert(0) += 1;
+            }
+            *by_type.entry(ticket.ticket_type.name).or_insert(0) += 1;
+        }
+        Ok((by_slot, by_type))
+    }
+
+    // Sold out when every ticket type or every slot has a limit, and it has been reached
+    fn is_sold_out(
+        event: &Event,
+        by_slot: &HashMap<String, i64>,
+        by_type: &HashMap<String, i64>,
+    ) -> bool {
+        let full = |limit: Option<i64>, sold: Option<&i64>| match limit {
+            Some(limit) => *sold.unwrap_or(&0) >= limit,
+            None => false,
+        };
+        let ticket_types = event.ticket_types();
+        let slots = &event.slots.list;
+        ticket_types.iter().all(|tt| full(tt.available, by_type.get(&tt.name)))
+            || (!slots.is_empty() && slots.iter().all(|s| full(s.capacity, by_slot.get(&s.name))))
+    }
+
+    pub async fn list_sold_out() -> Result<Vec<EventId>, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let events: Vec<DbEvent> = app_state.db.select("event").await.map_err(Fail::DbError)?;
+        let now = Utc::now();
+
+        let mut sold_out = Vec::new();
+        for event in events.into_iter().map(Event::from) {
+            if !event.is_listed(now) {
+                continue;
+            }
+            let (by_slot, by_type) = booked(&app_state, &event.id).await?;
+            if is_sold_out(&event, &by_slot, &by_type) {
+                sold_out.push(event.id);
+            }
+        }
+        Ok(sold_out)
+    }
+
+    // Takes the database rather than the app state so it can be used outside server functions,
+    // e.g. for the calendar feed. Checking who can have everything is up to the caller.
+    pub async fn list(db: &Surreal<Any>, everything: bool) -> Result<Vec<Event>, ServerFnError> {
+        // TODO - get DbEvent then into Event?
+        let events: Vec<Event> = db
+            .query("SELECT meta::id(id) as id, * FROM event;")
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+
+        let now = Utc::now();
+        Ok(events
+            .into_iter()
+            .filter(|e| everything || e.is_listed(now))
+            .collect())
+    }
+
+    async fn get(app_state: &AppState, id: &EventId) -> Result<Event, Fail> {
+        let event: Option<DbEvent> = app_state.db.select(id).await.map_err(Fail::DbError)?;
+        event.map(Event::from).ok_or(Fail::NotFound(id.clone()))
+    }
+
+    // Slots and ticket types that have been booked have to stay, with room for what was sold
+    pub async fn check_booked(
+        app_state: &AppState,
+        id: &EventId,
+        e: &NewEvent,
+    ) -> Result<(), ServerFnError> {
+        let (by_slot, by_type) = booked(app_state, id).await?;
+
+        for (slot, sold) in by_slot {
+            match e.slots.list.iter().find(|s| s.name == slot) {
+                None => {
+                    let msg = format!("'{}' can't be removed, {} are booked in it", slot, sold);
+                    return Err(invalid(msg));
+                }
+                Some(s) if s.capacity.is_some_and(|c| c < sold) => {
+                    let msg = format!("'{}' already has {} tickets booked in it", slot, sold);
+                    return Err(invalid(msg));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let ticket_types = e.ticket_types();
+        for (name, sold) in by_type {
+            match ticket_types.iter().find(|tt| tt.name == name) {
+                None => {
+                    let msg = format!("'{}' can't be removed, {} have been booked", name, sold);
+                    return Err(invalid(msg));
+                }
+                Some(tt) if tt.available.is_some_and(|a| a < sold) => {
+                    let msg = format!("{} '{}' tickets have already been booked", sold, name);
+                    return Err(invalid(msg));
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn update(id: EventId, e: NewEvent) -> Result<(), ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        validate(&e)?;
+
+        let before: NewEvent = get(&app_state, &id).await?.into();
+        check_booked(&app_state, &id, &e).await?;
+
+        app_state
+            .db
+            .query("UPDATE $event MERGE $content;")
+            .bind(("event", Thing::from(&id)))
+            .bind(("content", &e))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        info!("updated event {}", id);
+        let changes = audit::diff(Some(&before), Some(&e));
+        audit::record(&app_state.db, "update_event", &Thing::from(&id), changes).await;
+        Ok(())
+    }
+
+    pub async fn clone(
+        id: EventId,
+        offset_days: i64,
+        suffix: String,
+    ) -> Result<EventId, ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let original: NewEvent = get(&app_state, &id).await?.into();
+        let copy = NewEvent {
+            name: format!("{}{}", original.name, suffix),
+            status: EventStatus::Draft,
+            series: None,
+            occurrence: None,
+            ..original.shifted(Duration::days(offset_days))
+        };
+        validate(&copy)?;
+
+        let record: Option<surreal::Record> = app_state
+            .db
+            .create(Event::TABLE)
+            .content(&copy)
+            .await
+            .map_err(Fail::DbError)?
+            .pop();
+        let record = record.ok_or(Fail::Invalid("failed to create new event".to_string()))?;
+
+        info!("cloned event {} as {}", id, record.id);
+        let mut changes = audit::diff(None, Some(&copy));
+        changes.push(audit::Change {
+            field: "cloned_from".to_string(),
+            before: None,
+            after: Some(Thing::from(&id).to_string()),
+        });
+        audit::record(&app_state.db, "clone_event", &record.id, changes).await;
+        Ok(record.id.into())
+    }
+
+    pub async fn archive(id: EventId, archived: bool) -> Result<(), ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let event = get(&app_state, &id).await?;
+        if event.archived == archived {
+            return Ok(());
+        }
+
+        app_state
+            .db
+            .query("UPDATE $event SET archived=$archived;")
+            .bind(("event", Thing::from(&id)))
+            .bind(("archived", archived))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let action = if archived { "archive_event" } else { "restore_event" };
+        let changes = vec![audit::Change {
+            field: "archived".to_string(),
+            before: Some(event.archived.to_string()),
+            after: Some(archived.to_string()),
+        }];
+        audit::record(&app_state.db, action, &Thing::from(&id), changes).await;
+        Ok(())
+    }
+}
+
 ////////////////////////// Testy McTest Face //////////////////////////////////////
 
 // #[cfg(test)]
@@ -211,3 +779,142 @@ pub async fn get_slot_details(id: EventId) -> Result<Vec<SlotDetail>, ServerFnEr
 //     }
 // }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rust_decimal::Decimal;
+
+    fn at(hour: u32) -> DateTime<Utc> { Utc.with_ymd_and_hms(2024, 6, 1, hour, 0, 0).unwrap() }
+
+    // Runs from 18:00 to 21:00
+    fn event(status: EventStatus) -> Event {
+        Event {
+            id: EventId::from("test"),
+            name: "Test".to_string(),
+            tagline: "".to_string(),
+            description: "".to_string(),
+            hero_image: None,
+            gallery: Vec::new(),
+            venue: None,
+            default_ticket_type: TicketType {
+                name: "Standard".to_string(),
+                price: Decimal::ZERO,
+                square_item_id: "".to_string(),
+                square_catalog_version: 0,
+                available: None,
+            },
+            additional_ticket_types: Vec::new(),
+            slots: Slots {
+                description: None,
+                list: Vec::new(),
+            },
+            start: at(18),
+            end: at(21),
+            status,
+            sales_open: None,
+            sales_close: None,
+            series: None,
+            occurrence: None,
+            archived: false,
+        }
+    }
+
+    #[test]
+    fn sales_follow_the_status() {
+        assert_eq!(event(EventStatus::Draft).sales(at(12)), Sales::Unpublished);
+        assert_eq!(event(EventStatus::Closed).sales(at(12)), Sales::Closed);
+        assert_eq!(event(EventStatus::Published).sales(at(12)), Sales::Open);
+    }
+
+    #[test]
+    fn sales_stay_open_until_the_end_by_default() {
+        let e = event(EventStatus::Published);
+        assert_eq!(e.sales(at(20)), Sales::Open);
+        assert_eq!(e.sales(at(21)), Sales::Closed);
+    }
+
+    #[test]
+    fn sales_follow_the_window() {
+        let e = Event {
+            sales_open: Some(at(9)),
+            sales_close: Some(at(17)),
+            ..event(EventStatus::Published)
+        };
+        assert_eq!(e.sales(at(8)), Sales::NotOpenYet(at(9)));
+        assert_eq!(e.sales(at(9)), Sales::Open);
+        assert_eq!(e.sales(at(16)), Sales::Open);
+        assert_eq!(e.sales(at(17)), Sales::Closed);
+    }
+
+    #[test]
+    fn listed_until_over_unless_draft_or_archived() {
+        assert!(event(EventStatus::Published).is_listed(at(20)));
+        assert!(event(EventStatus::Closed).is_listed(at(20)));
+        assert!(!event(EventStatus::Published).is_listed(at(21)));
+        assert!(!event(EventStatus::Draft).is_listed(at(12)));
+        let archived = Event {
+            archived: true,
+            ..event(EventStatus::Published)
+        };
+        assert!(!archived.is_listed(at(12)));
+    }
+
+    #[test]
+    fn calendar_feed_keeps_finished_events_for_a_while() {
+        let e = event(EventStatus::Published);
+        let finished = at(21) + Duration::days(CALENDAR_FEED_KEEPS_DAYS);
+        assert!(e.in_calendar_feed(at(22)));
+        assert!(e.in_calendar_feed(finished - Duration::minutes(1)));
+        assert!(!e.in_calendar_feed(finished));
+        assert!(!event(EventStatus::Draft).in_calendar_feed(at(12)));
+        let archived = Event {
+            archived: true,
+            ..event(EventStatus::Published)
+        };
+        assert!(!archived.in_calendar_feed(at(12)));
+    }
+
+    #[test]
+    fn shifting_moves_every_time() {
+        let e = NewEvent {
+            sales_open: Some(at(9)),
+            sales_close: Some(at(17)),
+            slots: Slots {
+                description: None,
+                list: vec![Slot {
+                    name: "Late".to_string(),
+                    capacity: None,
+                    start: Some(at(19)),
+                }],
+            },
+            ..event(EventStatus::Published).into()
+        };
+        let week = Duration::weeks(1);
+        let shifted = e.shifted(week);
+        assert_eq!((shifted.start, shifted.end), (at(18) + week, at(21) + week));
+        assert_eq!(shifted.sales_open, Some(at(9) + week));
+        assert_eq!(shifted.sales_close, Some(at(17) + week));
+        assert_eq!(shifted.slots.list[0].start, Some(at(19) + week));
+        assert_eq!(shifted.name, e.name);
+    }
+
+    #[test]
+    fn descriptions_render_markdown() {
+        let html = backend::render("# Quiz\n\n**Bring** a [pen](https://example.com).");
+        assert!(html.contains("<h1>Quiz</h1>"), "{}", html);
+        assert!(html.contains("<strong>Bring</strong>"), "{}", html);
+        assert!(html.contains("href=\"https://example.com\""), "{}", html);
+    }
+
+    #[test]
+    fn descriptions_cant_run_script() {
+        let markdown = "<script>alert(1)</script>\n\n<img src=x onerror=alert(1)>\n\n\
+                        [click](javascript:alert(1)) <style>body{display:none}</style>";
+        let html = backend::render(markdown);
+        for bad in ["<script", "onerror", "javascript:", "<style"] {
+            assert!(!html.contains(bad), "{} in {}", bad, html);
+        }
+    }
+}
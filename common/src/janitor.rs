@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use leptos::ServerFnError;
+use serde::{Deserialize, Serialize};
+
+// How the janitor's last go at one kind of leftover went, and how much it has removed in all
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SweepReport {
+    pub name: String,
+    pub last_run_at: DateTime<Utc>,
+    pub last_removed: u64,
+    pub total_removed: u64,
+    pub last_error: Option<String>,
+}
+
+#[leptos::server(ListSweeps, "/api", "Url", "list_sweeps")]
+pub async fn list_sweeps() -> Result<Vec<SweepReport>, ServerFnError> {
+    backend::list_sweeps().await
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use surrealdb::engine::any::Any;
+use surrealdb::sql::Thing;
+use surrealdb::Surreal;
+
+const TABLE: &str = "janitor_run";
+
+// Keep a note of how a sweep went, for admins to look at
+pub async fn record(
+    db: &Surreal<Any>,
+    name: &str,
+    outcome: &Result<u64, String>,
+) -> Result<(), surrealdb::Error> {
+    let (removed, error) = match outcome {
+        Ok(removed) => (*removed, None),
+        Err(e) => (0, Some(e.clone())),
+    };
+
+    db.query(
+        "UPDATE $run SET name=$name, last_run_at=$now, last_removed=$removed, \
+         total_removed=(total_removed OR 0) + $removed, last_error=$error;",
+    )
+    .bind(("run", Thing::from((TABLE, name))))
+    .bind(("name", name))
+    .bind(("now", Utc::now()))
+    .bind(("removed", removed))
+    .bind(("error", error))
+    .await?
+    .check()?;
+    Ok(())
+}
+
+enum Fail {
+    NoAppState,
+    DbError(surrealdb::Error),
+}
+
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let msg = match fail {
+            Fail::NoAppState => "no app state in context".to_string(),
+            Fail::DbError(e) => format!("database error: {:?}", e),
+        };
+        ServerFnError::ServerError(msg)
+    }
+}
+
+mod backend {
+    use super::*;
+    use crate::auth::authz;
+    use crate::role::RoleId;
+    use crate::AppState;
+    use leptos::use_context;
+
+    pub async fn list_sweeps() -> Result<Vec<SweepReport>, ServerFnError> {
+        authz::require_role(RoleId::admin())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let reports: Vec<SweepReport> = app_state
+            .db
+            .query("SELECT * FROM type::table($table) ORDER BY name;")
+            .bind(("table", TABLE))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        Ok(reports)
+    }
+}
+
+}}
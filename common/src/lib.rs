@@ -1,22 +1,31 @@
+pub mod audit;
 pub mod auth;
 pub mod booking;
+pub mod calendar;
 pub mod config;
 pub mod error_handling;
 pub mod event;
 pub mod generic_id;
+pub mod janitor;
+pub mod mail;
 pub mod person;
 pub mod role;
 pub mod schema;
+pub mod series;
 pub mod square_api;
 pub mod ticket;
 pub mod user;
+pub mod venue;
 
 cfg_if::cfg_if! {
 if #[cfg(not(target_arch = "wasm32"))] {
     pub mod axum;
+    pub mod storage;
     pub mod surreal;
+    use std::sync::Arc;
     use surrealdb::{engine::any::Any, Surreal};
     use config::Config;
+    use storage::Storage;
 }}
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -24,5 +33,6 @@ if #[cfg(not(target_arch = "wasm32"))] {
 pub struct AppState {
     pub config: Config,
     pub db: Surreal<Any>,
+    pub storage: Arc<dyn Storage>,
 }
 
@@ -0,0 +1,120 @@
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+
+use crate::config;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment as MimeAttachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use leptos::ServerFnError::{self, ServerError};
+use tracing::info;
+
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Fail {
+    BadAddress(String),
+    BuildFailed(lettre::error::Error),
+    BadContentType(String),
+    SmtpFailed(lettre::transport::smtp::Error),
+}
+
+impl From<Fail> for ServerFnError {
+    fn from(fail: Fail) -> Self {
+        let msg = match fail {
+            Fail::BadAddress(a) => format!("invalid email address '{}'", a),
+            Fail::BuildFailed(e) => format!("failed to build email: {}", e),
+            Fail::BadContentType(t) => format!("invalid attachment content type '{}'", t),
+            Fail::SmtpFailed(e) => format!("failed to send email: {}", e),
+        };
+        ServerError(msg)
+    }
+}
+
+// To add a new way of sending mail, implement this and add a variant to config::MailTransport
+trait Transport {
+    async fn send(&self, from: &str, mail: Mail) -> Result<(), Fail>;
+}
+
+struct LogTransport;
+
+impl Transport for LogTransport {
+    async fn send(&self, from: &str, mail: Mail) -> Result<(), Fail> {
+        let attached: Vec<_> = mail.attachments.iter().map(|a| a.filename.as_str()).collect();
+        info!(
+            "mail from {} to {}\nsubject: {}\nattached: {:?}\n\n{}",
+            from, mail.to, mail.subject, attached, mail.body
+        );
+        Ok(())
+    }
+}
+
+struct SmtpTransport(AsyncSmtpTransport<Tokio1Executor>);
+
+impl SmtpTransport {
+    fn new(host: &str, port: u16, username: &str, password: &str) -> Result<Self, Fail> {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(Fail::SmtpFailed)?
+            .port(port)
+            .credentials(Credentials::new(username.to_string(), password.to_string()))
+            .build();
+        Ok(Self(transport))
+    }
+}
+
+impl Transport for SmtpTransport {
+    async fn send(&self, from: &str, mail: Mail) -> Result<(), Fail> {
+        let from: Mailbox = from.parse().map_err(|_| Fail::BadAddress(from.to_string()))?;
+        let to: Mailbox = mail.to.parse().map_err(|_| Fail::BadAddress(mail.to.clone()))?;
+
+        let builder = Message::builder().from(from).to(to).subject(mail.subject);
+        let message = match mail.attachments.is_empty() {
+            true => builder.body(mail.body),
+            false => {
+                let mut parts = MultiPart::mixed().singlepart(SinglePart::plain(mail.body));
+                for a in mail.attachments {
+                    let content_type = ContentType::parse(&a.content_type)
+                        .map_err(|_| Fail::BadContentType(a.content_type.clone()))?;
+                    let attachment = MimeAttachment::new(a.filename).body(a.data, content_type);
+                    parts = parts.singlepart(attachment);
+                }
+                builder.multipart(parts)
+            }
+        }
+        .map_err(Fail::BuildFailed)?;
+
+        self.0.send(message).await.map_err(Fail::SmtpFailed)?;
+        Ok(())
+    }
+}
+
+pub async fn send(cfg: &config::Mail, mail: Mail) -> Result<(), Fail> {
+    match &cfg.transport {
+        config::MailTransport::Log => LogTransport.send(&cfg.from, mail).await,
+        config::MailTransport::Smtp {
+            host,
+            port,
+            username,
+            password,
+        } => {
+            SmtpTransport::new(host, *port, username, password)?
+                .send(&cfg.from, mail)
+                .await
+        }
+    }
+}
+
+}}
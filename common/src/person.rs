@@ -13,6 +13,8 @@ pub struct Person {
     pub family_name: String,
     pub picture: Option<String>,
     pub email: String,
+    #[serde(default)]
+    pub email_verified: bool,
     pub phone: Option<String>,
 }
 
@@ -35,6 +37,8 @@ pub mod db {
         pub family_name: String,
         pub picture: Option<String>,
         pub email: String,
+        #[serde(default)]
+        pub email_verified: bool,
         pub phone: Option<String>,
     }
 
@@ -46,6 +50,7 @@ pub mod db {
                 family_name: item.family_name,
                 picture: item.picture,
                 email: item.email,
+                email_verified: item.email_verified,
                 phone: item.phone,
             }
         }
@@ -57,6 +62,8 @@ pub mod db {
         pub family_name: String,
         pub picture: Option<String>,
         pub email: String,
+        #[serde(default)]
+        pub email_verified: bool,
         pub phone: Option<String>,
     }
 }
@@ -69,21 +76,24 @@ pub async fn get_logged_in_person() -> Result<Person, ServerFnError> {
     backend::get_logged_in().await
 }
 
-#[server(PersonExists, "/api", "Url", "person_exists")]
-pub async fn person_exists(email: String) -> Result<bool, ServerFnError> {
-    backend::person_exists(email).await
-}
-
 #[cfg(not(target_arch = "wasm32"))]
 mod backend {
     // use super::db;
     use super::*;
+    use crate::auth::authz;
+    use crate::role::RoleId;
     use crate::schema::Schema;
-    use crate::{axum::LoggedInUser, surreal, AppState};
+    use crate::AppState;
     use leptos::use_context;
     use surrealdb::sql::Thing;
 
     pub async fn get(id: PersonId) -> Result<Person, leptos::ServerFnError> {
+        // People can look themselves up, organisers can look up anyone
+        let user = authz::require_role(RoleId::attendee())?;
+        if user.person.id != id {
+            authz::require_role(RoleId::organiser())?;
+        }
+
         let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
 
         let people: Vec<db::DbPerson> = app_state
@@ -108,28 +118,7 @@ mod backend {
     }
 
     pub async fn get_logged_in() -> Result<Person, leptos::ServerFnError> {
-        let u = use_context::<Option<LoggedInUser>>();
-        match u {
-            None => Err(ServerFnError::new("no current user in context")),
-            Some(None) => Err(ServerFnError::new("no current user in context")),
-            Some(Some(LoggedInUser(person))) => Ok(person),
-        }
-    }
-
-    pub async fn person_exists(email: String) -> Result<bool, leptos::ServerFnError> {
-        let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
-
-        let people: Vec<surreal::Record> = app_state
-            .db
-            .query(format!(
-                "SELECT {} FROM {} where email=$email;",
-                Person::SELECT,
-                Person::TABLE
-            ))
-            .bind(("email", &email))
-            .await?
-            .take(0)?;
-        Ok(!people.is_empty())
+        Ok(authz::current_user()?.person)
     }
 }
 
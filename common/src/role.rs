@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
 use crate::generic_id::Id;
+use crate::person::PersonId;
+use crate::schema::Schema;
 
 pub type RoleId = Id<Role>;
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -8,6 +10,24 @@ pub struct Role {
     pub id: RoleId,
 }
 
+impl Schema for Role {
+    const TABLE: &'static str = "role";
+}
+
+impl RoleId {
+    pub fn admin() -> Self { "admin".into() }
+    pub fn organiser() -> Self { "organiser".into() }
+    pub fn attendee() -> Self { "attendee".into() }
+
+    // Roles are hierarchical: an admin can do anything an organiser can, and an organiser
+    // can do anything an attendee can.
+    pub fn grants(&self, required: &RoleId) -> bool {
+        self == required
+            || *self == Self::admin()
+            || (*self == Self::organiser() && *required == Self::attendee())
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct DbRole {
@@ -19,3 +39,125 @@ impl From<DbRole> for Role {
     fn from(item: DbRole) -> Self { Self { id: item.id.into() } }
 }
 
+#[leptos::server(GetLoggedInRoles, "/api", "Url", "get_logged_in_roles")]
+pub async fn get_logged_in_roles() -> Result<Vec<RoleId>, leptos::ServerFnError> {
+    Ok(crate::auth::authz::current_user()?.roles)
+}
+
+#[leptos::server(GrantRole, "/api", "Url", "grant_role")]
+pub async fn grant_role(person: PersonId, role: RoleId) -> Result<(), leptos::ServerFnError> {
+    backend::grant(person, role).await
+}
+
+#[leptos::server(RevokeRole, "/api", "Url", "revoke_role")]
+pub async fn revoke_role(person: PersonId, role: RoleId) -> Result<(), leptos::ServerFnError> {
+    backend::revoke(person, role).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use super::*;
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::user::db;
+    use crate::AppState;
+    use leptos::use_context;
+    use leptos::ServerFnError::{self, ServerError};
+    use surrealdb::engine::any::Any;
+    use surrealdb::sql::Thing;
+    use surrealdb::Surreal;
+    use tracing::info;
+
+    enum Fail {
+        NoAppState,
+        DbError(surrealdb::Error),
+        PersonNotFound,
+        OwnAdmin,
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoAppState => "no app state in context".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::PersonNotFound => "no such person".to_string(),
+                Fail::OwnAdmin => "you can't take away your own admin role".to_string(),
+            };
+            ServerError(msg)
+        }
+    }
+
+    async fn roles(db: &Surreal<Any>, person: &Thing) -> Result<Vec<RoleId>, Fail> {
+        let user = db::find_by_id(db, person)
+            .await
+            .map_err(Fail::DbError)?
+            .ok_or(Fail::PersonNotFound)?;
+        Ok(user.roles.into_iter().map(|r| r.into()).collect())
+    }
+
+    pub async fn grant(person: PersonId, role: RoleId) -> Result<(), ServerFnError> {
+        let admin = authz::require_role(RoleId::admin())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        let person = Thing::from(&person);
+        let before = roles(&app_state.db, &person).await?;
+        if before.contains(&role) {
+            return Ok(());
+        }
+
+        info!("{} granting {} to {}", admin.person.email, role, person);
+        let _: Option<crate::surreal::Record> =
+            app_state.db.update(&role).await.map_err(Fail::DbError)?;
+        app_state
+            .db
+            .query("RELATE $person->has_role->$role;")
+            .bind(("person", &person))
+            .bind(("role", Thing::from(&role)))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let after = roles(&app_state.db, &person).await?;
+        let changes = audit::diff(Some(&Roles { roles: before }), Some(&Roles { roles: after }));
+        audit::record(&app_state.db, "grant_role", &person, changes).await;
+        Ok(())
+    }
+
+    pub async fn revoke(person: PersonId, role: RoleId) -> Result<(), ServerFnError> {
+        let admin = authz::require_role(RoleId::admin())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoAppState)?;
+
+        // Somebody has to be left who can hand out roles
+        if person == admin.person.id && role == RoleId::admin() {
+            return Err(Fail::OwnAdmin.into());
+        }
+
+        let person = Thing::from(&person);
+        let before = roles(&app_state.db, &person).await?;
+        if !before.contains(&role) {
+            return Ok(());
+        }
+
+        info!("{} revoking {} from {}", admin.person.email, role, person);
+        app_state
+            .db
+            .query("DELETE has_role WHERE in=$person AND out=$role;")
+            .bind(("person", &person))
+            .bind(("role", Thing::from(&role)))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let after = roles(&app_state.db, &person).await?;
+        let changes = audit::diff(Some(&Roles { roles: before }), Some(&Roles { roles: after }));
+        audit::record(&app_state.db, "revoke_role", &person, changes).await;
+        Ok(())
+    }
+
+    #[derive(Serialize)]
+    struct Roles {
+        roles: Vec<RoleId>,
+    }
+}
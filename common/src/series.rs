@@ -0,0 +1,403 @@
+use crate::event::NewEvent;
+use crate::generic_id::Id;
+use crate::schema::Schema;
+use chrono::{DateTime, Days, Months, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use leptos::server_fn::codec::Json;
+use leptos::ServerFnError;
+use macros::generate_new;
+use serde::{Deserialize, Serialize};
+
+pub type SeriesId = Id<EventSeries>;
+impl Schema for EventSeries {
+    const TABLE: &'static str = "event_series";
+}
+
+// The most events one series can make
+pub const MAX_EVENTS: usize = 100;
+
+// An event that happens again and again. Each event in the series is a copy of the template moved
+// to its own start time, with the template's start being the first.
+#[generate_new]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct EventSeries {
+    pub id: SeriesId,
+    pub template: NewEvent,
+    pub recurrence: Recurrence,
+    // Where the events happen. Series from before this was kept were all in London.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+}
+
+fn default_timezone() -> Tz { chrono_tz::Europe::London }
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub enum Recurrence {
+    Weekly { count: u32 },
+    Monthly { count: u32 },
+    // Whatever start times are wanted, the template's start isn't included unless it's listed
+    Dates(Vec<DateTime<Utc>>),
+}
+
+impl Recurrence {
+    // How many events were asked for, which can be checked before working out when they are
+    pub fn count(&self) -> usize {
+        match self {
+            Recurrence::Weekly { count } | Recurrence::Monthly { count } => *count as usize,
+            Recurrence::Dates(dates) => dates.len(),
+        }
+    }
+
+    // When each event in the series starts, at most MAX_EVENTS of them. Weekly and monthly events
+    // are worked out in the series' timezone, so they stay at the same time of day either side of
+    // the clocks changing.
+    pub fn starts(&self, first: DateTime<Utc>, timezone: Tz) -> Vec<DateTime<Utc>> {
+        let first_local = first.with_timezone(&timezone).naive_local();
+        let at = |naive: Option<NaiveDateTime>| {
+            let local = timezone.from_local_datetime(&naive?).earliest()?;
+            Some(local.with_timezone(&Utc))
+        };
+
+        let capped = |count: u32| count.min(MAX_EVENTS as u32);
+
+        match self {
+            Recurrence::Weekly { count } => (0..capped(*count) as u64)
+                .filter_map(|week| at(first_local.checked_add_days(Days::new(week * 7))))
+                .collect(),
+            Recurrence::Monthly { count } => (0..capped(*count))
+                .filter_map(|month| at(first_local.checked_add_months(Months::new(month))))
+                .collect(),
+            Recurrence::Dates(dates) => {
+                let mut dates = dates.clone();
+                dates.sort();
+                dates.dedup();
+                dates.truncate(MAX_EVENTS);
+                dates
+            }
+        }
+    }
+}
+
+#[leptos::server(name=CreateSeries, prefix="/api", endpoint="create_series", input = Json, output = Json)]
+pub async fn create_series(series: NewEventSeries) -> Result<SeriesId, ServerFnError> {
+    backend::create(series).await
+}
+
+// Events that haven't been made yet always follow the new template. With `apply_to_future` set,
+// events still to come that already exist are changed to match it too, the rest are left alone.
+#[leptos::server(name=UpdateSeries, prefix="/api", endpoint="update_series", input = Json, output = Json)]
+pub async fn update_series(
+    id: SeriesId,
+    series: NewEventSeries,
+    apply_to_future: bool,
+) -> Result<(), ServerFnError> {
+    backend::update(id, series, apply_to_future).await
+}
+
+#[leptos::server(ListSeries, "/api", "Url", "list_series")]
+pub async fn list_series() -> Result<Vec<EventSeries>, ServerFnError> { backend::list().await }
+
+#[leptos::server(GetSeries, "/api", "Url", "get_series")]
+pub async fn get_series(id: SeriesId) -> Result<EventSeries, ServerFnError> {
+    backend::get(id).await
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use super::*;
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::event::{self, Event};
+    use crate::role::RoleId;
+    use crate::{surreal, AppState};
+    use chrono::NaiveDate;
+    use leptos::use_context;
+    use leptos::ServerFnError::ServerError;
+    use surrealdb::sql::Thing;
+    use tracing::info;
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        NotFound(SeriesId),
+        NotCreated,
+        Invalid(String),
+        EventNotSaved(NaiveDate, ServerFnError),
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::NotFound(id) => format!("no series with id '{}'", id),
+                Fail::NotCreated => "failed to create new series".to_string(),
+                Fail::Invalid(msg) => msg,
+                Fail::EventNotSaved(date, e) => {
+                    format!("couldn't save the event on {}: {}", date, e)
+                }
+            };
+            ServerError(msg)
+        }
+    }
+
+    fn local_date(t: DateTime<Utc>, timezone: Tz) -> NaiveDate {
+        t.with_timezone(&timezone).date_naive()
+    }
+
+    // Whether an existing event, with its place in the series and start, is the one for this
+    // place and start. Weekly and monthly events keep their place however the template moves. A
+    // custom date is only ever the same event at the same time, so adding an earlier date doesn't
+    // shuffle the rest along.
+    pub(super) fn same_occurrence(
+        recurrence: &Recurrence,
+        timezone: Tz,
+        existing: (Option<u32>, DateTime<Utc>),
+        wanted: (u32, DateTime<Utc>),
+    ) -> bool {
+        match (recurrence, existing.0) {
+            (Recurrence::Dates(_), _) => existing.1 == wanted.1,
+            (_, Some(occurrence)) => occurrence == wanted.0,
+            // Events made before occurrences were kept only ever had one a day
+            (_, None) => local_date(existing.1, timezone) == local_date(wanted.1, timezone),
+        }
+    }
+
+    fn validate(series: &NewEventSeries) -> Result<(), ServerFnError> {
+        event::validate(&series.template)?;
+        // Before working out the starts, so a huge count doesn't get as far as allocating them
+        if series.recurrence.count() > MAX_EVENTS {
+            let msg = format!("a series can have at most {} events", MAX_EVENTS);
+            return Err(Fail::Invalid(msg).into());
+        }
+        if series.recurrence.starts(series.template.start, series.timezone).is_empty() {
+            return Err(Fail::Invalid("the series needs at least one event".to_string()).into());
+        }
+        Ok(())
+    }
+
+    // Makes the events in the series that are still to come and don't exist yet, matching them
+    // up with what's already there by `same_occurrence`. Every event is checked, including
+    // against what has been booked, before any are saved, so a bad template or a booked slot
+    // doesn't leave a series half made.
+    async fn generate(
+        app_state: &AppState,
+        id: &SeriesId,
+        series: &NewEventSeries,
+        apply_to_future: bool,
+    ) -> Result<(), ServerFnError> {
+        let existing: Vec<Event> = app_state
+            .db
+            .query("SELECT meta::id(id) as id, * FROM event WHERE series=$series;")
+            .bind(("series", id))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+
+        let now = Utc::now();
+        let template = &series.template;
+        let date = |t| local_date(t, series.timezone);
+        let starts = series.recurrence.starts(template.start, series.timezone);
+
+        // What to save, with the existing event to change if there is one
+        let mut planned = Vec::new();
+        for (occurrence, start) in (0..).zip(starts) {
+            if start <= now {
+                continue;
+            }
+
+            let found = existing.iter().find(|e| {
+                let existing = (e.occurrence, e.start);
+                same_occurrence(&series.recurrence, series.timezone, existing, (occurrence, start))
+            });
+            let target = match found {
+                None => None,
+                Some(e) if apply_to_future && e.start > now && !e.archived => Some(e.id.clone()),
+                Some(_) => continue,
+            };
+
+            let instance = NewEvent {
+                series: Some(id.clone()),
+                occurrence: Some(occurrence),
+                ..template.shifted(start - template.start)
+            };
+            let not_saved = |e| Fail::EventNotSaved(date(start), e);
+            event::validate(&instance).map_err(not_saved)?;
+            if let Some(target) = &target {
+                event::check_booked(app_state, target, &instance).await.map_err(not_saved)?;
+            }
+            planned.push((start, target, instance));
+        }
+
+        let (mut created, mut updated) = (0, 0);
+        for (start, target, instance) in planned {
+            let saved = match target {
+                None => event::new_event(instance).await.map(|_| created += 1),
+                Some(e) => event::update_event(e, instance).await.map(|_| updated += 1),
+            };
+            saved.map_err(|e| Fail::EventNotSaved(date(start), e))?;
+        }
+
+        info!("series {}: created {} events, updated {}", id, created, updated);
+        Ok(())
+    }
+
+    pub async fn create(series: NewEventSeries) -> Result<SeriesId, ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        validate(&series)?;
+
+        let record: Option<surreal::Record> = app_state
+            .db
+            .create(EventSeries::TABLE)
+            .content(&series)
+            .await
+            .map_err(Fail::DbError)?
+            .pop();
+        let record = record.ok_or(Fail::NotCreated)?;
+
+        let changes = audit::diff(None, Some(&series));
+        audit::record(&app_state.db, "create_series", &record.id, changes).await;
+
+        let id = SeriesId::from(record.id);
+        generate(&app_state, &id, &series, false).await?;
+        Ok(id)
+    }
+
+    pub async fn update(
+        id: SeriesId,
+        series: NewEventSeries,
+        apply_to_future: bool,
+    ) -> Result<(), ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        validate(&series)?;
+
+        let before = get(id.clone()).await?;
+        app_state
+            .db
+            .query("UPDATE $series CONTENT $content;")
+            .bind(("series", Thing::from(&id)))
+            .bind(("content", &series))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let before = NewEventSeries {
+            template: before.template,
+            recurrence: before.recurrence,
+            timezone: before.timezone,
+        };
+        let changes = audit::diff(Some(&before), Some(&series));
+        audit::record(&app_state.db, "update_series", &Thing::from(&id), changes).await;
+
+        generate(&app_state, &id, &series, apply_to_future).await
+    }
+
+    pub async fn list() -> Result<Vec<EventSeries>, ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let series: Vec<EventSeries> = app_state
+            .db
+            .query("SELECT meta::id(id) as id, * FROM type::table($table);")
+            .bind(("table", EventSeries::TABLE))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        Ok(series)
+    }
+
+    pub async fn get(id: SeriesId) -> Result<EventSeries, ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let series: Option<EventSeries> = app_state
+            .db
+            .query("SELECT meta::id(id) as id, * FROM ONLY $series;")
+            .bind(("series", Thing::from(&id)))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        Ok(series.ok_or(Fail::NotFound(id))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::London;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn weekly_keeps_the_local_time_across_the_clocks_changing() {
+        // 19:00 in London, before and after the clocks go forward on 31 March
+        let starts = Recurrence::Weekly { count: 3 }.starts(utc(2024, 3, 21, 19), London);
+        assert_eq!(starts, [utc(2024, 3, 21, 19), utc(2024, 3, 28, 19), utc(2024, 4, 4, 18)]);
+    }
+
+    #[test]
+    fn monthly_keeps_to_the_day_of_the_month() {
+        let starts = Recurrence::Monthly { count: 3 }.starts(utc(2024, 1, 15, 19), London);
+        assert_eq!(starts, [utc(2024, 1, 15, 19), utc(2024, 2, 15, 19), utc(2024, 3, 15, 19)]);
+
+        // Short months get their last day
+        let starts = Recurrence::Monthly { count: 2 }.starts(utc(2024, 1, 31, 19), London);
+        assert_eq!(starts, [utc(2024, 1, 31, 19), utc(2024, 2, 29, 19)]);
+    }
+
+    #[test]
+    fn dates_are_sorted_without_repeats() {
+        let dates = vec![utc(2024, 5, 2, 19), utc(2024, 5, 1, 19), utc(2024, 5, 2, 19)];
+        let starts = Recurrence::Dates(dates).starts(utc(2024, 1, 1, 0), London);
+        assert_eq!(starts, [utc(2024, 5, 1, 19), utc(2024, 5, 2, 19)]);
+    }
+
+    #[test]
+    fn huge_counts_stop_at_the_most_a_series_can_have() {
+        let recurrence = Recurrence::Weekly { count: u32::MAX };
+        assert_eq!(recurrence.count(), u32::MAX as usize);
+        assert_eq!(recurrence.starts(utc(2024, 1, 1, 19), London).len(), MAX_EVENTS);
+    }
+
+    // Which of `existing`, as (occurrence, start), each start in the series is matched with
+    fn matched(
+        recurrence: &Recurrence,
+        existing: &[(Option<u32>, DateTime<Utc>)],
+    ) -> Vec<Option<usize>> {
+        let first = utc(2024, 5, 1, 19);
+        (0..)
+            .zip(recurrence.starts(first, London))
+            .map(|wanted| {
+                existing
+                    .iter()
+                    .position(|e| backend::same_occurrence(recurrence, London, *e, wanted))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adding_an_earlier_date_leaves_the_others_alone() {
+        let existing = [(Some(0), utc(2024, 5, 8, 19)), (Some(1), utc(2024, 5, 15, 19))];
+        let dates = vec![utc(2024, 5, 8, 19), utc(2024, 5, 1, 19), utc(2024, 5, 15, 19)];
+        assert_eq!(matched(&Recurrence::Dates(dates), &existing), [None, Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn weekly_events_keep_their_place_when_the_template_moves() {
+        let existing = [(Some(0), utc(2024, 5, 1, 18)), (Some(1), utc(2024, 5, 8, 18))];
+        let recurrence = Recurrence::Weekly { count: 3 };
+        assert_eq!(matched(&recurrence, &existing), [Some(0), Some(1), None]);
+
+        // From before occurrences were kept
+        let existing = [(None, utc(2024, 5, 8, 18))];
+        assert_eq!(matched(&recurrence, &existing), [None, Some(0), None]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::config::{UploadStorage, Uploads};
+
+// Somewhere to keep uploaded files. Keys are made up by the server, never taken from whoever
+// uploaded the file.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    // None when nothing has been stored under the key
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    // Every key, with when it was stored
+    async fn list(&self) -> Result<Vec<(String, DateTime<Utc>)>>;
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+pub fn from_config(config: &Uploads) -> Arc<dyn Storage> {
+    match &config.storage {
+        UploadStorage::Local { dir } => Arc::new(LocalStorage {
+            dir: PathBuf::from(dir),
+        }),
+    }
+}
+
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    // Keys are a single file name, so nothing outside the directory can be reached
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("bad key '{}'", key)));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(path, data).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<(String, DateTime<Utc>)>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            // Nothing has been uploaded yet
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if let (true, Some(key)) = (metadata.is_file(), entry.file_name().to_str()) {
+                keys.push((key.to_string(), metadata.modified()?.into()));
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> LocalStorage {
+        let dir = std::env::temp_dir().join(format!("happenings-{:x}", rand::random::<u64>()));
+        LocalStorage { dir }
+    }
+
+    #[tokio::test]
+    async fn lists_and_deletes_what_was_put() {
+        let storage = storage();
+        assert!(storage.list().await.unwrap().is_empty());
+
+        storage.put("a.png", b"a").await.unwrap();
+        storage.put("b.png", b"b").await.unwrap();
+        let listed = storage.list().await.unwrap();
+        let mut keys: Vec<String> = listed.into_iter().map(|(key, _)| key).collect();
+        keys.sort();
+        assert_eq!(keys, ["a.png", "b.png"]);
+
+        storage.delete("a.png").await.unwrap();
+        storage.delete("a.png").await.unwrap();
+        assert_eq!(storage.get("a.png").await.unwrap(), None);
+        assert_eq!(storage.get("b.png").await.unwrap(), Some(b"b".to_vec()));
+        tokio::fs::remove_dir_all(&storage.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_outside_the_directory() {
+        let storage = storage();
+        for key in ["", ".hidden", "../escape", "a/b", "a\\b"] {
+            assert!(storage.put(key, b"x").await.is_err(), "{}", key);
+            assert!(storage.delete(key).await.is_err(), "{}", key);
+        }
+    }
+}
@@ -7,16 +7,144 @@ use crate::person::Person;
 use crate::role::RoleId;
 use crate::schema::Schema;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Credentials {
+// All the ways a person can sign in: a password and/or any number of OAuth provider accounts,
+// optionally with an authenticator app as a second factor.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "StoredCredentials")]
+pub struct Credentials {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<PasswordHash>,
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<Totp>,
+    // A secret shown for enrolment that hasn't been confirmed with a code yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_totp: Option<String>,
+}
+
+// `hash` is an Argon2id PHC string. Accounts created before we moved to Argon2 also have a
+// `salt`, and their `hash` is sha256(salt + password); these are upgraded on next sign in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordHash {
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+// An account with an OAuth provider, `subject` is its stable id for the account (OIDC `sub`)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+}
+
+// `secret` is base32, as shown to authenticator apps. `last_step` is the time step of the last
+// code accepted, so a code can't be used twice. Only hashes of the recovery codes are kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Totp {
+    pub secret: String,
+    #[serde(default)]
+    pub last_step: u64,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
+impl Credentials {
+    pub fn password(hash: String) -> Self {
+        Self {
+            password: Some(PasswordHash { hash, salt: None }),
+            ..Default::default()
+        }
+    }
+
+    pub fn identity(identity: Identity) -> Self {
+        Self {
+            identities: vec![identity],
+            ..Default::default()
+        }
+    }
+
+    pub fn login_methods(&self) -> usize {
+        self.password.iter().count() + self.identities.len()
+    }
+}
+
+// People used to have either the string "OAuth" or {"Password": {..}}. We don't know the
+// provider account of the former, it gets linked by email address next time they sign in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredCredentials {
+    Legacy(LegacyCredentials),
+    Current {
+        #[serde(default)]
+        password: Option<PasswordHash>,
+        #[serde(default)]
+        identities: Vec<Identity>,
+        #[serde(default)]
+        totp: Option<Totp>,
+        #[serde(default)]
+        pending_totp: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+enum LegacyCredentials {
     OAuth,
-    Password { hash: String, salt: String },
+    Password {
+        hash: String,
+        salt: Option<String>,
+    },
+}
+
+impl From<StoredCredentials> for Credentials {
+    fn from(stored: StoredCredentials) -> Self {
+        match stored {
+            StoredCredentials::Legacy(LegacyCredentials::OAuth) => Self::default(),
+            StoredCredentials::Legacy(LegacyCredentials::Password { hash, salt }) => Self {
+                password: Some(PasswordHash { hash, salt }),
+                ..Default::default()
+            },
+            StoredCredentials::Current {
+                password,
+                identities,
+                totp,
+                pending_totp,
+            } => Self {
+                password,
+                identities,
+                totp,
+                pending_totp,
+            },
+        }
+    }
+}
+
+// What we tell the browser about someone's credentials, without the password hash
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct LoginMethods {
+    pub password: bool,
+    pub identities: Vec<Identity>,
+    pub totp: bool,
+    pub recovery_codes_left: usize,
+}
+
+impl From<&Credentials> for LoginMethods {
+    fn from(credentials: &Credentials) -> Self {
+        Self {
+            password: credentials.password.is_some(),
+            identities: credentials.identities.clone(),
+            totp: credentials.totp.is_some(),
+            recovery_codes_left: credentials.totp.as_ref().map_or(0, |t| t.recovery_codes.len()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub person: Person,
-    pub credentials: Credentials,
+    pub login_methods: LoginMethods,
     pub roles: Vec<RoleId>,
 }
 
@@ -39,7 +167,7 @@ impl From<DbUser> for User {
     fn from(item: DbUser) -> Self {
         Self {
             person: item.person.into(),
-            credentials: item.credentials,
+            login_methods: (&item.credentials).into(),
             roles: item.roles.into_iter().map(|r| r.into()).collect(),
         }
     }
@@ -53,16 +181,93 @@ pub struct NewDbUser {
     pub credentials: Credentials,
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod db {
+    use super::*;
+    use crate::surreal;
+    use surrealdb::engine::any::Any;
+    use surrealdb::opt::PatchOp;
+    use surrealdb::sql::Thing;
+    use surrealdb::Surreal;
+
+    pub async fn find_by_email(
+        db: &Surreal<Any>,
+        email: &str,
+    ) -> Result<Option<DbUser>, surrealdb::Error> {
+        let mut users: Vec<DbUser> = db
+            .query(format!(
+                "SELECT {} FROM {} WHERE email=$email;",
+                User::SELECT,
+                User::TABLE
+            ))
+            .bind(("email", email))
+            .await?
+            .take(0)?;
+        Ok(users.pop())
+    }
+
+    pub async fn find_by_id(
+        db: &Surreal<Any>,
+        person: &Thing,
+    ) -> Result<Option<DbUser>, surrealdb::Error> {
+        let mut users: Vec<DbUser> = db
+            .query(format!(
+                "SELECT {} FROM {} WHERE id=$id;",
+                User::SELECT,
+                User::TABLE
+            ))
+            .bind(("id", person))
+            .await?
+            .take(0)?;
+        Ok(users.pop())
+    }
+
+    pub async fn find_by_identity(
+        db: &Surreal<Any>,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<DbUser>, surrealdb::Error> {
+        let mut users: Vec<DbUser> = db
+            .query(format!(
+                "SELECT {} FROM {} \
+                 WHERE count(credentials.identities[\
+                     WHERE provider=$provider AND subject=$subject]) > 0;",
+                User::SELECT,
+                User::TABLE
+            ))
+            .bind(("provider", provider))
+            .bind(("subject", subject))
+            .await?
+            .take(0)?;
+        Ok(users.pop())
+    }
+
+    // Always writes the whole thing, so older records end up in the current shape
+    pub async fn save_credentials(
+        db: &Surreal<Any>,
+        person: &Thing,
+        credentials: &Credentials,
+    ) -> Result<(), surrealdb::Error> {
+        let _: Option<surreal::Record> = db
+            .update(person.clone())
+            .patch(PatchOp::replace("/credentials", credentials))
+            .await?;
+        Ok(())
+    }
+}
+
 #[server(ListUsers, "/api", "Url", "list_users")]
 pub async fn list_users() -> Result<Vec<User>, ServerFnError> { backend::list_users().await }
 
 #[cfg(not(target_arch = "wasm32"))]
 mod backend {
     use super::*;
+    use crate::auth::authz;
     use crate::AppState;
     use leptos::use_context;
 
     pub async fn list_users() -> Result<Vec<User>, ServerFnError> {
+        authz::require_role(RoleId::admin())?;
         let app_state = use_context::<AppState>().ok_or(ServerFnError::new("No server state"))?;
 
         let people: Vec<DbUser> = app_state
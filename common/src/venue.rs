@@ -0,0 +1,215 @@
+use crate::generic_id::Id;
+use crate::schema::Schema;
+use leptos::server_fn::codec::Json;
+use leptos::ServerFnError;
+use macros::generate_new;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+pub type VenueId = Id<Venue>;
+impl Schema for Venue {
+    const TABLE: &'static str = "venue";
+}
+
+// Somewhere events happen, kept separately so events at the same place can share it
+#[generate_new]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Venue {
+    pub id: VenueId,
+    pub name: String,
+    pub address: String,
+    pub postcode: String,
+    pub latitude: Option<Decimal>,
+    pub longitude: Option<Decimal>,
+    pub accessibility: String,
+    pub parking: String,
+}
+
+impl From<Venue> for NewVenue {
+    fn from(v: Venue) -> Self {
+        NewVenue {
+            name: v.name,
+            address: v.address,
+            postcode: v.postcode,
+            latitude: v.latitude,
+            longitude: v.longitude,
+            accessibility: v.accessibility,
+            parking: v.parking,
+        }
+    }
+}
+
+impl Venue {
+    pub fn map_url(&self) -> Option<String> {
+        let (latitude, longitude) = (self.latitude?, self.longitude?);
+        let query = format!("{},{}", latitude, longitude);
+        Some(format!("https://www.google.com/maps/search/?api=1&query={}", query))
+    }
+}
+
+#[leptos::server(ListVenues, "/api", "Url", "list_venues")]
+pub async fn list_venues() -> Result<Vec<Venue>, ServerFnError> { backend::list().await }
+
+#[leptos::server(GetVenue, "/api", "Url", "get_venue")]
+pub async fn get_venue(id: VenueId) -> Result<Venue, ServerFnError> { backend::get(id).await }
+
+// Saves a new venue when `id` is None
+#[leptos::server(name=SaveVenue, prefix="/api", endpoint="save_venue", input = Json, output = Json)]
+pub async fn save_venue(id: Option<VenueId>, venue: NewVenue) -> Result<VenueId, ServerFnError> {
+    backend::save(id, venue).await
+}
+
+// Venues that events are at can't be deleted
+#[leptos::server(DeleteVenue, "/api", "Url", "delete_venue")]
+pub async fn delete_venue(id: VenueId) -> Result<(), ServerFnError> { backend::delete(id).await }
+
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+    use super::*;
+    use crate::audit;
+    use crate::auth::authz;
+    use crate::role::RoleId;
+    use crate::{surreal, AppState};
+    use leptos::use_context;
+    use leptos::ServerFnError::ServerError;
+    use surrealdb::sql::Thing;
+
+    enum Fail {
+        NoServerState,
+        DbError(surrealdb::Error),
+        NotFound(VenueId),
+        NotCreated,
+        Invalid(&'static str),
+        InUse(usize),
+    }
+
+    impl From<Fail> for ServerFnError {
+        fn from(fail: Fail) -> Self {
+            let msg = match fail {
+                Fail::NoServerState => "no server state".to_string(),
+                Fail::DbError(e) => format!("database error: {:?}", e),
+                Fail::NotFound(id) => format!("no venue with id '{}'", id),
+                Fail::NotCreated => "failed to create new venue".to_string(),
+                Fail::Invalid(msg) => msg.to_string(),
+                Fail::InUse(events) => format!("the venue is used by {} events", events),
+            };
+            ServerError(msg)
+        }
+    }
+
+    fn validate(venue: &NewVenue) -> Result<(), ServerFnError> {
+        if venue.name.trim().is_empty() {
+            return Err(Fail::Invalid("the venue needs a name").into());
+        }
+        match (venue.latitude, venue.longitude) {
+            (Some(latitude), Some(longitude)) => {
+                let on_map = latitude.abs() <= Decimal::from(90)
+                    && longitude.abs() <= Decimal::from(180);
+                if !on_map {
+                    return Err(Fail::Invalid("those coordinates aren't on the map").into());
+                }
+            }
+            (None, None) => {}
+            _ => {
+                let msg = "give both the latitude and longitude, or neither";
+                return Err(Fail::Invalid(msg).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn list() -> Result<Vec<Venue>, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let venues: Vec<Venue> = app_state
+            .db
+            .query("SELECT meta::id(id) as id, * FROM type::table($table) ORDER BY name;")
+            .bind(("table", Venue::TABLE))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        Ok(venues)
+    }
+
+    pub async fn get(id: VenueId) -> Result<Venue, ServerFnError> {
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let venue: Option<Venue> = app_state
+            .db
+            .query("SELECT meta::id(id) as id, * FROM ONLY $venue;")
+            .bind(("venue", Thing::from(&id)))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        Ok(venue.ok_or(Fail::NotFound(id))?)
+    }
+
+    pub async fn save(id: Option<VenueId>, venue: NewVenue) -> Result<VenueId, ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+        validate(&venue)?;
+
+        let Some(id) = id else {
+            let record: Option<surreal::Record> = app_state
+                .db
+                .create(Venue::TABLE)
+                .content(&venue)
+                .await
+                .map_err(Fail::DbError)?
+                .pop();
+            let record = record.ok_or(Fail::NotCreated)?;
+
+            let changes = audit::diff(None, Some(&venue));
+            audit::record(&app_state.db, "create_venue", &record.id, changes).await;
+            return Ok(record.id.into());
+        };
+
+        let before: NewVenue = get(id.clone()).await?.into();
+        app_state
+            .db
+            .query("UPDATE $venue CONTENT $content;")
+            .bind(("venue", Thing::from(&id)))
+            .bind(("content", &venue))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let changes = audit::diff(Some(&before), Some(&venue));
+        audit::record(&app_state.db, "update_venue", &Thing::from(&id), changes).await;
+        Ok(id)
+    }
+
+    pub async fn delete(id: VenueId) -> Result<(), ServerFnError> {
+        authz::require_role(RoleId::organiser())?;
+        let app_state = use_context::<AppState>().ok_or(Fail::NoServerState)?;
+
+        let events: Vec<surreal::Record> = app_state
+            .db
+            .query("SELECT id FROM event WHERE venue=$venue;")
+            .bind(("venue", &id))
+            .await
+            .map_err(Fail::DbError)?
+            .take(0)
+            .map_err(Fail::DbError)?;
+        if !events.is_empty() {
+            return Err(Fail::InUse(events.len()).into());
+        }
+
+        let before = get(id.clone()).await?;
+        app_state
+            .db
+            .query("DELETE $venue;")
+            .bind(("venue", Thing::from(&id)))
+            .await
+            .map_err(Fail::DbError)?
+            .check()
+            .map_err(Fail::DbError)?;
+
+        let changes = audit::diff(Some(&before), None);
+        audit::record(&app_state.db, "delete_venue", &Thing::from(&id), changes).await;
+        Ok(())
+    }
+}
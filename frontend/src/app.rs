@@ -4,11 +4,20 @@ use tracing::warn;
 
 use super::navbar::NavBar;
 use super::not_found::NotFound;
+use crate::audit::Audit;
 use crate::book_event::{Booking, BookingRoot, CheckPayment, EventProvider, GeneratePaymentLink, ListBookings, NewBooking};
+use crate::event_editor::{CreateEvent, EditEvent};
 use crate::events::Events;
-use crate::sign_in::{OAuthReturn, SignIn};
+use crate::janitor::Janitor;
+use crate::lockouts::Lockouts;
+use crate::profile::Profile;
+use crate::series::{CreateSeries, EditSeries, SeriesList};
+use crate::sessions::Sessions;
+use crate::sign_in::{MagicLinkReturn, OAuthReturn, ResetPassword, SignIn, VerifyEmail};
 use crate::users::Users;
+use crate::venues::Venues;
 use common::person::{get_logged_in_person, Person};
+use common::role::{get_logged_in_roles, RoleId};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum SignInStatus {
@@ -16,84 +25,86 @@ pub enum SignInStatus {
     Welcome,
     Password(String),
     CreateUser(String),
+    ForgotPassword(String),
+    MagicLink(String),
+    SecondFactor,
 }
 #[derive(Copy, Clone)]
 pub struct SignInSignal(pub RwSignal<SignInStatus>);
 // #[derive(Copy, Clone)]
 pub type MaybePersonSignal = Signal<Option<Person>>;
+pub type RolesSignal = Signal<Vec<RoleId>>;
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum SessionID {
-    NotSet,
-    Set(String),
+// For when something changes the logged in person on the server, e.g. verifying their email
+#[derive(Copy, Clone)]
+pub struct PersonResource(pub Resource<usize, Option<Person>>);
+
+// Whether the signed in user holds a role. This only decides what to show, the server functions
+// do their own checks.
+pub fn use_has_role(role: RoleId) -> Signal<bool> {
+    let roles = use_context::<RolesSignal>().unwrap();
+    Signal::derive(move || roles().iter().any(|r| r.grants(&role)))
 }
 
-impl SessionID {
-    pub fn store_cookie(&self) {
-        // TODO: Decide properly if we're using local storage or cookies
-        #[cfg(target_arch = "wasm32")]
-        match self {
-            Self::Set(id) => {
-                wasm_cookies::set(
-                    "session_id",
-                    id.as_ref(),
-                    &wasm_cookies::CookieOptions {
-                        path: Some("/"),
-                        ..Default::default()
-                    },
-                )
-            }
-            Self::NotSet => wasm_cookies::delete("session_id"),
-        }
-    }
+// Bumped whenever someone signs in or out. The session cookie is HttpOnly so we can't look at
+// it from here, instead anything that depends on who is signed in refetches when this changes.
+#[derive(Copy, Clone)]
+pub struct SessionChanged(RwSignal<usize>);
 
-    pub fn from_cookie() -> Self {
-        // TODO: Decide properly if we're using local storage or cookies
-
-        #[cfg(target_arch = "wasm32")]
-        match wasm_cookies::get("session_id") {
-            None => Self::NotSet,
-            Some(Err(_)) => Self::NotSet,
-            Some(Ok(id)) => Self::Set(id),
-        }
-        #[cfg(not(target_arch = "wasm32"))]
-        Self::NotSet
-    }
+impl SessionChanged {
+    pub fn notify(&self) { self.0.update(|n| *n += 1) }
+    pub fn get(&self) -> usize { self.0.get() }
 }
 
 #[component]
 pub fn App() -> impl IntoView {
     provide_context(SignInSignal(create_rw_signal(SignInStatus::NotVisible)));
 
-    let (session_id, set_session_id) = create_signal(SessionID::from_cookie());
-    create_effect(move |_| session_id().store_cookie());
-
-    provide_context(set_session_id);
+    let session_changed = SessionChanged(create_rw_signal(0));
+    provide_context(session_changed);
 
-    let user_info = create_resource(session_id, |sid| async move {
-        match sid {
-            SessionID::Set(_) => match get_logged_in_person().await {
+    let user_info = create_resource(
+        move || session_changed.get(),
+        |_| async move {
+            match get_logged_in_person().await {
                 Ok(p) => Some(p),
                 Err(e) => {
                     warn!("Error getting logged in person: {:?}", e);
                     None
                 }
-            },
-            SessionID::NotSet => None,
-        }
-    });
+            }
+        },
+    );
 
+    provide_context(PersonResource(user_info));
     let maybe_person = Signal::derive(move || user_info.get().flatten());
     provide_context::<MaybePersonSignal>(maybe_person);
+
+    let roles = create_resource(
+        move || session_changed.get(),
+        |_| async move { get_logged_in_roles().await.unwrap_or_default() },
+    );
+    provide_context::<RolesSignal>(Signal::derive(move || roles.get().unwrap_or_default()));
     view! {
       <Router>
         <Routes>
           <Route path="/" view=|| with_navbar(Events())/>
           <Route path="/users" view=|| with_navbar(Users())/>
+          <Route path="/lockouts" view=|| with_navbar(Lockouts())/>
+          <Route path="/janitor" view=|| with_navbar(Janitor())/>
+          <Route path="/audit" view=|| with_navbar(Audit())/>
+          <Route path="/profile" view=|| with_navbar(Profile())/>
+          <Route path="/sessions" view=|| with_navbar(Sessions())/>
           <Route path="/events" view=|| with_navbar(Events())/>
+          <Route path="/events/new" view=|| with_navbar(CreateEvent())/>
+          <Route path="/series" view=|| with_navbar(SeriesList())/>
+          <Route path="/venues" view=|| with_navbar(Venues())/>
+          <Route path="/series/new" view=|| with_navbar(CreateSeries())/>
+          <Route path="/series/:id/edit" view=|| with_navbar(EditSeries())/>
           <Route path="/events/:id" view=|| with_navbar(EventProvider())>
             <Route path="bookings" view=ListBookings/>
             <Route path="book" view=NewBooking/>
+            <Route path="edit" view=EditEvent/>
           </Route>
 
           <Route path="/booking" view=|| with_navbar(BookingRoot())>
@@ -105,6 +116,9 @@ pub fn App() -> impl IntoView {
           </Route>
 
           <Route path="/oauth_return" view=OAuthReturn/>
+          <Route path="/reset_password" view=|| with_navbar(ResetPassword())/>
+          <Route path="/verify_email" view=|| with_navbar(VerifyEmail())/>
+          <Route path="/magic_link" view=|| with_navbar(MagicLinkReturn())/>
           <Route path="/*any" view=NotFound/>
         </Routes>
       </Router>
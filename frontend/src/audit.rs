@@ -0,0 +1,153 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use common::audit::{list_audit, AuditEntry, AuditFilter, Change};
+use leptos::*;
+
+use crate::book_event::require_login;
+
+fn local(t: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(t)
+        .format("%d %b %Y %-I:%M:%S %p")
+        .to_string()
+}
+
+fn non_empty(s: String) -> Option<String> {
+    let s = s.trim().to_string();
+    (!s.is_empty()).then_some(s)
+}
+
+// Midnight at the start of a day picked in a date input, `days` later
+fn start_of(date: &str, days: u64) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()? + chrono::Days::new(days);
+    let midnight = Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest()?;
+    Some(midnight.into())
+}
+
+fn change_item(c: Change) -> impl IntoView {
+    let before = c.before.unwrap_or("-".to_string());
+    let after = c.after.unwrap_or("-".to_string());
+    view! {
+      <li>
+        <strong>{c.field}</strong>
+        ": "
+        <span class="has-text-danger">{before}</span>
+        " → "
+        <span class="has-text-success">{after}</span>
+      </li>
+    }
+}
+
+// Who did what, newest first
+#[component]
+pub fn Audit() -> impl IntoView {
+    require_login();
+
+    let (action, set_action) = create_signal("".to_string());
+    let (actor, set_actor) = create_signal("".to_string());
+    let (target, set_target) = create_signal("".to_string());
+    let (since, set_since) = create_signal("".to_string());
+    let (until, set_until) = create_signal("".to_string());
+
+    let filter = create_rw_signal(AuditFilter::default());
+    let entries = create_resource(filter, list_audit);
+
+    let apply = move || {
+        filter.set(AuditFilter {
+            action: non_empty(action()),
+            actor: non_empty(actor()),
+            target: non_empty(target()),
+            since: start_of(&since(), 0),
+            // Up to the end of the day picked
+            until: start_of(&until(), 1),
+        })
+    };
+
+    let entry_row = move |e: AuditEntry| {
+        view! {
+          <tr>
+            <td>{local(e.at)}</td>
+            <td>{e.action}</td>
+            <td>{e.actor_email.unwrap_or("-".to_string())}</td>
+            <td>{e.target}</td>
+            <td>
+              <ul class="is-size-7">{e.changes.into_iter().map(change_item).collect_view()}</ul>
+            </td>
+            <td>{e.address}</td>
+          </tr>
+        }
+    };
+
+    let text_filter = move |placeholder: &'static str, set: WriteSignal<String>| {
+        view! {
+          <div class="control">
+            <input
+              class="input"
+              type="text"
+              placeholder=placeholder
+              on:change=move |e| set(event_target_value(&e))
+            />
+          </div>
+        }
+    };
+
+    let date_filter = move |set: WriteSignal<String>| {
+        view! {
+          <div class="control">
+            <input class="input" type="date" on:change=move |e| set(event_target_value(&e))/>
+          </div>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Audit log</h1>
+          <form on:submit=move |e| {
+              e.prevent_default();
+              apply()
+          }>
+            <div class="field is-grouped is-grouped-multiline">
+              {text_filter("Action, e.g. grant_role", set_action)}
+              {text_filter("Who (email)", set_actor)}
+              {text_filter("Record", set_target)}
+              {date_filter(set_since)}
+              {date_filter(set_until)}
+              <div class="control">
+                <button class="button is-primary" type="submit">
+                  Filter
+                </button>
+              </div>
+            </div>
+          </form>
+          <table class="table is-fullwidth is-striped">
+            <thead>
+              <tr>
+                <th>When</th>
+                <th>Action</th>
+                <th>Who</th>
+                <th>Record</th>
+                <th>Changes</th>
+                <th>Address</th>
+              </tr>
+            </thead>
+            <tbody>
+              {move || match entries.get() {
+                  Some(Ok(entries)) => entries.into_iter().map(entry_row).collect_view(),
+                  Some(Err(e)) => {
+                      view! {
+                        <tr>
+                          <td colspan="6" class="has-text-danger">
+                            {format!("{:?}", e)}
+                          </td>
+                        </tr>
+                      }
+                          .into_view()
+                  }
+                  None => ().into_view(),
+              }}
+
+            </tbody>
+          </table>
+        </div>
+      </section>
+    }
+}
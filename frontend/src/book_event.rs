@@ -1,13 +1,17 @@
 use crate::app::{MaybePersonSignal, SignInSignal, SignInStatus};
 use crate::components::controls::*;
 use crate::components::modal::Modal;
+use crate::event_media::{EventDescription, HeroImage};
 use crate::field::Field;
 use crate::icon_button::{Color, IconButton};
 use crate::reactive_list::{ReactiveList, TrackableList};
 use crate::slot_state_for_ticket;
+use crate::venues::VenueDetails;
 
 use class_list::class_list;
+use common::auth::verify::resend_verification_email;
 use common::booking::{self, get_booking, BookingId, CreateBooking, Status};
+use common::calendar::booking_calendar;
 use common::event::{get_event, get_slot_details, Event, EventId, SlotDetail};
 use common::person::{get_person, Person};
 use common::ticket::Ticket;
@@ -94,11 +98,33 @@ pub fn Booking() -> impl IntoView {
 
 #[component]
 pub fn BookingSummary(#[prop(into)] booking: Signal<booking::Booking>) -> impl IntoView {
-    // TODO: these are now eagerly fetched, don't need to fetch again
-    let event = create_resource(booking, |b| async move { get_event(b.event.id).await });
+    // TODO: the contact is now eagerly fetched, don't need to fetch again
     let contact = create_resource(booking, |b| async move { get_person(b.contact.id).await });
 
-    let event_name = move || event.get().map(|er| er.map(|e| e.name).unwrap_or_default());
+    // The booking's own copy, since the event may be over and no longer listed
+    let event_name = move || booking.with(|b| b.event.name.clone());
+    let venue = move || {
+        booking
+            .with(|b| b.event.venue.clone())
+            .map(|id| view! { <VenueDetails id/> })
+    };
+
+    // Downloaded straight from the page, it's only small
+    let calendar = create_resource(move || booking.get().id, booking_calendar);
+    let calendar_link = move || {
+        calendar.get().and_then(Result::ok).map(|ics| {
+            let ics = String::from(web_sys::js_sys::encode_uri_component(&ics));
+            view! {
+              <a
+                class="button block"
+                href=format!("data:text/calendar;charset=utf-8,{}", ics)
+                download="booking.ics"
+              >
+                Add to Calendar
+              </a>
+            }
+        })
+    };
 
     let full_name = Signal::derive(move || {
         contact
@@ -151,6 +177,8 @@ pub fn BookingSummary(#[prop(into)] booking: Signal<booking::Booking>) -> impl I
 
             {ticket_table_data}
           </table>
+          {calendar_link}
+          {venue}
 
         </Suspense>
       </div>
@@ -416,6 +444,8 @@ pub fn NewBookingForPerson(#[prop(into)] person: Signal<Person>) -> impl IntoVie
 
     let event_name = event().name.clone();
     let event_tagline = event().tagline.clone();
+    let venue = event().venue.map(|id| view! { <VenueDetails id/> });
+    let hero = event().hero_image.map(|key| view! { <HeroImage key/> });
 
     let default_tt = event().default_ticket_type.clone();
     let tickets = vec![Ticket::new(default_tt)];
@@ -473,13 +503,35 @@ pub fn NewBookingForPerson(#[prop(into)] person: Signal<Person>) -> impl IntoVie
 
     let disabled = Signal::derive(move || pending() | !validation_errors().is_empty());
 
+    let resend = create_action(|_: &()| async move { resend_verification_email().await });
+    let unverified_notice = move || {
+        (!person().email_verified).then(|| {
+            let resend_status = move || match resend.value().get() {
+                None => "".into_view(),
+                Some(Ok(_)) => " Sent, check your inbox.".into_view(),
+                Some(Err(e)) => format!(" Couldn't send it: {}", e).into_view(),
+            };
+            view! {
+              <div class="notification is-warning">
+                "You haven't verified your email address yet. Please follow the link we emailed you before booking. "
+                <a on:click=move |_| resend.dispatch(())>Send it again</a>
+                {resend_status}
+              </div>
+            }
+        })
+    };
+
     view! {
       <section class="section">
         <input type="hidden" name="event" value=event().id/>
         <input type="hidden" name="contact" value=person().id/>
         <div class="container">
+          {hero}
           <h1 class="title">{event_name}</h1>
           <p class="subtitle">{event_tagline}</p>
+          <EventDescription event=event()/>
+          {venue}
+          {unverified_notice}
 
           <div class="box">
             <Field label=|| "Booking Contact">
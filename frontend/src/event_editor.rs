@@ -0,0 +1,506 @@
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use common::event::{
+    archive_event, clone_event, new_event, update_event, EventId, EventStatus, NewEvent, Slot,
+    Slots,
+};
+use common::ticket::TicketType;
+use common::venue::{list_venues, Venue};
+use leptos::*;
+use leptos_router::{use_navigate, NavigateOptions};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+use crate::book_event::{require_login, ContextEvent};
+use crate::event_media::{DescriptionEditor, ImagesEditor};
+use crate::sign_in::ErrorNotification;
+
+// What <input type="datetime-local"> reads and writes
+const DATE_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M";
+
+pub fn to_input(t: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(t)
+        .format(DATE_TIME_FORMAT)
+        .to_string()
+}
+
+pub fn from_input(s: &str) -> Option<DateTime<Utc>> {
+    let naive = NaiveDateTime::parse_from_str(s, DATE_TIME_FORMAT).ok()?;
+    Some(Local.from_local_datetime(&naive).earliest()?.into())
+}
+
+// Blank inputs mean no limit
+fn optional_number(s: &str) -> Option<i64> { s.trim().parse().ok() }
+
+fn blank_ticket_type(name: &str) -> TicketType {
+    TicketType {
+        name: name.to_string(),
+        price: Decimal::ZERO,
+        square_item_id: "".to_string(),
+        square_catalog_version: 0,
+        available: None,
+    }
+}
+
+pub fn blank_event() -> NewEvent {
+    let start = Utc::now() + Duration::days(7);
+    NewEvent {
+        name: "".to_string(),
+        tagline: "".to_string(),
+        description: "".to_string(),
+        hero_image: None,
+        gallery: Vec::new(),
+        venue: None,
+        default_ticket_type: blank_ticket_type("Standard"),
+        additional_ticket_types: Vec::new(),
+        slots: Slots {
+            description: None,
+            list: Vec::new(),
+        },
+        start,
+        end: start + Duration::hours(3),
+        status: EventStatus::Draft,
+        sales_open: None,
+        sales_close: None,
+        series: None,
+        occurrence: None,
+    }
+}
+
+// Ticket type 0 is the default one, the rest are the additional ones
+fn ticket_type(e: &NewEvent, i: usize) -> &TicketType {
+    match i {
+        0 => &e.default_ticket_type,
+        _ => &e.additional_ticket_types[i - 1],
+    }
+}
+
+fn ticket_type_mut(e: &mut NewEvent, i: usize) -> &mut TicketType {
+    match i {
+        0 => &mut e.default_ticket_type,
+        _ => &mut e.additional_ticket_types[i - 1],
+    }
+}
+
+pub fn input(
+    kind: &'static str,
+    placeholder: &'static str,
+    get: impl Fn() -> String + 'static,
+    set: impl Fn(String) + 'static,
+) -> impl IntoView {
+    view! {
+      <div class="control is-expanded">
+        <input
+          class="input"
+          type=kind
+          placeholder=placeholder
+          prop:value=get
+          on:change=move |ev| set(event_target_value(&ev))
+        />
+      </div>
+    }
+}
+
+#[component]
+fn TicketTypeRow(event: RwSignal<NewEvent>, index: usize) -> impl IntoView {
+    // The row may have been removed by the time one of these runs
+    let get = move |f: fn(&TicketType) -> String| {
+        move || {
+            event.with(|e| match index <= e.additional_ticket_types.len() {
+                true => f(ticket_type(e, index)),
+                false => "".to_string(),
+            })
+        }
+    };
+    let set = move |f: fn(&mut TicketType, String)| {
+        move |value: String| event.update(|e| f(ticket_type_mut(e, index), value))
+    };
+
+    // The default ticket type can't go, there has to be at least one
+    let remove = move |_| {
+        if index > 0 {
+            event.update(|e| drop(e.additional_ticket_types.remove(index - 1)))
+        }
+    };
+
+    view! {
+      <div class="field is-grouped">
+        {input("text", "Name", get(|tt| tt.name.clone()), set(|tt, v| tt.name = v))}
+        {input(
+            "number",
+            "Price",
+            get(|tt| tt.price.to_string()),
+            set(|tt, v| tt.price = Decimal::from_str(&v).unwrap_or_default()),
+        )}
+        {input(
+            "number",
+            "How many (blank for no limit)",
+            get(|tt| tt.available.map(|a| a.to_string()).unwrap_or_default()),
+            set(|tt, v| tt.available = optional_number(&v)),
+        )}
+        {input(
+            "text",
+            "Square item id",
+            get(|tt| tt.square_item_id.clone()),
+            set(|tt, v| tt.square_item_id = v),
+        )}
+        {input(
+            "number",
+            "Square catalog version",
+            get(|tt| tt.square_catalog_version.to_string()),
+            set(|tt, v| tt.square_catalog_version = v.trim().parse().unwrap_or_default()),
+        )}
+        <div class="control">
+          <button
+            class="button is-danger is-outlined"
+            type="button"
+            disabled=index == 0
+            on:click=remove
+          >
+            Remove
+          </button>
+        </div>
+      </div>
+    }
+}
+
+#[component]
+fn SlotRow(event: RwSignal<NewEvent>, index: usize) -> impl IntoView {
+    let get = move |f: fn(&Slot) -> String| {
+        move || event.with(|e| e.slots.list.get(index).map(f).unwrap_or_default())
+    };
+    let set = move |f: fn(&mut Slot, String)| {
+        move |value: String| event.update(|e| f(&mut e.slots.list[index], value))
+    };
+
+    let remove = move |_| event.update(|e| drop(e.slots.list.remove(index)));
+
+    view! {
+      <div class="field is-grouped">
+        {input("text", "Name", get(|s| s.name.clone()), set(|s, v| s.name = v))}
+        {input(
+            "number",
+            "Capacity (blank for no limit)",
+            get(|s| s.capacity.map(|c| c.to_string()).unwrap_or_default()),
+            set(|s, v| s.capacity = optional_number(&v)),
+        )}
+        {input(
+            "datetime-local",
+            "",
+            get(|s| s.start.map(to_input).unwrap_or_default()),
+            set(|s, v| s.start = from_input(&v)),
+        )}
+        <div class="control">
+          <button class="button is-danger is-outlined" type="button" on:click=remove>
+            Remove
+          </button>
+        </div>
+      </div>
+    }
+}
+
+// The form for an event or a series template, `save` gets the whole event when submitted
+#[component]
+pub fn EventForm(
+    event: RwSignal<NewEvent>,
+    save: Action<NewEvent, Result<(), String>>,
+    #[prop(into)] button: String,
+) -> impl IntoView {
+    // Only redraw the rows when one is added or removed, not on every keystroke
+    let ticket_type_count = create_memo(move |_| event.with(|e| e.ticket_types().len()));
+    let slot_count = create_memo(move |_| event.with(|e| e.slots.list.len()));
+
+    let add_ticket_type = move |_| {
+        event.update(|e| e.additional_ticket_types.push(blank_ticket_type("")))
+    };
+    let add_slot = move |_| {
+        event.update(|e| {
+            e.slots.list.push(Slot {
+                name: "".to_string(),
+                capacity: None,
+                start: None,
+            })
+        })
+    };
+
+    let text = move |placeholder, get: fn(&NewEvent) -> String, set: fn(&mut NewEvent, String)| {
+        let (get, set) = (move || event.with(get), move |v| event.update(|e| set(e, v)));
+        view! { <div class="field">{input("text", placeholder, get, set)}</div> }
+    };
+
+    let when = move |get: fn(&NewEvent) -> DateTime<Utc>, set: fn(&mut NewEvent, DateTime<Utc>)| {
+        input(
+            "datetime-local",
+            "",
+            move || event.with(|e| to_input(get(e))),
+            move |v| {
+                if let Some(t) = from_input(&v) {
+                    event.update(|e| set(e, t))
+                }
+            },
+        )
+    };
+
+    // Clearing one of these leaves it unset
+    let maybe_when = move |get: fn(&NewEvent) -> Option<DateTime<Utc>>,
+                           set: fn(&mut NewEvent, Option<DateTime<Utc>>)| {
+        input(
+            "datetime-local",
+            "",
+            move || event.with(|e| get(e).map(to_input).unwrap_or_default()),
+            move |v| event.update(|e| set(e, from_input(&v))),
+        )
+    };
+
+    let status_option = move |status: &EventStatus| {
+        let status = *status;
+        view! {
+          <option value=status.to_string() selected=move || event.with(|e| e.status == status)>
+            {status.to_string()}
+          </option>
+        }
+    };
+    let venues = create_resource(|| (), |_| list_venues());
+    let venue_option = move |v: Venue| {
+        let selected = {
+            let id = v.id.clone();
+            move || event.with(|e| e.venue.as_ref() == Some(&id))
+        };
+        view! {
+          <option value=v.id.to_string() selected=selected>
+            {v.name}
+          </option>
+        }
+    };
+    let set_venue = move |v: String| event.update(|e| e.venue = (!v.is_empty()).then(|| v.into()));
+
+    let set_status = move |v: String| {
+        if let Ok(status) = v.parse() {
+            event.update(|e| e.status = status)
+        }
+    };
+
+    view! {
+      <form on:submit=move |e| {
+          e.prevent_default();
+          save.dispatch(event.get_untracked())
+      }>
+        <label class="label">Name</label>
+        {text("Name", |e| e.name.clone(), |e, v| e.name = v)}
+        <label class="label">Tagline</label>
+        {text("Tagline", |e| e.tagline.clone(), |e, v| e.tagline = v)}
+        <DescriptionEditor event/>
+        <ImagesEditor event/>
+        <label class="label">Venue</label>
+        <div class="field">
+          <div class="control">
+            <div class="select">
+              <select on:change=move |e| set_venue(event_target_value(&e))>
+                <option value="" selected=move || event.with(|e| e.venue.is_none())>
+                  "Not set"
+                </option>
+                {move || {
+                    venues
+                        .get()
+                        .and_then(Result::ok)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(venue_option)
+                        .collect_view()
+                }}
+
+              </select>
+            </div>
+          </div>
+        </div>
+        <label class="label">Starts and ends</label>
+        <div class="field is-grouped">
+          {when(|e| e.start, |e, t| e.start = t)} {when(|e| e.end, |e, t| e.end = t)}
+        </div>
+        <label class="label">Status</label>
+        <div class="field">
+          <div class="control">
+            <div class="select">
+              <select on:change=move |e| set_status(event_target_value(&e))>
+                {EventStatus::ALL.iter().map(status_option).collect_view()}
+              </select>
+            </div>
+          </div>
+          <p class="help">
+            "Drafts can only be seen by organisers. Closed events can't be booked."
+          </p>
+        </div>
+        <label class="label">Sales open and close</label>
+        <div class="field is-grouped">
+          {maybe_when(|e| e.sales_open, |e, t| e.sales_open = t)}
+          {maybe_when(|e| e.sales_close, |e, t| e.sales_close = t)}
+        </div>
+        <p class="help mb-3">
+          "Leave these blank to take bookings from when the event is published until it ends."
+        </p>
+
+        <h2 class="subtitle mt-5">Ticket types</h2>
+        {move || {
+            (0..ticket_type_count())
+                .map(|index| view! { <TicketTypeRow event index/> })
+                .collect_view()
+        }}
+
+        <button class="button mb-5" type="button" on:click=add_ticket_type>
+          Add Ticket Type
+        </button>
+
+        <h2 class="subtitle">Slots</h2>
+        {text(
+            "What the slots are for, e.g. Arrival time (optional)",
+            |e| e.slots.description.clone().unwrap_or_default(),
+            |e, v| e.slots.description = Some(v).filter(|v| !v.trim().is_empty()),
+        )}
+        {move || (0..slot_count()).map(|index| view! { <SlotRow event index/> }).collect_view()}
+        <p class="help mb-3">
+          "Give a slot a start time if people in it shouldn't turn up when the event starts."
+        </p>
+
+        <button class="button mb-5" type="button" on:click=add_slot>
+          Add Slot
+        </button>
+
+        <ErrorNotification sig=save.value()/>
+        <div class="buttons">
+          <button class="button is-primary" type="submit">
+            {button}
+          </button>
+        </div>
+      </form>
+    }
+}
+
+#[component]
+pub fn CreateEvent() -> impl IntoView {
+    require_login();
+    let event = create_rw_signal(blank_event());
+    let navigate = use_navigate();
+
+    let save = create_action(move |e: &NewEvent| {
+        let (e, navigate) = (e.clone(), navigate.clone());
+        async move {
+            let id = new_event(e).await.map_err(|e| format!("{:?}", e))?;
+            navigate(&format!("/events/{}/edit", id), NavigateOptions::default());
+            Ok::<(), String>(())
+        }
+    });
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">New event</h1>
+          <EventForm event save button="Create Event"/>
+        </div>
+      </section>
+    }
+}
+
+// Start a new draft from this event, moved on by however many days
+#[component]
+fn CloneForm(id: StoredValue<EventId>) -> impl IntoView {
+    let (days, set_days) = create_signal("364".to_string());
+    let (suffix, set_suffix) = create_signal(" (copy)".to_string());
+    let navigate = use_navigate();
+
+    let clone = create_action(move |_: &()| {
+        let navigate = navigate.clone();
+        async move {
+            let days = days.get_untracked().trim().parse().map_err(|_| "enter a number of days")?;
+            let new_id = clone_event(id.get_value(), days, suffix.get_untracked())
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            navigate(&format!("/events/{}/edit", new_id), NavigateOptions::default());
+            Ok::<(), String>(())
+        }
+    });
+
+    view! {
+      <h2 class="subtitle mt-5">Copy</h2>
+      <p class="block">
+        "Makes a draft with the same ticket types and slots, without any of the bookings. "
+        "364 days later is the same day of the week next year."
+      </p>
+      <div class="field is-grouped">
+        {input("number", "Days later", days, set_days)}
+        {input("text", "Added to the name", suffix, set_suffix)}
+        <div class="control">
+          <button class="button" on:click=move |_| clone.dispatch(())>
+            Copy Event
+          </button>
+        </div>
+      </div>
+      <ErrorNotification sig=clone.value()/>
+    }
+}
+
+#[component]
+pub fn EditEvent() -> impl IntoView {
+    require_login();
+    let current = expect_context::<ContextEvent>().0;
+    let id = store_value(current.with_value(|e| e.id.clone()));
+    let event = create_rw_signal(NewEvent::from(current.get_value()));
+    let archived = create_rw_signal(current.with_value(|e| e.archived));
+
+    let save = create_action(move |e: &NewEvent| {
+        let e = e.clone();
+        async move {
+            update_event(id.get_value(), e).await.map_err(|e| format!("{:?}", e))
+        }
+    });
+
+    let archive = create_action(move |archive: &bool| {
+        let archive = *archive;
+        async move {
+            archive_event(id.get_value(), archive).await.map_err(|e| format!("{:?}", e))?;
+            archived.set(archive);
+            Ok::<(), String>(())
+        }
+    });
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">
+            {move || event.with(|e| e.name.clone())}
+            <Show when=archived>
+              <span class="tag is-warning ml-3">Archived</span>
+            </Show>
+          </h1>
+          <EventForm event save button="Save Changes"/>
+          <Show when=move || matches!(save.value()(), Some(Ok(())))>
+            <div class="notification is-success mt-3">"The event has been saved."</div>
+          </Show>
+
+          <h2 class="subtitle mt-5">Archive</h2>
+          <p class="block">
+            "Archived events are hidden from the event list. Their bookings are kept and they can "
+            "be brought back at any time."
+          </p>
+          <ErrorNotification sig=archive.value()/>
+          <Show
+            when=archived
+            fallback=move || {
+                view! {
+                  <button
+                    class="button is-danger is-outlined"
+                    on:click=move |_| archive.dispatch(true)
+                  >
+                    Archive Event
+                  </button>
+                }
+            }
+          >
+
+            <button class="button" on:click=move |_| archive.dispatch(false)>
+              Restore Event
+            </button>
+          </Show>
+          <CloneForm id/>
+        </div>
+      </section>
+    }
+}
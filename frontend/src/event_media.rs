@@ -0,0 +1,204 @@
+use common::event::{get_event_description, image_url, preview_description, Event, NewEvent};
+use leptos::wasm_bindgen::{JsCast, JsValue};
+use leptos::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{File, HtmlInputElement, RequestInit, Response};
+
+use crate::sign_in::ErrorNotification;
+
+const IMAGE_TYPES: &str = "image/png,image/jpeg,image/gif,image/webp";
+
+fn js_error(e: JsValue) -> String { format!("{:?}", e) }
+
+// The file goes up as the request body, and what comes back is the key it was stored under
+async fn upload(file: File) -> Result<String, String> {
+    let mut init = RequestInit::new();
+    init.method("POST").body(Some(file.as_ref()));
+    let response: Response = JsFuture::from(window().fetch_with_str_and_init("/uploads", &init))
+        .await
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(js_error)?;
+
+    let text = JsFuture::from(response.text().map_err(js_error)?)
+        .await
+        .map_err(js_error)?
+        .as_string()
+        .unwrap_or_default();
+    match response.ok() {
+        true => Ok(text),
+        false => Err(text),
+    }
+}
+
+#[component]
+fn UploadButton(
+    #[prop(into)] label: String,
+    #[prop(into)] uploaded: Callback<String>,
+) -> impl IntoView {
+    let send = create_action(move |file: &File| {
+        let file = file.clone();
+        async move {
+            uploaded(upload(file).await?);
+            Ok::<(), String>(())
+        }
+    });
+
+    let choose = move |e: ev::Event| {
+        let input = event_target::<HtmlInputElement>(&e);
+        if let Some(file) = input.files().and_then(|files| files.get(0)) {
+            send.dispatch(file);
+        }
+        // So choosing the same file again still counts as a change
+        input.set_value("");
+    };
+    let label = move || match send.pending()() {
+        true => "Uploading..".to_string(),
+        false => label.clone(),
+    };
+
+    view! {
+      <div class="field">
+        <div class="file">
+          <label class="file-label">
+            <input class="file-input" type="file" accept=IMAGE_TYPES on:change=choose/>
+            <span class="file-cta">
+              <span class="file-label">{label}</span>
+            </span>
+          </label>
+        </div>
+      </div>
+      <ErrorNotification sig=send.value()/>
+    }
+}
+
+// Markdown for the description, with a preview of how it'll look once the server has
+// rendered it
+#[component]
+pub fn DescriptionEditor(event: RwSignal<NewEvent>) -> impl IntoView {
+    let preview = create_action(move |_: &()| {
+        let markdown = event.with_untracked(|e| e.description.clone());
+        async move { preview_description(markdown).await.map_err(|e| format!("{:?}", e)) }
+    });
+    let html = move || preview.value()().and_then(Result::ok);
+    let error = Signal::derive(move || preview.value()().map(|r| r.map(drop)));
+
+    view! {
+      <label class="label">Description</label>
+      <div class="field">
+        <div class="control">
+          <textarea
+            class="textarea"
+            rows="8"
+            placeholder="Everything people need to know before they book, in Markdown"
+            prop:value=move || event.with(|e| e.description.clone())
+            on:change=move |e| event.update(|n| n.description = event_target_value(&e))
+          ></textarea>
+        </div>
+      </div>
+      <button class="button mb-3" type="button" on:click=move |_| preview.dispatch(())>
+        Preview
+      </button>
+      <ErrorNotification sig=error/>
+      {move || html().map(|html| view! { <div class="box content" inner_html=html></div> })}
+    }
+}
+
+#[component]
+pub fn ImagesEditor(event: RwSignal<NewEvent>) -> impl IntoView {
+    let hero = move || {
+        event.with(|e| e.hero_image.clone()).map(|key| {
+            view! {
+              <HeroImage key/>
+              <button
+                class="button is-danger is-outlined mb-3"
+                type="button"
+                on:click=move |_| event.update(|e| e.hero_image = None)
+              >
+                Remove Hero Image
+              </button>
+            }
+        })
+    };
+
+    let gallery = move || {
+        let keys = event.with(|e| e.gallery.clone());
+        keys.into_iter()
+            .enumerate()
+            .map(|(index, key)| {
+                view! {
+                  <div class="column is-3">
+                    <figure class="image">
+                      <img src=image_url(&key)/>
+                    </figure>
+                    <button
+                      class="button is-small is-danger is-outlined mt-1"
+                      type="button"
+                      on:click=move |_| event.update(|e| drop(e.gallery.remove(index)))
+                    >
+                      Remove
+                    </button>
+                  </div>
+                }
+            })
+            .collect_view()
+    };
+
+    view! {
+      <label class="label">Hero image</label>
+      {hero}
+      <UploadButton
+        label="Choose a hero image"
+        uploaded=move |key| event.update(|e| e.hero_image = Some(key))
+      />
+      <label class="label">Gallery</label>
+      <div class="columns is-multiline">{gallery}</div>
+      <UploadButton
+        label="Add to the gallery"
+        uploaded=move |key| event.update(|e| e.gallery.push(key))
+      />
+    }
+}
+
+#[component]
+pub fn HeroImage(key: String) -> impl IntoView {
+    view! {
+      <figure class="image block">
+        <img src=image_url(&key)/>
+      </figure>
+    }
+}
+
+// What people read about an event before booking it
+#[component]
+pub fn EventDescription(event: Event) -> impl IntoView {
+    let id = event.id.clone();
+    let description = create_resource(move || id.clone(), get_event_description);
+    let html = move || {
+        description
+            .get()
+            .and_then(Result::ok)
+            .filter(|html| !html.is_empty())
+            .map(|html| view! { <div class="content block" inner_html=html></div> })
+    };
+
+    let gallery = (!event.gallery.is_empty()).then(|| {
+        let images = event.gallery.iter().map(|key| {
+            view! {
+              <div class="column is-3">
+                <a href=image_url(key) target="_blank">
+                  <figure class="image">
+                    <img src=image_url(key)/>
+                  </figure>
+                </a>
+              </div>
+            }
+        });
+        view! { <div class="columns is-multiline block">{images.collect_view()}</div> }
+    });
+
+    view! {
+      {html}
+      {gallery}
+    }
+}
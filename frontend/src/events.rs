@@ -1,6 +1,11 @@
+use crate::app::use_has_role;
 use async_trait::async_trait;
-use common::event::{list_events, Event};
+use chrono::{DateTime, Local, Utc};
+use common::event::{list_events, list_sold_out, Event, EventId, Sales};
+use common::role::RoleId;
+use itertools::Itertools;
 use leptos::*;
+use leptos_router::A;
 use leptos_struct_table::*;
 use serde::{Deserialize, Serialize};
 
@@ -29,6 +34,52 @@ pub struct EventRow {
     action: FieldGetter<String>,
 }
 
+// Book Now, or why you can't, and a link to the editor for organisers
+fn actions(id: EventId) -> impl IntoView {
+    let is_organiser = use_has_role(RoleId::organiser());
+    let listed = expect_context::<Listed>();
+    let id = store_value(id);
+
+    let sales = move || {
+        listed.events.with(|events| {
+            events
+                .iter()
+                .find(|e| e.id == id())
+                .map(|e| e.sales(Utc::now()))
+        })
+    };
+    let sold_out = move || listed.sold_out.with(|s| s.contains(&id()));
+
+    let unavailable =
+        |msg: String| view! { <span class="button is-static">{msg}</span> }.into_view();
+    let book = move || match sales() {
+        Some(Sales::Unpublished) => unavailable("Draft".to_string()),
+        Some(Sales::Closed) => unavailable("Sales closed".to_string()),
+        _ if sold_out() => unavailable("Sold out".to_string()),
+        Some(Sales::NotOpenYet(open)) => {
+            let open = DateTime::<Local>::from(open).format("%d %B %-I:%M %p");
+            unavailable(format!("Sales open {}", open))
+        }
+        Some(Sales::Open) | None => view! {
+          <a href=format!("/events/{}/book", id()) class="button is-primary">
+            Book Now
+          </a>
+        }
+        .into_view(),
+    };
+
+    view! {
+      <div class="buttons">
+        {book}
+        <Show when=is_organiser>
+          <a href=format!("/events/{}/edit", id()) class="button">
+            Edit
+          </a>
+        </Show>
+      </div>
+    }
+}
+
 #[allow(unused_variables)]
 #[component]
 pub fn ActionRenderer<F>(
@@ -40,13 +91,7 @@ pub fn ActionRenderer<F>(
 where
     F: Fn(String) + 'static,
 {
-    view! {
-      <td class=class>
-        <a href=format!("/events/{}/book", value()) class="button is-primary">
-          Book Now
-        </a>
-      </td>
-    }
+    view! { <td class=class>{actions(value().into())}</td> }
 }
 
 impl EventRow {
@@ -70,27 +115,116 @@ impl From<Event> for EventRow {
     }
 }
 
+// What the booking buttons need to know about the events on the page
+#[derive(Clone, Copy)]
+struct Listed {
+    events: RwSignal<Vec<Event>>,
+    sold_out: RwSignal<Vec<EventId>>,
+}
+
+// Events from the same series are shown together, under the name of the first one
+#[component]
+fn SeriesBox(events: Vec<Event>) -> impl IntoView {
+    let name = events[0].name.clone();
+    let tagline = events[0].tagline.clone();
+    let row = |e: Event| {
+        let when = e.start_local().format("%a %d %B %Y, %-I:%M %p").to_string();
+        view! {
+          <tr>
+            <td>{when}</td>
+            <td>{actions(e.id)}</td>
+          </tr>
+        }
+    };
+
+    view! {
+      <div class="box">
+        <h2 class="title is-5">{name}</h2>
+        <p class="subtitle is-6">{tagline}</p>
+        <table class="table is-fullwidth">
+          <tbody>{events.into_iter().map(row).collect_view()}</tbody>
+        </table>
+      </div>
+    }
+}
+
 #[component]
 pub fn Events() -> impl IntoView {
     let rows = create_rw_signal::<Vec<EventRow>>(vec![]);
+    let events = create_rw_signal::<Vec<Event>>(vec![]);
+    let sold_out = create_rw_signal::<Vec<EventId>>(vec![]);
+    provide_context(Listed { events, sold_out });
+
+    let is_organiser = use_has_role(RoleId::organiser());
+    let show_everything = create_rw_signal(false);
     let _res = create_resource(
-        || (),
-        move |_| async move {
-            let doofers: Vec<EventRow> = list_events()
-                .await
-                .unwrap_or(vec![])
-                .into_iter()
-                .map(|u| u.into())
+        move || show_everything() && is_organiser(),
+        move |everything| async move {
+            let mut listed = list_events(everything).await.unwrap_or(vec![]);
+            listed.sort_by_key(|e| e.start);
+            let doofers: Vec<EventRow> = listed
+                .iter()
+                .filter(|e| e.series.is_none())
+                .map(|u| u.clone().into())
                 .collect();
 
+            sold_out.set(list_sold_out().await.unwrap_or_default());
+            events.set(listed);
             rows.set(doofers);
         },
     );
+
+    let series = move || {
+        events.with(|events| {
+            events
+                .iter()
+                .filter(|e| e.series.is_some())
+                .cloned()
+                .into_group_map_by(|e| e.series.clone())
+                .into_values()
+                .sorted_by_key(|group| group[0].start)
+                .map(|group| view! { <SeriesBox events=group/> })
+                .collect_view()
+        })
+    };
     view! {
       <section class="section">
         <div class="container">
           <h1 class="title">Events</h1>
+          <Show when=is_organiser>
+            <div class="field is-grouped">
+              <div class="control">
+                <A class="button is-primary" href="/events/new">
+                  New Event
+                </A>
+              </div>
+              <div class="control">
+                <A class="button" href="/series">
+                  Series
+                </A>
+              </div>
+              <div class="control">
+                <A class="button" href="/venues">
+                  Venues
+                </A>
+              </div>
+              <div class="control">
+                <label class="checkbox">
+                  <input
+                    type="checkbox"
+                    prop:checked=show_everything
+                    on:change=move |e| show_everything.set(event_target_checked(&e))
+                  />
+                  " Show drafts and finished or archived events"
+                </label>
+              </div>
+            </div>
+          </Show>
           <EventRowTable items=rows/>
+          {series}
+          <p class="block mt-5">
+            <a href="/calendar.ics">"Subscribe to these events in your calendar"</a>
+          </p>
         </div>
       </section>
     }
@@ -0,0 +1,60 @@
+use chrono::{DateTime, Local, Utc};
+use common::janitor::{list_sweeps, SweepReport};
+use leptos::*;
+
+use crate::book_event::require_login;
+
+fn local(t: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(t).format("%-d %b %-I:%M %p").to_string()
+}
+
+// What the background janitor has been clearing out of the database
+#[component]
+pub fn Janitor() -> impl IntoView {
+    require_login();
+
+    let sweeps = create_resource(|| (), |_| list_sweeps());
+
+    let sweep_row = move |s: SweepReport| {
+        view! {
+          <tr>
+            <td>{s.name.replace('_', " ")}</td>
+            <td>{local(s.last_run_at)}</td>
+            <td>{s.last_removed}</td>
+            <td>{s.total_removed}</td>
+            <td class="has-text-danger">{s.last_error}</td>
+          </tr>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Database cleanup</h1>
+          <table class="table is-fullwidth">
+            <thead>
+              <tr>
+                <th>Leftovers</th>
+                <th>Last swept</th>
+                <th>Removed</th>
+                <th>Removed in all</th>
+                <th>Last error</th>
+              </tr>
+            </thead>
+            <tbody>
+              {move || {
+                  sweeps
+                      .get()
+                      .and_then(|s| s.ok())
+                      .unwrap_or_default()
+                      .into_iter()
+                      .map(sweep_row)
+                      .collect_view()
+              }}
+
+            </tbody>
+          </table>
+        </div>
+      </section>
+    }
+}
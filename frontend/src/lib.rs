@@ -1,18 +1,27 @@
 mod app;
+mod audit;
 mod book_event;
 mod components;
 mod email_field;
 mod error_handling;
+mod event_editor;
+mod event_media;
 mod events;
 mod field;
 mod icon_button;
+mod janitor;
+mod lockouts;
 mod navbar;
 mod not_found;
+mod profile;
 mod reactive_list;
+mod series;
+mod sessions;
 mod sign_in;
 mod slot_state;
 mod users;
 mod utils;
+mod venues;
 
 use app::App;
 use leptos::*;
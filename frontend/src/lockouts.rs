@@ -0,0 +1,76 @@
+use chrono::{DateTime, Local, Utc};
+use common::auth::throttle::{clear_lockout, list_lockouts, Lockout, ThrottleKind};
+use leptos::*;
+
+use crate::book_event::require_login;
+use crate::sign_in::ErrorNotification;
+
+fn local(t: DateTime<Utc>) -> String { DateTime::<Local>::from(t).format("%-I:%M %p").to_string() }
+
+// Accounts and addresses currently locked out after too many failed sign ins
+#[component]
+pub fn Lockouts() -> impl IntoView {
+    require_login();
+
+    let lockouts = create_resource(|| (), |_| list_lockouts());
+
+    let clear = create_action(move |lockout: &(ThrottleKind, String)| {
+        let (kind, key) = lockout.clone();
+        async move {
+            clear_lockout(kind, key)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            lockouts.refetch();
+            Ok::<(), String>(())
+        }
+    });
+
+    let lockout_row = move |l: Lockout| {
+        let target = (l.kind, l.key.clone());
+        view! {
+          <tr>
+            <td>{l.kind.to_string()}</td>
+            <td>{l.key}</td>
+            <td>{l.failures}</td>
+            <td>{local(l.locked_until)}</td>
+            <td class="has-text-right">
+              <button class="button is-small" on:click=move |_| clear.dispatch(target.clone())>
+                Unlock
+              </button>
+            </td>
+          </tr>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Sign in lockouts</h1>
+          <table class="table is-fullwidth">
+            <thead>
+              <tr>
+                <th>Locked</th>
+                <th>Email or address</th>
+                <th>Failed attempts</th>
+                <th>Until</th>
+                <th></th>
+              </tr>
+            </thead>
+            <tbody>
+              {move || {
+                  lockouts
+                      .get()
+                      .and_then(|l| l.ok())
+                      .unwrap_or_default()
+                      .into_iter()
+                      .map(lockout_row)
+                      .collect_view()
+              }}
+
+            </tbody>
+          </table>
+          <ErrorNotification sig=clear.value()/>
+        </div>
+      </section>
+    }
+}
@@ -1,4 +1,6 @@
-use crate::app::{MaybePersonSignal, SessionID, SignInSignal, SignInStatus};
+use crate::app::{use_has_role, MaybePersonSignal, SessionChanged, SignInSignal, SignInStatus};
+use common::auth::session::logout;
+use common::role::RoleId;
 use leptos::*;
 use leptos_router::A;
 // use leptos_use::storage::{use_local_storage, JsonCodec};
@@ -6,17 +8,30 @@ use leptos_router::A;
 #[component]
 pub fn NavBar() -> impl IntoView {
     let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
-    let set_session = use_context::<WriteSignal<SessionID>>().unwrap();
+    let session_changed = use_context::<SessionChanged>().unwrap();
     let user_info = use_context::<MaybePersonSignal>().unwrap();
     let menu_open = create_rw_signal(false);
+    let is_admin = use_has_role(RoleId::admin());
+
+    let sign_out = create_action(move |_: &()| async move {
+        if let Err(e) = logout().await {
+            logging::warn!("failed to sign out: {:?}", e);
+        }
+        session_changed.notify();
+    });
 
     let dudger = move || match user_info() {
         Some(ui) => view! {
           <div class="navbar-item has-dropdown is-hoverable">
             <a class="navbar-link">{format!("{} {}", ui.given_name, ui.family_name)}</a>
             <div class="navbar-dropdown">
-              // <a class="navbar-item">Profile</a>
-              <a class="navbar-item" on:click=move |_| set_session(SessionID::NotSet)>
+              <A class="navbar-item" href="/profile">
+                Profile
+              </A>
+              <A class="navbar-item" href="/sessions">
+                Sessions
+              </A>
+              <a class="navbar-item" on:click=move |_| sign_out.dispatch(())>
                 Sign Out
               </a>
             </div>
@@ -61,6 +76,21 @@ pub fn NavBar() -> impl IntoView {
               Events
             </A>
 
+            <Show when=is_admin>
+              <A class="navbar-item" href="/users">
+                Users
+              </A>
+              <A class="navbar-item" href="/lockouts">
+                Lockouts
+              </A>
+              <A class="navbar-item" href="/janitor">
+                Cleanup
+              </A>
+              <A class="navbar-item" href="/audit">
+                Audit
+              </A>
+            </Show>
+
           </div>
 
           <div class="navbar-end">
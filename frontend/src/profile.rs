@@ -0,0 +1,552 @@
+use chrono::{DateTime, Local, Utc};
+use common::auth::api_token::{create_api_token, list_api_tokens, revoke_api_token, ApiTokenInfo};
+use common::auth::login_methods::{get_login_methods, remove_password, set_password, unlink_identity};
+use common::auth::oauth::{list_providers, OAuthProviderInfo, OAuthRedirect};
+use common::auth::two_factor::{
+    begin_totp_enrolment, confirm_totp_enrolment, disable_totp, two_factor_status, TotpEnrolment,
+};
+use common::role::RoleId;
+use common::user::{Identity, LoginMethods};
+use leptos::*;
+
+use crate::app::{MaybePersonSignal, RolesSignal, SessionChanged};
+use crate::book_event::require_login;
+use crate::sign_in::{oauth_popup, ErrorNotification};
+
+#[component]
+pub fn Profile() -> impl IntoView {
+    require_login();
+    let person = use_context::<MaybePersonSignal>().unwrap();
+
+    let providers = create_resource(|| (), |_| list_providers());
+    let methods_res = create_resource(|| (), |_| get_login_methods());
+
+    let providers = Signal::derive(move || providers.get().and_then(|p| p.ok()).unwrap_or_default());
+    let methods = Signal::derive(move || methods_res.get().and_then(|m| m.ok()));
+    // Changes hand back the updated methods, so keep hold of the latest
+    let updated = create_rw_signal(None::<LoginMethods>);
+    let current = Signal::derive(move || updated().or(methods()).unwrap_or_default());
+    let is_last = Signal::derive(move || {
+        let m = current();
+        m.identities.len() + m.password as usize <= 1
+    });
+
+    let unlink = create_action(move |identity: &Identity| {
+        let identity = identity.clone();
+        async move {
+            let m = unlink_identity(identity.provider, identity.subject)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            updated.set(Some(m));
+            Ok::<(), String>(())
+        }
+    });
+
+    let label = move |provider: &str| {
+        providers()
+            .into_iter()
+            .find(|p| p.name == provider)
+            .map(|p| p.label)
+            .unwrap_or(provider.to_string())
+    };
+
+    let identity_row = move |identity: Identity| {
+        let name = label(&identity.provider);
+        let email = identity.email.clone().unwrap_or_default();
+        view! {
+          <tr>
+            <td>{name}</td>
+            <td>{email}</td>
+            <td class="has-text-right">
+              <button
+                class="button is-small"
+                disabled=is_last
+                on:click=move |_| unlink.dispatch(identity.clone())
+              >
+                Unlink
+              </button>
+            </td>
+          </tr>
+        }
+    };
+
+    let link_button = move |provider: OAuthProviderInfo| {
+        let query = serde_qs::to_string(&OAuthRedirect {
+            provider: provider.name,
+            return_url: Some("/profile".to_string()),
+            link: Some(true),
+        })
+        .unwrap();
+        let url = format!("{}?{}", OAuthRedirect::PATH, query);
+        view! {
+          <button
+            class="button"
+            on:click=move |_| {
+                let _ = oauth_popup(
+                    &url,
+                    move || {
+                        updated.set(None);
+                        methods_res.refetch();
+                    },
+                );
+            }
+          >
+
+            <span class="icon">
+              <img src=provider.icon_url/>
+            </span>
+            <span>"Link " {provider.label}</span>
+          </button>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container" style="max-width: 40em">
+          <h1 class="title">Your profile</h1>
+          {move || {
+              person()
+                  .map(|p| {
+                      view! {
+                        <p class="subtitle">{p.full_name()} " (" {p.email} ")"</p>
+                      }
+                  })
+          }}
+
+          <div class="box">
+            <h2 class="subtitle">Linked accounts</h2>
+            <table class="table is-fullwidth">
+              <tbody>
+                {move || current().identities.into_iter().map(identity_row).collect_view()}
+              </tbody>
+            </table>
+            <ErrorNotification sig=unlink.value()/>
+            <div class="buttons">
+              {move || {
+                  providers()
+                      .into_iter()
+                      .filter(|p| {
+                          !current().identities.iter().any(|i| i.provider == p.name)
+                      })
+                      .map(link_button)
+                      .collect_view()
+              }}
+
+            </div>
+          </div>
+
+          <div class="box">
+            <h2 class="subtitle">Password</h2>
+            <PasswordForm
+              has_password=Signal::derive(move || current().password)
+              is_last=is_last
+              updated=updated
+            />
+          </div>
+
+          <div class="box">
+            <h2 class="subtitle">Two-factor authentication</h2>
+            <TwoFactor/>
+          </div>
+
+          <div class="box">
+            <h2 class="subtitle">API tokens</h2>
+            <ApiTokens/>
+          </div>
+        </div>
+      </section>
+    }
+}
+
+#[component]
+fn PasswordForm(
+    has_password: Signal<bool>,
+    is_last: Signal<bool>,
+    updated: RwSignal<Option<LoginMethods>>,
+) -> impl IntoView {
+    let (current_password, set_current_password) = create_signal("".to_string());
+    let (password1, set_password1) = create_signal("".to_string());
+    let (password2, set_password2) = create_signal("".to_string());
+    let password_mismatch = Signal::derive(move || password1() != password2());
+
+    let submit = create_action(move |(current, new): &(Option<String>, String)| {
+        let (current, new) = (current.clone(), new.clone());
+        async move {
+            let m = set_password(current, new).await.map_err(|e| format!("{:?}", e))?;
+            updated.set(Some(m));
+            Ok::<(), String>(())
+        }
+    });
+
+    let remove = create_action(move |_: &()| async move {
+        let m = remove_password().await.map_err(|e| format!("{:?}", e))?;
+        updated.set(Some(m));
+        Ok::<(), String>(())
+    });
+
+    view! {
+      <form on:submit=move |e| {
+          e.prevent_default();
+          if password_mismatch() {
+              return;
+          }
+          let current = if has_password() { Some(current_password()) } else { None };
+          submit.dispatch((current, password1()))
+      }>
+        <Show when=has_password>
+          <div class="field">
+            <div class="control">
+              <input
+                class="input"
+                type="password"
+                placeholder="Current Password"
+                on:change=move |e| set_current_password(event_target_value(&e))
+              />
+            </div>
+          </div>
+        </Show>
+        <div class="field is-grouped">
+          <div class="control is-expanded">
+            <input
+              class="input"
+              class:is-danger=password_mismatch
+              type="password"
+              placeholder="New Password"
+              on:change=move |e| set_password1(event_target_value(&e))
+            />
+          </div>
+          <div class="control is-expanded">
+            <input
+              class="input"
+              class:is-danger=password_mismatch
+              type="password"
+              placeholder="Password Confirmation"
+              on:change=move |e| set_password2(event_target_value(&e))
+            />
+          </div>
+        </div>
+        <ErrorNotification sig=submit.value()/>
+        <ErrorNotification sig=remove.value()/>
+        <Show when=move || matches!(submit.value()(), Some(Ok(())))>
+          <div class="notification is-success">"Your password has been saved."</div>
+        </Show>
+        <div class="buttons">
+          <button class="button is-primary" disabled=password_mismatch type="submit">
+            {move || if has_password() { "Change Password" } else { "Set Password" }}
+          </button>
+          <Show when=has_password>
+            <button
+              class="button is-danger is-outlined"
+              type="button"
+              disabled=is_last
+              on:click=move |_| remove.dispatch(())
+            >
+              Remove Password
+            </button>
+          </Show>
+        </div>
+      </form>
+    }
+}
+
+fn local_date(t: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(t)
+        .format("%d %B %Y")
+        .to_string()
+}
+
+// Tokens for scripts to act as you, limited to the roles ticked when they're made
+#[component]
+fn ApiTokens() -> impl IntoView {
+    let roles = use_context::<RolesSignal>().unwrap();
+    let tokens = create_resource(|| (), |_| list_api_tokens());
+
+    let (name, set_name) = create_signal("".to_string());
+    let scopes = create_rw_signal(vec![RoleId::attendee()]);
+    let (expires_in_days, set_expires_in_days) = create_signal(Some(90));
+
+    let create = create_action(move |(name, scopes, days): &(String, Vec<RoleId>, Option<i64>)| {
+        let (name, scopes, days) = (name.clone(), scopes.clone(), *days);
+        async move {
+            let new = create_api_token(name, scopes, days)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            tokens.refetch();
+            Ok::<String, String>(new.token)
+        }
+    });
+
+    let revoke = create_action(move |id: &String| {
+        let id = id.clone();
+        async move {
+            revoke_api_token(id).await.map_err(|e| format!("{:?}", e))?;
+            tokens.refetch();
+            Ok::<(), String>(())
+        }
+    });
+
+    let token_row = move |t: ApiTokenInfo| {
+        let scopes = t.scopes.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ");
+        view! {
+          <tr>
+            <td>{t.name}</td>
+            <td>{scopes}</td>
+            <td>{t.expires_at.map(local_date).unwrap_or("Never".to_string())}</td>
+            <td>{t.last_used_at.map(local_date).unwrap_or("Never".to_string())}</td>
+            <td class="has-text-right">
+              <button class="button is-small" on:click=move |_| revoke.dispatch(t.id.clone())>
+                Revoke
+              </button>
+            </td>
+          </tr>
+        }
+    };
+
+    let scope_checkbox = move |role: RoleId| {
+        let checked = {
+            let role = role.clone();
+            move || scopes().contains(&role)
+        };
+        let toggle = {
+            let role = role.clone();
+            move |_| {
+                scopes.update(|s| match s.iter().position(|r| *r == role) {
+                    Some(i) => {
+                        s.remove(i);
+                    }
+                    None => s.push(role.clone()),
+                })
+            }
+        };
+        view! {
+          <label class="checkbox mr-3">
+            <input type="checkbox" prop:checked=checked on:change=toggle/>
+            " "
+            {role.to_string()}
+          </label>
+        }
+    };
+
+    // Only offer the roles we have to give
+    let available = move || {
+        [RoleId::attendee(), RoleId::organiser(), RoleId::admin()]
+            .into_iter()
+            .filter(|s| *s == RoleId::attendee() || roles().iter().any(|r| r.grants(s)))
+            .map(scope_checkbox)
+            .collect_view()
+    };
+
+    let new_token = move || match create.value()() {
+        Some(Ok(token)) => view! {
+          <div class="notification is-success">
+            "Here's your new token. Copy it now, you won't be able to see it again."
+            <pre class="mt-2">{token}</pre>
+          </div>
+        }
+        .into_view(),
+        _ => "".into_view(),
+    };
+
+    view! {
+      <table class="table is-fullwidth">
+        <thead>
+          <tr>
+            <th>Name</th>
+            <th>Scopes</th>
+            <th>Expires</th>
+            <th>Last used</th>
+            <th></th>
+          </tr>
+        </thead>
+        <tbody>
+          {move || {
+              tokens
+                  .get()
+                  .and_then(|t| t.ok())
+                  .unwrap_or_default()
+                  .into_iter()
+                  .map(token_row)
+                  .collect_view()
+          }}
+
+        </tbody>
+      </table>
+      <ErrorNotification sig=revoke.value()/>
+      {new_token}
+      <form on:submit=move |e| {
+          e.prevent_default();
+          create.dispatch((name(), scopes(), expires_in_days()))
+      }>
+        <div class="field is-grouped">
+          <div class="control is-expanded">
+            <input
+              class="input"
+              type="text"
+              placeholder="Token Name"
+              on:change=move |e| set_name(event_target_value(&e))
+            />
+          </div>
+          <div class="control">
+            <div class="select">
+              <select on:change=move |e| {
+                  set_expires_in_days(event_target_value(&e).parse().ok())
+              }>
+                <option value="30">Expires in 30 days</option>
+                <option value="90" selected>Expires in 90 days</option>
+                <option value="365">Expires in a year</option>
+                <option value="never">Never expires</option>
+              </select>
+            </div>
+          </div>
+        </div>
+        <div class="field">{available}</div>
+        <ErrorNotification sig=create.value()/>
+        <button class="button is-primary" type="submit">
+          Create Token
+        </button>
+      </form>
+    }
+}
+
+// An authenticator app as a second factor when signing in
+#[component]
+fn TwoFactor() -> impl IntoView {
+    let session_changed = use_context::<SessionChanged>().unwrap();
+    let enrolment = create_rw_signal(None::<TotpEnrolment>);
+
+    let begin = create_action(move |_: &()| async move {
+        let e = begin_totp_enrolment()
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        enrolment.set(Some(e));
+        Ok::<(), String>(())
+    });
+
+    // Hands back the recovery codes to show
+    let confirm = create_action(move |code: &String| {
+        let code = code.clone();
+        async move {
+            let codes = confirm_totp_enrolment(code)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            enrolment.set(None);
+            session_changed.notify();
+            Ok::<Vec<String>, String>(codes)
+        }
+    });
+
+    let disable = create_action(move |code: &String| {
+        let code = code.clone();
+        async move {
+            disable_totp(code).await.map_err(|e| format!("{:?}", e))?;
+            Ok::<(), String>(())
+        }
+    });
+
+    // Fetched again whenever it's been changed
+    let status = create_resource(
+        move || (confirm.version()(), disable.version()()),
+        |_| two_factor_status(),
+    );
+    let status = Signal::derive(move || status.get().and_then(|s| s.ok()));
+
+    let recovery_codes = move || match confirm.value()() {
+        Some(Ok(codes)) => view! {
+          <div class="notification is-success">
+            "Your authenticator app is set up. Keep these recovery codes somewhere safe, each can \
+             be used once instead of a code from the app. You won't see them again."
+            <pre class="mt-2">{codes.join("\n")}</pre>
+          </div>
+        }
+        .into_view(),
+        _ => "".into_view(),
+    };
+
+    let body = move || {
+        let Some(s) = status() else {
+            return "".into_view();
+        };
+        if s.enrolled {
+            return view! {
+              <p class="block">
+                {format!(
+                    "An authenticator app is set up, with {} recovery codes left.",
+                    s.recovery_codes_left,
+                )}
+
+              </p>
+              <Show when=move || !s.required>
+                <p class="block">"To remove it, enter a code from the app."</p>
+                <CodeForm label="Remove" action=disable/>
+              </Show>
+            }
+            .into_view();
+        }
+
+        match enrolment() {
+            Some(e) => view! {
+              <p class="block">
+                "Scan this with your authenticator app, or enter the key by hand, then enter the \
+                 code it shows."
+              </p>
+              <img class="block" src=e.qr_code/>
+              <p class="block">
+                <code>{e.secret}</code>
+              </p>
+              <CodeForm label="Confirm" action=confirm/>
+            }
+            .into_view(),
+            None => view! {
+              <Show when=move || s.required>
+                <div class="notification is-warning">
+                  "Your role needs a second factor. Until you set one up you can only do what \
+                   attendees can."
+                </div>
+              </Show>
+              <button class="button" on:click=move |_| begin.dispatch(())>
+                Set up an authenticator app
+              </button>
+            }
+            .into_view(),
+        }
+    };
+
+    view! {
+      {recovery_codes}
+      {body}
+      <ErrorNotification sig=begin.value()/>
+      <ErrorNotification sig=confirm.value()/>
+      <ErrorNotification sig=disable.value()/>
+    }
+}
+
+#[component]
+fn CodeForm<T: 'static>(
+    label: &'static str,
+    action: Action<String, Result<T, String>>,
+) -> impl IntoView {
+    let (code, set_code) = create_signal("".to_string());
+    view! {
+      <form on:submit=move |e| {
+          e.prevent_default();
+          action.dispatch(code())
+      }>
+        <div class="field has-addons">
+          <div class="control is-expanded">
+            <input
+              class="input"
+              type="text"
+              autocomplete="one-time-code"
+              placeholder="Code"
+              on:change=move |e| set_code(event_target_value(&e))
+            />
+          </div>
+          <div class="control">
+            <button class="button is-primary" type="submit">
+              {label}
+            </button>
+          </div>
+        </div>
+      </form>
+    }
+}
@@ -53,7 +53,6 @@ impl<T> IntoIterator for ReactiveList<T> {
 pub trait TrackableList<T> {
     fn tracked_push(&self, guest: T);
     fn tracked_remove(&self, uid: Uuid);
-    fn tracked_insert(&self, uid: Uuid, new: T);
 }
 
 impl<S, T> TrackableList<T> for S
@@ -72,12 +71,6 @@ where
             gs.0.shift_remove(&uid);
         });
     }
-
-    fn tracked_insert(&self, uid: Uuid, new: T) {
-        self.update(|gs| {
-            gs.0.insert(uid, create_rw_signal::<T>(new));
-        });
-    }
 }
 
 // impl<T> From<ReactiveList<T>> for Vec<T> {
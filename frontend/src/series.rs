@@ -0,0 +1,363 @@
+use chrono::{DateTime, Local, Utc};
+use chrono_tz::{Tz, TZ_VARIANTS};
+use common::event::NewEvent;
+use common::series::{
+    create_series, get_series, list_series, update_series, EventSeries, NewEventSeries,
+    Recurrence, SeriesId,
+};
+use leptos::*;
+use leptos_router::{use_navigate, use_params_map, NavigateOptions, A};
+
+use crate::book_event::require_login;
+use crate::event_editor::{blank_event, from_input, input, to_input, EventForm};
+
+fn local_date(t: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(t)
+        .format("%a %d %B %Y")
+        .to_string()
+}
+
+// Where the browser is, which is the best guess at where a new series happens
+fn browser_timezone() -> Tz {
+    use web_sys::js_sys::{Array, Intl, Object, Reflect};
+    let options = Intl::DateTimeFormat::new(&Array::new(), &Object::new()).resolved_options();
+    Reflect::get(&options, &"timeZone".into())
+        .ok()
+        .and_then(|tz| tz.as_string())
+        .and_then(|tz| tz.parse().ok())
+        .unwrap_or(chrono_tz::Europe::London)
+}
+
+fn describe(recurrence: &Recurrence) -> String {
+    match recurrence {
+        Recurrence::Weekly { count } => format!("Weekly, {} events", count),
+        Recurrence::Monthly { count } => format!("Monthly, {} events", count),
+        Recurrence::Dates(dates) => format!("On {} chosen dates", dates.len()),
+    }
+}
+
+#[component]
+fn DateRow(recurrence: RwSignal<Recurrence>, index: usize) -> impl IntoView {
+    let get = move || {
+        recurrence.with(|r| match r {
+            Recurrence::Dates(dates) => dates.get(index).copied().map(to_input).unwrap_or_default(),
+            _ => "".to_string(),
+        })
+    };
+    let set = move |v: String| {
+        recurrence.update(|r| {
+            if let (Recurrence::Dates(dates), Some(t)) = (r, from_input(&v)) {
+                dates[index] = t
+            }
+        })
+    };
+    let remove = move |_| {
+        recurrence.update(|r| {
+            if let Recurrence::Dates(dates) = r {
+                dates.remove(index);
+            }
+        })
+    };
+
+    view! {
+      <div class="field is-grouped">
+        {input("datetime-local", "", get, set)}
+        <div class="control">
+          <button class="button is-danger is-outlined" type="button" on:click=remove>
+            Remove
+          </button>
+        </div>
+      </div>
+    }
+}
+
+#[component]
+fn RecurrenceForm(
+    recurrence: RwSignal<Recurrence>,
+    timezone: RwSignal<Tz>,
+    first: Signal<DateTime<Utc>>,
+) -> impl IntoView {
+    let kind = create_memo(move |_| {
+        recurrence.with(|r| match r {
+            Recurrence::Weekly { .. } => "weekly",
+            Recurrence::Monthly { .. } => "monthly",
+            Recurrence::Dates(_) => "dates",
+        })
+    });
+    let set_kind = move |kind: String| {
+        let count = recurrence.with(|r| match r {
+            Recurrence::Weekly { count } | Recurrence::Monthly { count } => *count,
+            Recurrence::Dates(dates) => dates.len() as u32,
+        });
+        recurrence.set(match kind.as_str() {
+            "weekly" => Recurrence::Weekly { count },
+            "monthly" => Recurrence::Monthly { count },
+            _ => Recurrence::Dates(
+                recurrence.with(|r| r.starts(first.get_untracked(), timezone.get_untracked())),
+            ),
+        })
+    };
+
+    let count = move || {
+        recurrence.with(|r| match r {
+            Recurrence::Weekly { count } | Recurrence::Monthly { count } => count.to_string(),
+            Recurrence::Dates(dates) => dates.len().to_string(),
+        })
+    };
+    let set_count = move |v: String| {
+        let n = v.trim().parse().unwrap_or(1);
+        recurrence.update(|r| match r {
+            Recurrence::Weekly { count } | Recurrence::Monthly { count } => *count = n,
+            Recurrence::Dates(_) => {}
+        })
+    };
+
+    let date_count = create_memo(move |_| {
+        recurrence.with(|r| match r {
+            Recurrence::Dates(dates) => dates.len(),
+            _ => 0,
+        })
+    });
+    let add_date = move |_| {
+        recurrence.update(|r| {
+            if let Recurrence::Dates(dates) = r {
+                dates.push(dates.last().copied().unwrap_or(first.get_untracked()))
+            }
+        })
+    };
+
+    let preview = move || {
+        let starts = recurrence.with(|r| r.starts(first(), timezone()));
+        starts
+            .into_iter()
+            .map(|t| view! { <li>{local_date(t)}</li> })
+            .collect_view()
+    };
+
+    let kind_option = move |value: &'static str, label: &'static str| {
+        view! {
+          <option value=value selected=move || kind() == value>
+            {label}
+          </option>
+        }
+    };
+
+    let timezones = TZ_VARIANTS.iter().map(|tz| {
+        view! {
+          <option value=tz.name() selected=move || timezone() == *tz>
+            {tz.name()}
+          </option>
+        }
+    });
+    let set_timezone = move |name: String| {
+        if let Ok(tz) = name.parse() {
+            timezone.set(tz)
+        }
+    };
+
+    view! {
+      <label class="label">Timezone</label>
+      <div class="field">
+        <div class="control">
+          <div class="select">
+            <select on:change=move |e| set_timezone(event_target_value(&e))>
+              {timezones.collect_view()}
+            </select>
+          </div>
+        </div>
+      </div>
+      <label class="label">Repeats</label>
+      <div class="field is-grouped">
+        <div class="control">
+          <div class="select">
+            <select on:change=move |e| set_kind(event_target_value(&e))>
+              {kind_option("weekly", "Every week")}
+              {kind_option("monthly", "Every month")}
+              {kind_option("dates", "On chosen dates")}
+            </select>
+          </div>
+        </div>
+        <Show when=move || kind() != "dates">
+          {input("number", "How many events", count, set_count)}
+        </Show>
+      </div>
+      <Show when=move || kind() == "dates">
+        {move || {
+            (0..date_count())
+                .map(|index| view! { <DateRow recurrence index/> })
+                .collect_view()
+        }}
+
+        <button class="button mb-3" type="button" on:click=add_date>
+          Add Date
+        </button>
+      </Show>
+      <div class="content">
+        <p>"Events in this series:"</p>
+        <ul>{preview}</ul>
+      </div>
+    }
+}
+
+// Organisers' list of series, the events they make show up on the event list as usual
+#[component]
+pub fn SeriesList() -> impl IntoView {
+    require_login();
+    let series = create_resource(|| (), |_| list_series());
+
+    let row = move |s: EventSeries| {
+        view! {
+          <tr>
+            <td>{s.template.name.clone()}</td>
+            <td>{describe(&s.recurrence)}</td>
+            <td>{local_date(s.template.start)}</td>
+            <td>
+              <A class="button" href=format!("/series/{}/edit", s.id)>
+                Edit
+              </A>
+            </td>
+          </tr>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Event series</h1>
+          <A class="button is-primary" href="/series/new">
+            New Series
+          </A>
+          <table class="table is-fullwidth is-striped">
+            <thead>
+              <tr>
+                <th>Name</th>
+                <th>Repeats</th>
+                <th>First event</th>
+                <th></th>
+              </tr>
+            </thead>
+            <tbody>
+              {move || match series.get() {
+                  Some(Ok(series)) => series.into_iter().map(row).collect_view(),
+                  Some(Err(e)) => {
+                      view! {
+                        <tr>
+                          <td colspan="4" class="has-text-danger">
+                            {format!("{:?}", e)}
+                          </td>
+                        </tr>
+                      }
+                          .into_view()
+                  }
+                  None => ().into_view(),
+              }}
+
+            </tbody>
+          </table>
+        </div>
+      </section>
+    }
+}
+
+#[component]
+pub fn CreateSeries() -> impl IntoView {
+    require_login();
+    let template = create_rw_signal(blank_event());
+    let recurrence = create_rw_signal(Recurrence::Weekly { count: 4 });
+    let timezone = create_rw_signal(browser_timezone());
+    let first = Signal::derive(move || template.with(|t| t.start));
+    let navigate = use_navigate();
+
+    let save = create_action(move |template: &NewEvent| {
+        let series = NewEventSeries {
+            template: template.clone(),
+            recurrence: recurrence.get_untracked(),
+            timezone: timezone.get_untracked(),
+        };
+        let navigate = navigate.clone();
+        async move {
+            let id = create_series(series).await.map_err(|e| format!("{:?}", e))?;
+            navigate(&format!("/series/{}/edit", id), NavigateOptions::default());
+            Ok::<(), String>(())
+        }
+    });
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">New series</h1>
+          <RecurrenceForm recurrence timezone first/>
+          <h2 class="subtitle mt-5">Each event</h2>
+          <EventForm event=template save button="Create Series"/>
+        </div>
+      </section>
+    }
+}
+
+#[component]
+fn SeriesEditor(id: SeriesId, series: EventSeries) -> impl IntoView {
+    let id = store_value(id);
+    let template = create_rw_signal(series.template);
+    let recurrence = create_rw_signal(series.recurrence);
+    let timezone = create_rw_signal(series.timezone);
+    let apply_to_future = create_rw_signal(true);
+    let first = Signal::derive(move || template.with(|t| t.start));
+
+    let save = create_action(move |template: &NewEvent| {
+        let series = NewEventSeries {
+            template: template.clone(),
+            recurrence: recurrence.get_untracked(),
+            timezone: timezone.get_untracked(),
+        };
+        async move {
+            update_series(id.get_value(), series, apply_to_future.get_untracked())
+                .await
+                .map_err(|e| format!("{:?}", e))
+        }
+    });
+
+    view! {
+      <RecurrenceForm recurrence timezone first/>
+      <h2 class="subtitle mt-5">Each event</h2>
+      <div class="field">
+        <label class="checkbox">
+          <input
+            type="checkbox"
+            prop:checked=apply_to_future
+            on:change=move |e| apply_to_future.set(event_target_checked(&e))
+          />
+          " Change events that are still to come as well as new ones"
+        </label>
+      </div>
+      <EventForm event=template save button="Save Changes"/>
+      <Show when=move || matches!(save.value()(), Some(Ok(())))>
+        <div class="notification is-success mt-3">"The series has been saved."</div>
+      </Show>
+    }
+}
+
+#[component]
+pub fn EditSeries() -> impl IntoView {
+    require_login();
+    let params = use_params_map();
+    let id =
+        move || -> SeriesId { params.with(|p| p.get("id").cloned().unwrap_or_default().into()) };
+    let series = create_resource(id, get_series);
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Edit series</h1>
+          {move || match series.get() {
+              Some(Ok(s)) => view! { <SeriesEditor id=id() series=s/> }.into_view(),
+              Some(Err(e)) => {
+                  view! { <div class="notification is-danger">{format!("{:?}", e)}</div> }
+                      .into_view()
+              }
+              None => view! { <p>"Loading.."</p> }.into_view(),
+          }}
+
+        </div>
+      </section>
+    }
+}
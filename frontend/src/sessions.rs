@@ -0,0 +1,97 @@
+use chrono::{DateTime, Local, Utc};
+use common::auth::session::{list_sessions, logout_everywhere, revoke_session, SessionInfo};
+use leptos::*;
+
+use crate::app::SessionChanged;
+use crate::book_event::require_login;
+use crate::sign_in::ErrorNotification;
+
+fn local(t: DateTime<Utc>) -> String {
+    DateTime::<Local>::from(t)
+        .format("%d %B %Y %-I:%M %p")
+        .to_string()
+}
+
+#[component]
+pub fn Sessions() -> impl IntoView {
+    require_login();
+    let session_changed = use_context::<SessionChanged>().unwrap();
+
+    let sessions = create_resource(|| (), |_| list_sessions());
+
+    let revoke = create_action(move |handle: &String| {
+        let handle = handle.clone();
+        async move {
+            revoke_session(handle)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            sessions.refetch();
+            Ok::<(), String>(())
+        }
+    });
+
+    let revoke_all = create_action(move |_: &()| async move {
+        logout_everywhere().await.map_err(|e| format!("{:?}", e))?;
+        session_changed.notify();
+        Ok::<(), String>(())
+    });
+
+    let session_row = move |s: SessionInfo| {
+        let handle = s.handle.clone();
+        view! {
+          <tr>
+            <td>
+              {s.user_agent.unwrap_or("Unknown browser".to_string())}
+              <Show when=move || s.current>
+                <span class="tag is-info ml-2">This device</span>
+              </Show>
+            </td>
+            <td>{local(s.created_at)}</td>
+            <td>{local(s.last_seen_at)}</td>
+            <td class="has-text-right">
+              <Show when=move || !s.current>
+                <button class="button is-small" on:click={
+                    let handle = handle.clone();
+                    move |_| revoke.dispatch(handle.clone())
+                }>Sign Out</button>
+              </Show>
+            </td>
+          </tr>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Where you are signed in</h1>
+          <table class="table is-fullwidth">
+            <thead>
+              <tr>
+                <th>Browser</th>
+                <th>Signed in</th>
+                <th>Last seen</th>
+                <th></th>
+              </tr>
+            </thead>
+            <tbody>
+              {move || {
+                  sessions
+                      .get()
+                      .and_then(|s| s.ok())
+                      .unwrap_or_default()
+                      .into_iter()
+                      .map(session_row)
+                      .collect_view()
+              }}
+
+            </tbody>
+          </table>
+          <ErrorNotification sig=revoke.value()/>
+          <ErrorNotification sig=revoke_all.value()/>
+          <button class="button is-danger is-outlined" on:click=move |_| revoke_all.dispatch(())>
+            Sign out everywhere
+          </button>
+        </div>
+      </section>
+    }
+}
@@ -1,7 +1,9 @@
-use common::auth::oauth::{check, OAuthRedirect};
+use common::auth::magic_link::{redeem_magic_link, request_magic_link};
+use common::auth::oauth::{check, list_providers, OAuthProviderInfo, OAuthRedirect};
 use common::auth::password;
-use common::error_handling::ErrorResponse;
-use common::person::person_exists;
+use common::auth::reset::{complete_password_reset, request_password_reset};
+use common::auth::two_factor::{second_factor_pending, verify_second_factor, SignInStep};
+use common::auth::verify::verify_email;
 use leptos::*;
 use leptos_router::*;
 use logging::*;
@@ -9,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::js_sys::{Array, Reflect};
 
-use crate::app::{SessionID, SignInSignal, SignInStatus};
+use crate::app::{PersonResource, SessionChanged, SignInSignal, SignInStatus};
 use crate::error_handling::*;
 
 #[component]
@@ -20,6 +22,9 @@ pub fn SignIn() -> impl IntoView {
         SignInStatus::Welcome => SignInWelcome.into_view(),
         SignInStatus::CreateUser(email) => view! { <SignUpPassword email=email/> },
         SignInStatus::Password(email) => view! { <SignInPassword email=email/> },
+        SignInStatus::ForgotPassword(email) => view! { <ForgotPassword email=email/> },
+        SignInStatus::MagicLink(email) => view! { <MagicLink email=email/> },
+        SignInStatus::SecondFactor => SecondFactor.into_view(),
     };
 
     view! {
@@ -51,16 +56,15 @@ pub fn SignInPassword(email: String) -> impl IntoView {
     let (password, set_password) = create_signal("".to_string());
     let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
 
-    let set_session_id = use_context::<WriteSignal<SessionID>>().unwrap();
+    let session_changed = use_context::<SessionChanged>().unwrap();
 
     let submit = create_action(move |ep: &EmailPassword| {
         let ep = ep.clone();
         async move {
-            let session_id = password::signin(ep.email, ep.password)
+            let next = password::signin(ep.email, ep.password)
                 .await
                 .map_err(|e| format!("{:?}", e))?;
-            set_session_id(SessionID::Set(session_id));
-            sign_in_signal.set(SignInStatus::NotVisible);
+            after_sign_in(next, sign_in_signal, session_changed);
             Ok::<(), String>(())
         }
     });
@@ -76,7 +80,7 @@ pub fn SignInPassword(email: String) -> impl IntoView {
       }>
 
         <div class="block">
-          <h1 class="subtitle my-4">Hello again</h1>
+          <h1 class="subtitle my-4">Welcome</h1>
           <div class="field">
             <div class="control is-expanded">
               <input
@@ -91,27 +95,275 @@ pub fn SignInPassword(email: String) -> impl IntoView {
           <button class="button is-primary is-fullwidth" type="submit">
             Continue
           </button>
+          <a
+            class="is-size-7 mt-2 is-block"
+            on:click=move |_| sign_in_signal.set(SignInStatus::ForgotPassword(email()))
+          >
+            Forgotten your password?
+          </a>
+          <a
+            class="is-size-7 mt-2 is-block"
+            on:click=move |_| sign_in_signal.set(SignInStatus::MagicLink(email()))
+          >
+            Email me a sign in link instead
+          </a>
+          <a
+            class="is-size-7 mt-2 is-block"
+            on:click=move |_| sign_in_signal.set(SignInStatus::CreateUser(email()))
+          >
+            New here? Create an account
+          </a>
         </div>
       </form>
     }
 }
 
-trait JsonError {
-    async fn json_error_for_status(self) -> Result<Self, String>
-    where
-        Self: Sized;
+#[component]
+pub fn ForgotPassword(email: String) -> impl IntoView {
+    let (email, set_email) = create_signal(email);
+
+    let submit = create_action(move |email: &String| {
+        let email = email.clone();
+        async move {
+            request_password_reset(email)
+                .await
+                .map_err(|e| format!("{:?}", e))
+        }
+    });
+
+    let sent = move || matches!(submit.value()(), Some(Ok(())));
+
+    view! {
+      <h1 class="subtitle my-4">Forgotten your password?</h1>
+      <Show
+        when=sent
+        fallback=move || {
+            view! {
+              <form on:submit=move |e| {
+                  e.prevent_default();
+                  submit.dispatch(email())
+              }>
+                <div class="block">"We'll email you a link to choose a new one."</div>
+                <div class="field">
+                  <div class="control">
+                    <input
+                      class="input"
+                      type="text"
+                      placeholder="Email Address"
+                      prop:value=email
+                      on:change=move |e| set_email(event_target_value(&e))
+                    />
+                  </div>
+                </div>
+                <ErrorNotification sig=submit.value()/>
+                <button
+                  class="button is-primary is-fullwidth"
+                  class:is-loading=submit.pending()
+                  type="submit"
+                >
+                  Send Reset Link
+                </button>
+              </form>
+            }
+        }
+      >
+
+        <div class="block">
+          {move || format!("If there is an account for {}, a reset link is on its way.", email())}
+        </div>
+      </Show>
+    }
 }
 
-impl JsonError for reqwest::Response {
-    async fn json_error_for_status(self) -> Result<Self, String> {
-        if self.error_for_status_ref().is_err() {
-            let resp = self
-                .json::<ErrorResponse>()
+#[component]
+pub fn MagicLink(email: String) -> impl IntoView {
+    let (email, set_email) = create_signal(email);
+
+    let submit = create_action(move |email: &String| {
+        let email = email.clone();
+        async move {
+            request_magic_link(email, current_path())
                 .await
-                .map_err(|e| e.to_string())?;
-            return Err(resp.message);
+                .map_err(|e| format!("{:?}", e))
         }
-        Ok(self)
+    });
+
+    let sent = move || matches!(submit.value()(), Some(Ok(())));
+
+    view! {
+      <h1 class="subtitle my-4">Sign in by email</h1>
+      <Show
+        when=sent
+        fallback=move || {
+            view! {
+              <form on:submit=move |e| {
+                  e.prevent_default();
+                  submit.dispatch(email())
+              }>
+                <div class="block">
+                  "We'll email you a link that signs you in, no password needed. If you're new \
+                   here we'll make you an account."
+                </div>
+                <div class="field">
+                  <div class="control">
+                    <input
+                      class="input"
+                      type="text"
+                      placeholder="Email Address"
+                      prop:value=email
+                      on:change=move |e| set_email(event_target_value(&e))
+                    />
+                  </div>
+                </div>
+                <ErrorNotification sig=submit.value()/>
+                <button
+                  class="button is-primary is-fullwidth"
+                  class:is-loading=submit.pending()
+                  type="submit"
+                >
+                  Send Sign In Link
+                </button>
+              </form>
+            }
+        }
+      >
+
+        <div class="block">
+          {move || format!("A sign in link is on its way to {}.", email())}
+        </div>
+      </Show>
+    }
+}
+
+#[derive(Params, PartialEq, Clone)]
+pub struct MagicLinkParams {
+    pub token: String,
+}
+
+#[component]
+pub fn MagicLinkReturn() -> impl IntoView {
+    let params = use_query::<MagicLinkParams>();
+    let session_changed = use_context::<SessionChanged>().unwrap();
+    let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
+    let navigate = use_navigate();
+
+    let res = create_resource(params, move |param_res| {
+        let navigate = navigate.clone();
+        async move {
+            let p = param_res.map_err(|_| "this sign in link is incomplete".to_string())?;
+            let signed_in = redeem_magic_link(p.token)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            navigate(&signed_in.return_url, Default::default());
+            after_sign_in(signed_in.next, sign_in_signal, session_changed);
+            Ok::<(), String>(())
+        }
+    });
+
+    view! {
+      <section class="section">
+        <div class="container" style="max-width: 30em">
+          <h1 class="title">Signing in</h1>
+          {move || match res.get() {
+              None => view! { <p>"Checking.."</p> }.into_view(),
+              Some(Ok(_)) => view! { <p>"Signed in."</p> }.into_view(),
+              Some(Err(e)) => view! { <div class="notification is-danger">{e}</div> }.into_view(),
+          }}
+
+        </div>
+      </section>
+    }
+}
+
+#[derive(Params, PartialEq, Clone)]
+pub struct ResetPasswordParams {
+    pub token: String,
+}
+
+#[component]
+pub fn ResetPassword() -> impl IntoView {
+    let params = use_query::<ResetPasswordParams>();
+    let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
+
+    let (password1, set_password1) = create_signal("".to_string());
+    let (password2, set_password2) = create_signal("".to_string());
+    let password_mismatch = Signal::derive(move || password1() != password2());
+
+    let submit = create_action(move |password: &String| {
+        let password = password.clone();
+        async move {
+            let token = params
+                .get_untracked()
+                .map_err(|_| "this reset link is incomplete".to_string())?
+                .token;
+            complete_password_reset(token, password)
+                .await
+                .map_err(|e| format!("{:?}", e))
+        }
+    });
+
+    let done = move || matches!(submit.value()(), Some(Ok(())));
+
+    view! {
+      <section class="section">
+        <div class="container" style="max-width: 30em">
+          <h1 class="title">Choose a new password</h1>
+          <Show
+            when=done
+            fallback=move || {
+                view! {
+                  <form on:submit=move |e| {
+                      e.prevent_default();
+                      if password_mismatch() {
+                          return;
+                      }
+                      submit.dispatch(password1())
+                  }>
+                    <div class="field">
+                      <div class="control">
+                        <input
+                          class="input"
+                          class:is-danger=password_mismatch
+                          type="password"
+                          placeholder="New Password"
+                          on:change=move |e| set_password1(event_target_value(&e))
+                        />
+                      </div>
+                    </div>
+                    <div class="field">
+                      <div class="control">
+                        <input
+                          class="input"
+                          class:is-danger=password_mismatch
+                          type="password"
+                          placeholder="Password Confirmation"
+                          on:change=move |e| set_password2(event_target_value(&e))
+                        />
+                      </div>
+                    </div>
+                    <ErrorNotification sig=submit.value()/>
+                    <button
+                      class="button is-primary is-fullwidth"
+                      disabled=password_mismatch
+                      type="submit"
+                    >
+                      Set Password
+                    </button>
+                  </form>
+                }
+            }
+          >
+
+            <div class="notification is-success">"Your password has been changed."</div>
+            <button
+              class="button is-primary"
+              on:click=move |_| sign_in_signal.set(SignInStatus::Welcome)
+            >
+              Sign in
+            </button>
+          </Show>
+        </div>
+      </section>
     }
 }
 
@@ -152,30 +404,32 @@ pub fn SignUpPassword(email: String) -> impl IntoView {
     let password_mismatch = Signal::derive(move || password1() != password2());
     let is_invalid = password_mismatch;
 
-    let set_session_id = use_context::<WriteSignal<SessionID>>().unwrap();
+    let session_changed = use_context::<SessionChanged>().unwrap();
 
     let submit =
         create_action(move |new_user: &NewUser| {
             let new_user = new_user.clone();
             async move {
-                let _res = common::auth::password::signup_password(
+                common::auth::password::signup_password(
                     new_user.email.clone(),
                     new_user.password.clone(),
                     new_user.given_name,
                     new_user.family_name,
                     new_user.phone,
                 )
-                .await; //todo - check resut
-
-                match common::auth::password::signin(new_user.email, new_user.password).await {
-                    // todo handle error
-                    Err(_e) => {}
-                    Ok(session_id) => {
-                        set_session_id(SessionID::Set(session_id));
-                        sign_in_signal.set(SignInStatus::NotVisible);
-                    }
-                }
+                .await
+                .map_err(|e| format!("{:?}", e))?;
 
+                // Signing up with an email that already has an account looks like it worked, but
+                // the owner gets an email instead and this sign in fails
+                let next = common::auth::password::signin(new_user.email, new_user.password)
+                    .await
+                    .map_err(|_| {
+                        "If you already have an account with this email address we've sent you \
+                         an email about it"
+                            .to_string()
+                    })?;
+                after_sign_in(next, sign_in_signal, session_changed);
                 Ok::<(), String>(())
             }
         });
@@ -203,8 +457,7 @@ pub fn SignUpPassword(email: String) -> impl IntoView {
       }>
 
         <div class="block">
-          <h1 class="subtitle my-4">Hello stranger</h1>
-          <div>"Looks like you don't have an account yet. Let's fix that!"</div>
+          <h1 class="subtitle my-4">Create an account</h1>
         </div>
         <div class="field is-grouped">
           <div class="control is-expanded">
@@ -276,53 +529,70 @@ pub fn SignUpPassword(email: String) -> impl IntoView {
 #[component]
 pub fn SignInWelcome() -> impl IntoView {
     let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
-    let set_session = use_context::<WriteSignal<SessionID>>().unwrap();
+    let session_changed = use_context::<SessionChanged>().unwrap();
 
+    // The popup can't tell us how it went, so ask whether there's a second factor to check
     let on_success = move || {
-        set_session(SessionID::from_cookie());
-        sign_in_signal.set(SignInStatus::NotVisible);
+        spawn_local(async move {
+            let next = match second_factor_pending().await {
+                Ok(true) => SignInStep::SecondFactor,
+                _ => SignInStep::Done,
+            };
+            after_sign_in(next, sign_in_signal, session_changed);
+        })
     };
 
-    let oauth_button = view! {
-      <button
-        class="button"
-        type="button"
-        on:click=move |_| {
-            let _ = oauth_popup(OAuthRedirect::PATH, on_success);
+    let providers = create_resource(|| (), |_| list_providers());
+
+    let oauth_button = move |provider: OAuthProviderInfo| {
+        let query = serde_qs::to_string(&OAuthRedirect {
+            provider: provider.name,
+            return_url: current_path(),
+            link: None,
+        })
+        .unwrap();
+        let url = format!("{}?{}", OAuthRedirect::PATH, query);
+        view! {
+          <button
+            class="button is-fullwidth mb-2"
+            type="button"
+            on:click=move |_| {
+                let _ = oauth_popup(&url, on_success);
+            }
+          >
+
+            <span class="icon is-medium">
+              <img src=provider.icon_url/>
+            </span>
+            <span>"Sign in with " {provider.label}</span>
+          </button>
         }
-      >
+    };
 
-        <span class="icon is-medium">
-          <GoogleLogoSVG/>
-        </span>
-        <span>Sign in with Google</span>
-      </button>
+    let oauth_buttons = move || {
+        let providers = providers.get().and_then(|p| p.ok()).unwrap_or_default();
+        if providers.is_empty() {
+            return ().into_view();
+        }
+        view! {
+          <div class="level my-3">
+            <hr class="level-item is-flex-shrink-2"/>
+            <div class="is-size-7 px-2">OR</div>
+            <hr class="level-item is-flex-shrink-2"/>
+          </div>
+          {providers.into_iter().map(oauth_button).collect_view()}
+        }
+        .into_view()
     };
 
     let (email, set_email) = create_signal("".to_string());
 
-    let continue_pressed = create_action(move |email: &String| {
-        let email = email.clone();
-        async move {
-            let user_exists = person_exists(email.clone()).await?;
-
-            let next_status = if user_exists {
-                SignInStatus::Password(email.clone())
-            } else {
-                SignInStatus::CreateUser(email.clone())
-            };
-            log!("updating sign_in_signal");
-            sign_in_signal.set(next_status);
-            Ok::<(), AppError>(())
-        }
-    });
-
     view! {
       <h1 class="subtitle my-4">Sign in to continue</h1>
       <form on:submit=move |e| {
           log!("form submission");
           e.prevent_default();
-          continue_pressed.dispatch(email())
+          sign_in_signal.set(SignInStatus::Password(email()))
       }>
         <div class="field">
           <div class="control">
@@ -341,16 +611,53 @@ pub fn SignInWelcome() -> impl IntoView {
             </button>
           </div>
         </div>
-        <div class="level my-3">
-          <hr class="level-item is-flex-shrink-2"/>
-          <div class="is-size-7 px-2">OR</div>
-          <hr class="level-item is-flex-shrink-2"/>
-        </div>
-        {oauth_button}
+        {oauth_buttons}
       </form>
     }
 }
 
+#[derive(Params, PartialEq, Clone)]
+pub struct VerifyEmailParams {
+    pub token: String,
+}
+
+#[component]
+pub fn VerifyEmail() -> impl IntoView {
+    let params = use_query::<VerifyEmailParams>();
+    let person = use_context::<PersonResource>().unwrap().0;
+
+    let res = create_resource(params, move |param_res| async move {
+        let p = param_res.map_err(|_| "this verification link is incomplete".to_string())?;
+        verify_email(p.token)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        person.refetch();
+        Ok::<(), String>(())
+    });
+
+    view! {
+      <section class="section">
+        <div class="container" style="max-width: 30em">
+          <h1 class="title">Email verification</h1>
+          {move || match res.get() {
+              None => view! { <p>"Checking.."</p> }.into_view(),
+              Some(Ok(_)) => {
+                  view! {
+                    <div class="notification is-success">"Thanks, your email address is verified."</div>
+                    <A class="button is-primary" href="/events">
+                      Find an event
+                    </A>
+                  }
+                      .into_view()
+              }
+              Some(Err(e)) => view! { <div class="notification is-danger">{e}</div> }.into_view(),
+          }}
+
+        </div>
+      </section>
+    }
+}
+
 #[derive(Params, PartialEq, Clone)]
 pub struct OAuthReturnParams {
     pub state: String,
@@ -360,20 +667,29 @@ pub struct OAuthReturnParams {
 #[component]
 pub fn OAuthReturn() -> impl IntoView {
     let params = use_query::<OAuthReturnParams>();
-    let set_session_id = use_context::<WriteSignal<SessionID>>().unwrap();
+    let session_changed = use_context::<SessionChanged>().unwrap();
+    let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
+    let navigate = use_navigate();
 
-    let res = create_resource(params, move |param_res| async move {
-        let p = match param_res {
-            Ok(p) => p,
-            Err(e) => return Err(format!("unable to read oauth query params: {}", e)),
-        };
-
-        match check(p.state, p.code).await {
-            Err(e) => Err(format!("oauth check failed: {:?}", e)),
-            Ok(session_id) => {
-                set_session_id(SessionID::Set(session_id));
-                close_popup();
-                Ok(())
+    let res = create_resource(params, move |param_res| {
+        let navigate = navigate.clone();
+        async move {
+            let p = match param_res {
+                Ok(p) => p,
+                Err(e) => return Err(format!("unable to read oauth query params: {}", e)),
+            };
+
+            match check(p.state, p.code).await {
+                Err(e) => Err(format!("oauth check failed: {:?}", e)),
+                Ok(signed_in) => {
+                    // If we were opened as a popup the page we came from is still there
+                    // underneath and carries on, otherwise take them back to it.
+                    if !close_popup() {
+                        navigate(&signed_in.return_url, Default::default());
+                        after_sign_in(signed_in.next, sign_in_signal, session_changed);
+                    }
+                    Ok(())
+                }
             }
         }
     });
@@ -392,14 +708,89 @@ pub fn OAuthReturn() -> impl IntoView {
     }
 }
 
-fn oauth_popup<F>(url: &str, on_success: F) -> Result<(), AppError>
+// The first step of signing in worked, carry on to the second factor if there is one
+fn after_sign_in(
+    next: SignInStep,
+    sign_in_signal: RwSignal<SignInStatus>,
+    session_changed: SessionChanged,
+) {
+    session_changed.notify();
+    sign_in_signal.set(match next {
+        SignInStep::Done => SignInStatus::NotVisible,
+        SignInStep::SecondFactor => SignInStatus::SecondFactor,
+    });
+}
+
+#[component]
+pub fn SecondFactor() -> impl IntoView {
+    let sign_in_signal = use_context::<SignInSignal>().unwrap().0;
+    let session_changed = use_context::<SessionChanged>().unwrap();
+    let (code, set_code) = create_signal("".to_string());
+
+    let submit = create_action(move |code: &String| {
+        let code = code.clone();
+        async move {
+            verify_second_factor(code)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            after_sign_in(SignInStep::Done, sign_in_signal, session_changed);
+            Ok::<(), String>(())
+        }
+    });
+
+    view! {
+      <form on:submit=move |e| {
+          e.prevent_default();
+          submit.dispatch(code())
+      }>
+        <h1 class="subtitle my-4">One more step</h1>
+        <div class="block">
+          "Enter the code from your authenticator app, or one of your recovery codes."
+        </div>
+        <div class="field">
+          <div class="control">
+            <input
+              class="input"
+              type="text"
+              autocomplete="one-time-code"
+              placeholder="Code"
+              on:change=move |e| set_code(event_target_value(&e))
+            />
+          </div>
+        </div>
+        <ErrorNotification sig=submit.value()/>
+        <button class="button is-primary is-fullwidth" class:is-loading=submit.pending() type="submit">
+          Continue
+        </button>
+      </form>
+    }
+}
+
+// Where we are now, for coming back to after signing in
+fn current_path() -> Option<String> {
+    let location = window().location();
+    let path = location.pathname().ok()?;
+    let search = location.search().unwrap_or_default();
+    Some(format!("{}{}", path, search))
+}
+
+pub fn oauth_popup<F>(url: &str, on_success: F) -> Result<(), AppError>
 where
     F: Fn() + 'static,
 {
     let popup = window()
         .open_with_url_and_target_and_features(url, "popup", "popup")
-        .map_err(|_| "failed to open popup window".to_string())?
-        .ok_or("failed to open popup window".to_string())?;
+        .ok()
+        .flatten();
+
+    // Popup blocked, go there in this window instead and oauth_return will bring us back
+    let Some(popup) = popup else {
+        window()
+            .location()
+            .set_href(url)
+            .map_err(|_| "failed to go to sign in page".to_string())?;
+        return Ok(());
+    };
 
     // TODO: How do we remove this once we're done?
     let _remove_listener =
@@ -417,38 +808,17 @@ where
     Ok(())
 }
 
-fn close_popup() {
-    let opener = window().opener().unwrap();
+// Returns false if we weren't opened as a popup
+fn close_popup() -> bool {
+    let opener = match window().opener() {
+        Ok(opener) if !opener.is_null() && !opener.is_undefined() => opener,
+        _ => return false,
+    };
     let post_message = Reflect::get(&opener, &JsValue::from_str("postMessage")).unwrap();
 
     let args = Array::new();
     args.push(&JsValue::from_str("auth_ok"));
     let _ = Reflect::apply(post_message.unchecked_ref(), &opener, &args)
         .map_err(|_| "unable to push auth event");
+    true
 }
-
-#[component]
-pub fn GoogleLogoSVG() -> impl IntoView {
-    view! {
-      <svg class="Bz112c Bz112c-E3DyYd" xmlns="https://www.w3.org/2000/svg" viewBox="0 0 48 48">
-        <path
-          fill="#4285F4"
-          d="M45.12 24.5c0-1.56-.14-3.06-.4-4.5H24v8.51h11.84c-.51 2.75-2.06 5.08-4.39 6.64v5.52h7.11c4.16-3.83 6.56-9.47 6.56-16.17z"
-        ></path>
-        <path
-          fill="#34A853"
-          d="M24 46c5.94 0 10.92-1.97 14.56-5.33l-7.11-5.52c-1.97 1.32-4.49 2.1-7.45 2.1-5.73 0-10.58-3.87-12.31-9.07H4.34v5.7C7.96 41.07 15.4 46 24 46z"
-        ></path>
-        <path
-          fill="#FBBC05"
-          d="M11.69 28.18C11.25 26.86 11 25.45 11 24s.25-2.86.69-4.18v-5.7H4.34C2.85 17.09 2 20.45 2 24c0 3.55.85 6.91 2.34 9.88l7.35-5.7z"
-        ></path>
-        <path
-          fill="#EA4335"
-          d="M24 10.75c3.23 0 6.13 1.11 8.41 3.29l6.31-6.31C34.91 4.18 29.93 2 24 2 15.4 2 7.96 6.93 4.34 14.12l7.35 5.7c1.73-5.2 6.58-9.07 12.31-9.07z"
-        ></path>
-        <path fill="none" d="M2 2h44v44H2z"></path>
-      </svg>
-    }
-}
-
@@ -30,12 +30,12 @@ impl SlotStateForTicket {
     pub fn description(&self) -> String {
         use SlotStateForTicket::*;
         match self {
-            InSlot { available, .. } if *available <= 0 => format!("No more available."),
+            InSlot { available, .. } if *available <= 0 => "No more available.".to_string(),
             InSlot { available, .. } => format!("{available} more available."),
             SomeLeft { available, .. } => format!("{} available.", available),
             Unlimited => "".to_string(),
             NoneLeft { buying: 0 } => "Sold out.".to_string(),
-            NoneLeft { .. } => format!("No more available."),
+            NoneLeft { .. } => "No more available.".to_string(),
             NotFound(name) => format!("Error! slot {name} found."),
         }
     }
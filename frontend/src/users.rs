@@ -1,9 +1,12 @@
 use async_trait::async_trait;
-use common::{role::RoleId, user};
+use common::role::{grant_role, revoke_role, RoleId};
+use common::user;
 use leptos::*;
 use leptos_struct_table::*;
 use serde::{Deserialize, Serialize};
 
+use crate::sign_in::ErrorNotification;
+
 #[derive(Debug, Clone, Copy)]
 struct BulmaTableClasses;
 
@@ -55,7 +58,7 @@ where
 #[component]
 pub fn Users() -> impl IntoView {
     let users = create_rw_signal::<Vec<User>>(vec![]);
-    let _r = create_resource(
+    let list = create_resource(
         || (),
         move |_| async move {
             let doofers: Vec<User> = user::list_users()
@@ -68,11 +71,66 @@ pub fn Users() -> impl IntoView {
             users.set(doofers);
         },
     );
+
+    let (person, set_person) = create_signal("".to_string());
+    let (role, set_role) = create_signal(RoleId::organiser().to_string());
+
+    let change_role = create_action(move |grant: &bool| {
+        let grant = *grant;
+        async move {
+            let (person, role) = (person.get_untracked(), role.get_untracked());
+            if person.is_empty() {
+                return Err("choose somebody first".to_string());
+            }
+            let res = match grant {
+                true => grant_role(person.into(), role.into()).await,
+                false => revoke_role(person.into(), role.into()).await,
+            };
+            res.map_err(|e| format!("{:?}", e))?;
+            list.refetch();
+            Ok::<(), String>(())
+        }
+    });
+
+    let person_option = move |u: User| {
+        view! { <option value=u.id.clone()>{format!("{} ({})", u.email, u.given_name)}</option> }
+    };
+
     view! {
       <section class="section">
         <div class="container">
           <h1>Users</h1>
           <UserTable items=users/>
+          <h2 class="subtitle mt-5">Roles</h2>
+          <div class="field is-grouped">
+            <div class="control">
+              <div class="select">
+                <select on:change=move |e| set_person(event_target_value(&e))>
+                  <option value="">"Choose somebody"</option>
+                  {move || users().into_iter().map(person_option).collect_view()}
+                </select>
+              </div>
+            </div>
+            <div class="control">
+              <div class="select">
+                <select on:change=move |e| set_role(event_target_value(&e))>
+                  <option value=RoleId::organiser().to_string()>organiser</option>
+                  <option value=RoleId::admin().to_string()>admin</option>
+                </select>
+              </div>
+            </div>
+            <div class="control">
+              <button class="button is-primary" on:click=move |_| change_role.dispatch(true)>
+                Grant
+              </button>
+            </div>
+            <div class="control">
+              <button class="button" on:click=move |_| change_role.dispatch(false)>
+                Revoke
+              </button>
+            </div>
+          </div>
+          <ErrorNotification sig=change_role.value()/>
         </div>
       </section>
     }
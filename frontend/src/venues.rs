@@ -0,0 +1,228 @@
+use common::venue::{delete_venue, get_venue, list_venues, save_venue, NewVenue, Venue, VenueId};
+use leptos::*;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+use crate::book_event::require_login;
+use crate::event_editor::input;
+use crate::sign_in::ErrorNotification;
+
+fn blank_venue() -> NewVenue {
+    NewVenue {
+        name: "".to_string(),
+        address: "".to_string(),
+        postcode: "".to_string(),
+        latitude: None,
+        longitude: None,
+        accessibility: "".to_string(),
+        parking: "".to_string(),
+    }
+}
+
+fn coordinate(c: Option<Decimal>) -> String { c.map(|c| c.to_string()).unwrap_or_default() }
+
+// Where an event is and how to get there, for people booking it
+#[component]
+pub fn VenueDetails(id: VenueId) -> impl IntoView {
+    let venue = create_resource(move || id.clone(), get_venue);
+
+    let details = move |v: Venue| {
+        let map = v.map_url().map(|url| {
+            view! {
+              <a href=url target="_blank" rel="noopener">
+                "Show on a map"
+              </a>
+            }
+        });
+        let note = |label: &'static str, text: String| {
+            (!text.trim().is_empty()).then(|| {
+                view! {
+                  <p>
+                    <strong>{label}</strong>
+                    " "
+                    {text}
+                  </p>
+                }
+            })
+        };
+        view! {
+          <div class="box content">
+            <h2 class="title is-5">{v.name}</h2>
+            <p style="white-space: pre-line;">{v.address} "\n" {v.postcode}</p>
+            {map}
+            {note("Accessibility:", v.accessibility)}
+            {note("Parking:", v.parking)}
+          </div>
+        }
+    };
+
+    move || match venue.get() {
+        Some(Ok(v)) => details(v).into_view(),
+        _ => ().into_view(),
+    }
+}
+
+#[component]
+fn VenueForm(
+    editing: RwSignal<Option<VenueId>>,
+    venue: RwSignal<NewVenue>,
+    #[prop(into)] saved: Callback<()>,
+) -> impl IntoView {
+    let save = create_action(move |_: &()| async move {
+        save_venue(editing.get_untracked(), venue.get_untracked())
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        editing.set(None);
+        venue.set(blank_venue());
+        saved(());
+        Ok::<(), String>(())
+    });
+
+    let text = move |placeholder, get: fn(&NewVenue) -> String, set: fn(&mut NewVenue, String)| {
+        let (get, set) = (move || venue.with(get), move |v| venue.update(|n| set(n, v)));
+        view! { <div class="field">{input("text", placeholder, get, set)}</div> }
+    };
+    let set_coordinate = |v: String| Decimal::from_str(v.trim()).ok();
+
+    view! {
+      <form on:submit=move |e| {
+          e.prevent_default();
+          save.dispatch(())
+      }>
+        <h2 class="subtitle mt-5">
+          {move || if editing().is_some() { "Edit venue" } else { "New venue" }}
+        </h2>
+        {text("Name", |v| v.name.clone(), |v, s| v.name = s)}
+        <div class="field">
+          <div class="control">
+            <textarea
+              class="textarea"
+              rows="3"
+              placeholder="Address"
+              prop:value=move || venue.with(|v| v.address.clone())
+              on:change=move |e| venue.update(|v| v.address = event_target_value(&e))
+            ></textarea>
+          </div>
+        </div>
+        {text("Postcode", |v| v.postcode.clone(), |v, s| v.postcode = s)}
+        <div class="field is-grouped">
+          {input(
+              "number",
+              "Latitude",
+              move || venue.with(|v| coordinate(v.latitude)),
+              move |s| venue.update(|v| v.latitude = set_coordinate(s)),
+          )}
+          {input(
+              "number",
+              "Longitude",
+              move || venue.with(|v| coordinate(v.longitude)),
+              move |s| venue.update(|v| v.longitude = set_coordinate(s)),
+          )}
+        </div>
+        {text("Accessibility notes", |v| v.accessibility.clone(), |v, s| v.accessibility = s)}
+        {text("Parking", |v| v.parking.clone(), |v, s| v.parking = s)}
+        <ErrorNotification sig=save.value()/>
+        <div class="buttons">
+          <button class="button is-primary" type="submit">
+            Save Venue
+          </button>
+          <Show when=move || editing().is_some()>
+            <button
+              class="button"
+              type="button"
+              on:click=move |_| {
+                  editing.set(None);
+                  venue.set(blank_venue());
+              }
+            >
+              Cancel
+            </button>
+          </Show>
+        </div>
+      </form>
+    }
+}
+
+// Organisers' list of venues, which events are then set to be at
+#[component]
+pub fn Venues() -> impl IntoView {
+    require_login();
+    let venues = create_resource(|| (), |_| list_venues());
+    let editing = create_rw_signal(None::<VenueId>);
+    let venue = create_rw_signal(blank_venue());
+
+    let delete = create_action(move |id: &VenueId| {
+        let id = id.clone();
+        async move {
+            delete_venue(id).await.map_err(|e| format!("{:?}", e))?;
+            venues.refetch();
+            Ok::<(), String>(())
+        }
+    });
+
+    let row = move |v: Venue| {
+        let id = store_value(v.id.clone());
+        let edit = store_value(v.clone());
+        view! {
+          <tr>
+            <td>{v.name}</td>
+            <td>{v.postcode}</td>
+            <td>
+              <div class="buttons">
+                <button
+                  class="button"
+                  on:click=move |_| {
+                      editing.set(Some(id()));
+                      venue.set(edit().into());
+                  }
+                >
+                  Edit
+                </button>
+                <button
+                  class="button is-danger is-outlined"
+                  on:click=move |_| delete.dispatch(id())
+                >
+                  Delete
+                </button>
+              </div>
+            </td>
+          </tr>
+        }
+    };
+
+    view! {
+      <section class="section">
+        <div class="container">
+          <h1 class="title">Venues</h1>
+          <table class="table is-fullwidth is-striped">
+            <thead>
+              <tr>
+                <th>Name</th>
+                <th>Postcode</th>
+                <th></th>
+              </tr>
+            </thead>
+            <tbody>
+              {move || match venues.get() {
+                  Some(Ok(venues)) => venues.into_iter().map(row).collect_view(),
+                  Some(Err(e)) => {
+                      view! {
+                        <tr>
+                          <td colspan="3" class="has-text-danger">
+                            {format!("{:?}", e)}
+                          </td>
+                        </tr>
+                      }
+                          .into_view()
+                  }
+                  None => ().into_view(),
+              }}
+
+            </tbody>
+          </table>
+          <ErrorNotification sig=delete.value()/>
+          <VenueForm editing venue saved=move |_| venues.refetch()/>
+        </div>
+      </section>
+    }
+}
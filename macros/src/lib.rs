@@ -278,9 +278,11 @@ pub fn serverfn_test(_attr: TokenStream, item: TokenStream) -> TokenStream {
             let db = surrealdb::engine::any::connect("mem://").await.unwrap();
             db.use_ns("test").use_db("test").await.unwrap();
 
+            let config = crate::Config::default();
             let app_state = AppState {
                 db,
-                config: crate::Config::default(),
+                storage: crate::storage::from_config(&config.uploads),
+                config,
             };
             leptos::provide_context(app_state);
 